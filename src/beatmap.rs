@@ -0,0 +1,943 @@
+//! A parser for osu! beatmap (.osu) files.
+//!
+//! Only the sections that matter for difficulty and performance calculation
+//! are parsed: general info, metadata, difficulty settings, timing points
+//! and hit objects. Slider timing (ticks, repeats and tails) is also
+//! computed here, following osu!lazer's slider event generation, so the
+//! maximum combo of a beatmap is known right after parsing it.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The osu!standard base scoring distance, used on slider velocity calculations.
+const BASE_SCORING_DISTANCE: f64 = 100.0;
+
+/// Sliders longer than this are truncated when generating their ticks.
+const MAX_SLIDER_LENGTH: f64 = 100000.0;
+
+/// Sliders with more spans (repeats plus one) than this are cut down to it.
+const MAX_SLIDER_SPANS: usize = 1000;
+
+/// The most ticks and repeats generated for a whole beatmap. Sliders after
+/// that only get their tail.
+const MAX_NESTED_OBJECTS: usize = 1_000_000;
+
+/// How early, in ms, the legacy last tick of a slider happens before its end.
+const LEGACY_LAST_TICK_OFFSET: f64 = 36.0;
+
+/// An error that can be returned when parsing a .osu file fails.
+#[derive(Debug)]
+pub struct ParseError {
+    message: String,
+}
+
+impl ParseError {
+    fn new<S: Into<String>>(message: S) -> Self {
+        ParseError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid beatmap: {}", self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// A point (or a vector) in the osu! playfield.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Position {
+    pub fn new(x: f64, y: f64) -> Self {
+        Position { x: x, y: y }
+    }
+
+    pub fn add(self, other: Position) -> Position {
+        Position::new(self.x + other.x, self.y + other.y)
+    }
+
+    pub fn sub(self, other: Position) -> Position {
+        Position::new(self.x - other.x, self.y - other.y)
+    }
+
+    pub fn scale(self, factor: f64) -> Position {
+        Position::new(self.x * factor, self.y * factor)
+    }
+
+    pub fn dot(self, other: Position) -> f64 {
+        self.x * other.x + self.y * other.y
+    }
+
+    pub fn length_squared(self) -> f64 {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> f64 {
+        self.length_squared().sqrt()
+    }
+
+    pub fn distance(self, other: Position) -> f64 {
+        self.sub(other).length()
+    }
+}
+
+/// The difficulty settings of a beatmap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difficulty {
+    pub hp_drain_rate: f64,
+    pub circle_size: f64,
+    pub overall_difficulty: f64,
+    pub approach_rate: f64,
+    pub slider_multiplier: f64,
+    pub slider_tick_rate: f64,
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        Difficulty {
+            hp_drain_rate: 5.0,
+            circle_size: 5.0,
+            overall_difficulty: 5.0,
+            approach_rate: 5.0,
+            slider_multiplier: 1.4,
+            slider_tick_rate: 1.0,
+        }
+    }
+}
+
+/// Maps a difficulty value (0-10) to a value between `min`, `mid` and `max`,
+/// where 0 maps to `min`, 5 to `mid`, and 10 to `max`.
+pub fn difficulty_range(difficulty: f64, min: f64, mid: f64, max: f64) -> f64 {
+    if difficulty > 5.0 {
+        mid + (max - mid) * (difficulty - 5.0) / 5.0
+    } else if difficulty < 5.0 {
+        mid - (mid - min) * (5.0 - difficulty) / 5.0
+    } else {
+        mid
+    }
+}
+
+/// The beatmap metadata, used to describe it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub artist: String,
+    pub creator: String,
+    pub version: String,
+    pub beatmap_id: Option<i64>,
+    pub beatmap_set_id: Option<i64>,
+}
+
+/// A timing point. Uninherited (red) lines define the beat length, while
+/// inherited (green) lines change the slider velocity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimingPoint {
+    pub time: f64,
+    pub beat_length: f64,
+    pub uninherited: bool,
+}
+
+impl TimingPoint {
+    /// The slider velocity multiplier this timing point sets.
+    pub fn speed_multiplier(&self) -> f64 {
+        if self.beat_length < 0.0 {
+            (100.0 / -self.beat_length).max(0.1).min(10.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// The curve type of a slider.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurveType {
+    Linear,
+    PerfectCurve,
+    Bezier,
+    Catmull,
+}
+
+/// The kind of an object nested into a slider.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NestedKind {
+    Tick,
+    Repeat,
+    Tail,
+}
+
+/// An object nested into a slider, i.e. something that gives combo besides
+/// the slider head.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NestedObject {
+    pub kind: NestedKind,
+    pub time: f64,
+}
+
+/// Slider specific data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slider {
+    pub curve_type: CurveType,
+    /// The control points, relative to the slider position. The first one
+    /// is always (0, 0).
+    pub control_points: Vec<Position>,
+    /// The number of times the slider is traversed (i.e. repeats + 1).
+    pub span_count: usize,
+    /// The length of the slider, in osu!pixels, if it was specified.
+    pub pixel_length: Option<f64>,
+    pub span_duration: f64,
+    pub velocity: f64,
+    pub nested: Vec<NestedObject>,
+}
+
+/// The kind of a hit object, and its specific data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HitObjectKind {
+    Circle,
+    Slider(Slider),
+    Spinner { end_time: f64 },
+}
+
+/// A hit object (circle, slider or spinner).
+#[derive(Debug, Clone, PartialEq)]
+pub struct HitObject {
+    pub position: Position,
+    pub start_time: f64,
+    pub new_combo: bool,
+    pub kind: HitObjectKind,
+}
+
+impl HitObject {
+    /// The time at which this object ends.
+    pub fn end_time(&self) -> f64 {
+        match self.kind {
+            HitObjectKind::Circle => self.start_time,
+            HitObjectKind::Slider(ref slider) => {
+                self.start_time + slider.span_duration * slider.span_count as f64
+            }
+            HitObjectKind::Spinner { end_time } => end_time,
+        }
+    }
+
+    /// The maximum combo this object can give.
+    pub fn max_combo(&self) -> usize {
+        match self.kind {
+            HitObjectKind::Slider(ref slider) => 1 + slider.nested.len(),
+            _ => 1,
+        }
+    }
+
+    pub fn is_circle(&self) -> bool {
+        self.kind == HitObjectKind::Circle
+    }
+
+    pub fn is_slider(&self) -> bool {
+        match self.kind {
+            HitObjectKind::Slider(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_spinner(&self) -> bool {
+        match self.kind {
+            HitObjectKind::Spinner { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// A parsed beatmap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Beatmap {
+    /// The .osu file format version.
+    pub format_version: i32,
+    /// The game mode (0 = osu!, 1 = taiko, 2 = catch, 3 = mania).
+    pub mode: u8,
+    pub stack_leniency: f64,
+    pub metadata: Metadata,
+    pub difficulty: Difficulty,
+    pub timing_points: Vec<TimingPoint>,
+    pub hit_objects: Vec<HitObject>,
+}
+
+/// The section of the .osu file that's currently being parsed.
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    None,
+    General,
+    Metadata,
+    Difficulty,
+    TimingPoints,
+    HitObjects,
+    Other,
+}
+
+/// Splits a `Key: Value` line into its key and value.
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let mut split = line.splitn(2, ':');
+    let key = split.next()?.trim();
+    let value = split.next()?.trim();
+
+    Some((key, value))
+}
+
+/// Parses a number, with a error message containing `what` if it fails.
+/// Floats that aren't finite (`nan`, `inf` or overflowing ones) are rejected,
+/// as they can't be ordered or calculated with.
+fn parse_number<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, ParseError> {
+    let invalid = || ParseError::new(format!("invalid {}: {:?}", what, value));
+    if value
+        .trim()
+        .parse::<f64>()
+        .map_or(false, |number| !number.is_finite())
+    {
+        return Err(invalid());
+    }
+
+    value.trim().parse::<T>().map_err(|_| invalid())
+}
+
+impl Beatmap {
+    /// Reads and parses the .osu file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Will error if the file couldn't be read, or if it isn't a valid .osu file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Beatmap, Box<Error>> {
+        let contents = fs::read(path)?;
+
+        Ok(Beatmap::parse(&String::from_utf8_lossy(&contents))?)
+    }
+
+    /// Parses the contents of a .osu file.
+    ///
+    /// # Errors
+    ///
+    /// Will error if `contents` doesn't start with a `osu file format` header,
+    /// or if any of the parsed sections contains invalid values.
+    pub fn parse(contents: &str) -> Result<Beatmap, ParseError> {
+        let mut lines = contents.lines();
+
+        let header = lines
+            .by_ref()
+            .map(|l| l.trim_start_matches('\u{feff}').trim())
+            .find(|l| !l.is_empty())
+            .ok_or_else(|| ParseError::new("empty file"))?;
+
+        if !header.starts_with("osu file format v") {
+            return Err(ParseError::new("missing osu file format header"));
+        }
+
+        let format_version = parse_number(&header["osu file format v".len()..], "version")?;
+
+        let mut beatmap = Beatmap {
+            format_version: format_version,
+            mode: 0,
+            stack_leniency: 0.7,
+            metadata: Metadata::default(),
+            difficulty: Difficulty::default(),
+            timing_points: Vec::new(),
+            hit_objects: Vec::new(),
+        };
+
+        let mut approach_rate = None;
+        let mut section = Section::None;
+
+        for line in lines {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = match &line[1..line.len() - 1] {
+                    "General" => Section::General,
+                    "Metadata" => Section::Metadata,
+                    "Difficulty" => Section::Difficulty,
+                    "TimingPoints" => Section::TimingPoints,
+                    "HitObjects" => Section::HitObjects,
+                    _ => Section::Other,
+                };
+                continue;
+            }
+
+            match section {
+                Section::General => {
+                    if let Some((key, value)) = key_value(line) {
+                        match key {
+                            "Mode" => beatmap.mode = parse_number(value, "mode")?,
+                            "StackLeniency" => {
+                                beatmap.stack_leniency = parse_number(value, "stack leniency")?
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Section::Metadata => {
+                    if let Some((key, value)) = key_value(line) {
+                        let metadata = &mut beatmap.metadata;
+                        match key {
+                            "Title" => metadata.title = value.to_string(),
+                            "Artist" => metadata.artist = value.to_string(),
+                            "Creator" => metadata.creator = value.to_string(),
+                            "Version" => metadata.version = value.to_string(),
+                            "BeatmapID" => metadata.beatmap_id = value.parse().ok(),
+                            "BeatmapSetID" => metadata.beatmap_set_id = value.parse().ok(),
+                            _ => {}
+                        }
+                    }
+                }
+                Section::Difficulty => {
+                    if let Some((key, value)) = key_value(line) {
+                        let difficulty = &mut beatmap.difficulty;
+                        match key {
+                            "HPDrainRate" => difficulty.hp_drain_rate = parse_number(value, "HP")?,
+                            "CircleSize" => difficulty.circle_size = parse_number(value, "CS")?,
+                            "OverallDifficulty" => {
+                                difficulty.overall_difficulty = parse_number(value, "OD")?
+                            }
+                            "ApproachRate" => approach_rate = Some(parse_number(value, "AR")?),
+                            // Clamped like osu!lazer does.
+                            "SliderMultiplier" => {
+                                let multiplier: f64 = parse_number(value, "slider multiplier")?;
+                                difficulty.slider_multiplier = multiplier.max(0.4).min(3.6)
+                            }
+                            "SliderTickRate" => {
+                                let tick_rate: f64 = parse_number(value, "slider tick rate")?;
+                                difficulty.slider_tick_rate = tick_rate.max(0.5).min(8.0)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Section::TimingPoints => {
+                    beatmap.timing_points.push(parse_timing_point(line)?);
+                }
+                Section::HitObjects => {
+                    beatmap.hit_objects.push(parse_hit_object(line)?);
+                }
+                Section::None | Section::Other => {}
+            }
+        }
+
+        // Old beatmaps don't have a separate AR setting.
+        beatmap.difficulty.approach_rate =
+            approach_rate.unwrap_or(beatmap.difficulty.overall_difficulty);
+
+        beatmap
+            .timing_points
+            .sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+        beatmap.hit_objects.sort_by(|a, b| {
+            a.start_time
+                .partial_cmp(&b.start_time)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        beatmap.compute_slider_timings();
+
+        Ok(beatmap)
+    }
+
+    /// The uninherited timing point active at `time`. If `time` is before the
+    /// first timing point, the first one is returned.
+    pub fn timing_point_at(&self, time: f64) -> Option<&TimingPoint> {
+        let uninherited = self.timing_points.iter().filter(|tp| tp.uninherited);
+        let first = uninherited.clone().next();
+
+        uninherited.filter(|tp| tp.time <= time).last().or(first)
+    }
+
+    /// The slider velocity multiplier active at `time`.
+    pub fn speed_multiplier_at(&self, time: f64) -> f64 {
+        self.timing_points
+            .iter()
+            .filter(|tp| tp.time <= time)
+            .last()
+            .map(|tp| tp.speed_multiplier())
+            .unwrap_or(1.0)
+    }
+
+    /// The maximum combo achievable on this beatmap.
+    pub fn max_combo(&self) -> usize {
+        self.hit_objects.iter().map(|h| h.max_combo()).sum()
+    }
+
+    pub fn circle_count(&self) -> usize {
+        self.hit_objects.iter().filter(|h| h.is_circle()).count()
+    }
+
+    pub fn slider_count(&self) -> usize {
+        self.hit_objects.iter().filter(|h| h.is_slider()).count()
+    }
+
+    pub fn spinner_count(&self) -> usize {
+        self.hit_objects.iter().filter(|h| h.is_spinner()).count()
+    }
+
+    /// The playable length of this beatmap, in ms: from the start of the first
+    /// hit object to the end of the last one.
+    pub fn length(&self) -> f64 {
+        match self.hit_objects.first() {
+            Some(first) => {
+                let end_time = self
+                    .hit_objects
+                    .iter()
+                    .map(|h| h.end_time())
+                    .fold(first.start_time, f64::max);
+
+                end_time - first.start_time
+            }
+            None => 0.0,
+        }
+    }
+
+    /// The beat lengths used by this beatmap, each with how long (in ms) it
+    /// lasts. Timing points after the last hit object are ignored, unless
+    /// there's only one.
+    fn beat_length_durations(&self) -> Vec<(f64, f64)> {
+        let last_time = self
+            .hit_objects
+            .iter()
+            .map(|h| h.end_time())
+            .fold(0.0, f64::max);
+        let uninherited: Vec<&TimingPoint> = self
+            .timing_points
+            .iter()
+            .filter(|tp| tp.uninherited)
+            .collect();
+
+        uninherited
+            .iter()
+            .enumerate()
+            .filter(|(i, tp)| *i == 0 || tp.time <= last_time)
+            .map(|(i, tp)| {
+                let end_time = uninherited
+                    .get(i + 1)
+                    .map_or(last_time, |next| next.time.min(last_time));
+                // The first timing point applies from the start of the beatmap.
+                let start_time = if i == 0 { 0.0 } else { tp.time };
+
+                (tp.beat_length, (end_time - start_time).max(0.0))
+            })
+            .collect()
+    }
+
+    /// The most common BPM of this beatmap (the one used for the longest
+    /// time), or 0 if it has no timing points.
+    pub fn bpm(&self) -> f64 {
+        let mut durations: HashMap<i64, (f64, f64)> = HashMap::new();
+        for (beat_length, duration) in self.beat_length_durations() {
+            // Group beat lengths that only differ by rounding errors.
+            let entry = durations
+                .entry((beat_length * 1000.0).round() as i64)
+                .or_insert((beat_length, 0.0));
+            entry.1 += duration;
+        }
+
+        durations
+            .values()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+            .map_or(0.0, |(beat_length, _)| 60000.0 / beat_length)
+    }
+
+    /// The lowest and highest BPM of this beatmap, or (0, 0) if it has no
+    /// timing points.
+    pub fn bpm_range(&self) -> (f64, f64) {
+        let bpms: Vec<f64> = self
+            .beat_length_durations()
+            .into_iter()
+            .map(|(beat_length, _)| 60000.0 / beat_length)
+            .collect();
+
+        if bpms.is_empty() {
+            (0.0, 0.0)
+        } else {
+            (
+                bpms.iter().cloned().fold(std::f64::INFINITY, f64::min),
+                bpms.iter().cloned().fold(0.0, f64::max),
+            )
+        }
+    }
+
+    /// Computes the velocity, duration and nested objects of every slider.
+    fn compute_slider_timings(&mut self) {
+        let mut timings = Vec::new();
+        let mut nested_count = 0;
+
+        for hit_object in &self.hit_objects {
+            if let HitObjectKind::Slider(ref slider) = hit_object.kind {
+                let beat_length = self
+                    .timing_point_at(hit_object.start_time)
+                    .map(|tp| tp.beat_length)
+                    .unwrap_or(1000.0);
+                let speed_multiplier = self.speed_multiplier_at(hit_object.start_time);
+
+                let scoring_distance =
+                    BASE_SCORING_DISTANCE * self.difficulty.slider_multiplier * speed_multiplier;
+                let velocity = scoring_distance / beat_length;
+                let tick_distance_multiplier = if self.format_version < 8 {
+                    1.0 / speed_multiplier
+                } else {
+                    1.0
+                };
+                let tick_distance =
+                    scoring_distance / self.difficulty.slider_tick_rate * tick_distance_multiplier;
+
+                let distance = slider.pixel_length.unwrap_or(0.0);
+                let span_duration = distance / velocity;
+
+                let nested = slider_events(
+                    hit_object.start_time,
+                    span_duration,
+                    velocity,
+                    tick_distance,
+                    distance,
+                    slider.span_count,
+                    MAX_NESTED_OBJECTS.saturating_sub(nested_count),
+                );
+                nested_count += nested.len();
+
+                timings.push(Some((velocity, span_duration, nested)));
+            } else {
+                timings.push(None);
+            }
+        }
+
+        for (hit_object, timing) in self.hit_objects.iter_mut().zip(timings) {
+            if let (HitObjectKind::Slider(ref mut slider), Some((velocity, duration, nested))) =
+                (&mut hit_object.kind, timing)
+            {
+                slider.velocity = velocity;
+                slider.span_duration = duration;
+                slider.nested = nested;
+            }
+        }
+    }
+}
+
+/// Generates the ticks, repeats and tail of a slider, in chronological order.
+/// Only the first `max_ticks` ticks and repeats are generated.
+fn slider_events(
+    start_time: f64,
+    span_duration: f64,
+    velocity: f64,
+    tick_distance: f64,
+    total_distance: f64,
+    span_count: usize,
+    max_ticks: usize,
+) -> Vec<NestedObject> {
+    let mut events = Vec::new();
+
+    let length = total_distance.min(MAX_SLIDER_LENGTH);
+    let tick_distance = tick_distance.max(0.0).min(length);
+    let min_distance_from_end = velocity * 10.0;
+
+    if tick_distance > 0.0 {
+        for span in 0..span_count {
+            if events.len() >= max_ticks {
+                break;
+            }
+            let span_start_time = start_time + span as f64 * span_duration;
+            let reversed = span % 2 == 1;
+
+            let mut ticks = Vec::new();
+            let mut d = tick_distance;
+            while d <= length && events.len() + ticks.len() < max_ticks {
+                if d >= length - min_distance_from_end {
+                    break;
+                }
+
+                let path_progress = d / length;
+                let time_progress = if reversed {
+                    1.0 - path_progress
+                } else {
+                    path_progress
+                };
+
+                ticks.push(NestedObject {
+                    kind: NestedKind::Tick,
+                    time: span_start_time + time_progress * span_duration,
+                });
+
+                d += tick_distance;
+            }
+
+            if reversed {
+                ticks.reverse();
+            }
+            events.extend(ticks);
+
+            if span < span_count - 1 {
+                events.push(NestedObject {
+                    kind: NestedKind::Repeat,
+                    time: span_start_time + span_duration,
+                });
+            }
+        }
+    }
+
+    let total_duration = span_count as f64 * span_duration;
+    let final_span_start_time = start_time + (span_count - 1) as f64 * span_duration;
+    let final_span_end_time = (start_time + total_duration / 2.0)
+        .max(final_span_start_time + span_duration - LEGACY_LAST_TICK_OFFSET);
+
+    events.push(NestedObject {
+        kind: NestedKind::Tail,
+        time: final_span_end_time,
+    });
+
+    events
+}
+
+/// Parses a line of the `[TimingPoints]` section.
+fn parse_timing_point(line: &str) -> Result<TimingPoint, ParseError> {
+    let split: Vec<&str> = line.split(',').collect();
+
+    if split.len() < 2 {
+        return Err(ParseError::new(format!("invalid timing point: {:?}", line)));
+    }
+
+    let time = parse_number(split[0], "timing point time")?;
+    let beat_length: f64 = parse_number(split[1], "beat length")?;
+    let uninherited = if split.len() >= 7 {
+        split[6].trim() != "0"
+    } else {
+        true
+    };
+
+    if beat_length.is_nan() {
+        return Err(ParseError::new(format!("invalid beat length: {:?}", line)));
+    }
+
+    Ok(TimingPoint {
+        time: time,
+        beat_length: beat_length,
+        uninherited: uninherited && beat_length > 0.0,
+    })
+}
+
+/// Whether the three points `p` lie on a straight line.
+fn is_linear(p: &[Position]) -> bool {
+    ((p[1].y - p[0].y) * (p[2].x - p[0].x) - (p[1].x - p[0].x) * (p[2].y - p[0].y)).abs() < 1e-3
+}
+
+/// Parses a line of the `[HitObjects]` section.
+fn parse_hit_object(line: &str) -> Result<HitObject, ParseError> {
+    let split: Vec<&str> = line.split(',').collect();
+
+    if split.len() < 4 {
+        return Err(ParseError::new(format!("invalid hit object: {:?}", line)));
+    }
+
+    let x: f64 = parse_number(split[0], "hit object x")?;
+    let y: f64 = parse_number(split[1], "hit object y")?;
+    let position = Position::new(x.trunc(), y.trunc());
+    let start_time = parse_number(split[2], "hit object time")?;
+    let object_type: i32 = parse_number(split[3], "hit object type")?;
+
+    let kind = if object_type & 1 != 0 {
+        HitObjectKind::Circle
+    } else if object_type & 2 != 0 {
+        if split.len() < 7 {
+            return Err(ParseError::new(format!("invalid slider: {:?}", line)));
+        }
+
+        let mut curve_type = CurveType::Catmull;
+        let mut control_points = vec![Position::default()];
+
+        for point in split[5].split('|') {
+            if point.len() == 1 {
+                curve_type = match point {
+                    "L" => CurveType::Linear,
+                    "P" => CurveType::PerfectCurve,
+                    "B" => CurveType::Bezier,
+                    _ => CurveType::Catmull,
+                };
+                continue;
+            }
+
+            let mut coords = point.split(':');
+            let px: f64 = parse_number(coords.next().unwrap_or(""), "control point")?;
+            let py: f64 = parse_number(coords.next().unwrap_or(""), "control point")?;
+            control_points.push(Position::new(px.trunc(), py.trunc()).sub(position));
+        }
+
+        // osu!stable treats collinear perfect curves as linear ones.
+        if control_points.len() == 3
+            && curve_type == CurveType::PerfectCurve
+            && is_linear(&control_points)
+        {
+            curve_type = CurveType::Linear;
+        }
+
+        let repeats: usize = parse_number(split[6], "slider repeats")?;
+        let pixel_length = if split.len() > 7 {
+            Some(parse_number::<f64>(split[7], "slider length")?).filter(|l| *l > 0.0)
+        } else {
+            None
+        };
+
+        HitObjectKind::Slider(Slider {
+            curve_type: curve_type,
+            control_points: control_points,
+            span_count: repeats.max(1).min(MAX_SLIDER_SPANS),
+            pixel_length: pixel_length,
+            span_duration: 0.0,
+            velocity: 0.0,
+            nested: Vec::new(),
+        })
+    } else if object_type & 8 != 0 {
+        let end_time = if split.len() > 5 {
+            parse_number(split[5], "spinner end time")?
+        } else {
+            start_time
+        };
+
+        HitObjectKind::Spinner { end_time: end_time }
+    } else {
+        // Mania holds, or unknown objects. Treat them as circles, since
+        // they'll only ever be counted.
+        HitObjectKind::Circle
+    };
+
+    Ok(HitObject {
+        position: position,
+        start_time: start_time,
+        new_combo: object_type & 4 != 0,
+        kind: kind,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub const TEST_BEATMAP: &str = "osu file format v14
+
+[General]
+StackLeniency: 0.7
+Mode: 0
+
+[Metadata]
+Title:Test Song
+Artist:Test Artist
+Creator:Test Mapper
+Version:Insane
+BeatmapID:123
+BeatmapSetID:45
+
+[Difficulty]
+HPDrainRate:5
+CircleSize:4
+OverallDifficulty:8
+ApproachRate:9
+SliderMultiplier:1.4
+SliderTickRate:1
+
+[TimingPoints]
+0,500,4,2,0,100,1,0
+2000,-200,4,2,0,100,0,0
+
+[HitObjects]
+64,64,0,1,0,0:0:0:0:
+192,64,500,5,0,0:0:0:0:
+256,192,1000,2,0,L|384:192,1,140
+256,192,2000,2,0,B|384:192|384:320,2,140
+256,192,4000,12,0,5000,0:0:0:0:
+";
+
+    #[test]
+    fn test_parse_beatmap() {
+        let beatmap = Beatmap::parse(TEST_BEATMAP).unwrap();
+
+        assert_eq!(beatmap.format_version, 14);
+        assert_eq!(beatmap.metadata.title, "Test Song");
+        assert_eq!(beatmap.metadata.beatmap_id, Some(123));
+        assert_eq!(beatmap.difficulty.approach_rate, 9.0);
+        assert_eq!(beatmap.hit_objects.len(), 5);
+        assert_eq!(beatmap.circle_count(), 2);
+        assert_eq!(beatmap.slider_count(), 2);
+        assert_eq!(beatmap.spinner_count(), 1);
+    }
+
+    #[test]
+    fn test_max_combo() {
+        let beatmap = Beatmap::parse(TEST_BEATMAP).unwrap();
+
+        // First slider: 140px at 140px/beat, so no ticks, just head + tail.
+        // Second slider: half SV, 70px per beat, so one tick per span;
+        // two spans, a repeat, and the tail.
+        assert_eq!(beatmap.hit_objects[2].max_combo(), 2);
+        assert_eq!(beatmap.hit_objects[3].max_combo(), 5);
+        assert_eq!(beatmap.max_combo(), 2 + 2 + 5 + 1);
+    }
+
+    #[test]
+    fn test_bpm_and_length() {
+        let beatmap = Beatmap::parse(TEST_BEATMAP).unwrap();
+
+        assert_eq!(beatmap.bpm(), 120.0);
+        assert_eq!(beatmap.bpm_range(), (120.0, 120.0));
+        assert_eq!(beatmap.length(), 5000.0);
+
+        let variable_bpm = TEST_BEATMAP.replace(
+            "2000,-200,4,2,0,100,0,0",
+            "1000,250,4,2,0,100,1,0\n4500,500,4,2,0,100,1,0\n9000,100,4,2,0,100,1,0",
+        );
+        let beatmap = Beatmap::parse(&variable_bpm).unwrap();
+        // 240 BPM from 1000 to 4500ms is the longest section.
+        assert_eq!(beatmap.bpm(), 240.0);
+        assert_eq!(beatmap.bpm_range(), (120.0, 240.0));
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert!(Beatmap::parse("<html><body>404</body></html>").is_err());
+    }
+
+    #[test]
+    fn test_non_finite_numbers() {
+        for (from, to) in [
+            ("192,64,500,5", "192,64,nan,5"),
+            ("2000,-200,4", "inf,-200,4"),
+            ("0,500,4", "0,NaN,4"),
+            ("192,64,500,5", "192,64,1e999,5"),
+        ]
+        .iter()
+        {
+            let contents = TEST_BEATMAP.replace(from, to);
+            let error = Beatmap::parse(&contents).unwrap_err();
+            assert!(error.to_string().contains(": invalid "), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_hostile_sliders() {
+        let contents = TEST_BEATMAP
+            .replace("SliderMultiplier:1.4", "SliderMultiplier:0.0001")
+            .replace("SliderTickRate:1", "SliderTickRate:1e9")
+            .replace("L|384:192,1,140", "L|384:192,4000000000,100000");
+        let beatmap = Beatmap::parse(&contents).unwrap();
+        assert_eq!(beatmap.difficulty.slider_multiplier, 0.4);
+        assert_eq!(beatmap.difficulty.slider_tick_rate, 8.0);
+
+        let mut nested = 0;
+        for hit_object in &beatmap.hit_objects {
+            if let HitObjectKind::Slider(ref slider) = hit_object.kind {
+                assert!(slider.span_count <= MAX_SLIDER_SPANS);
+                nested += slider.nested.len();
+            }
+        }
+        assert!(nested <= MAX_NESTED_OBJECTS + 4, "{} nested", nested);
+    }
+}
@@ -0,0 +1,1073 @@
+//! A cache of beatmap (.osu) files, fetched from a `BeatmapSource`.
+//!
+//! Downloads are written into a temporary file, validated, and only then
+//! renamed into place, so a crash or a failed download never leaves a
+//! truncated beatmap behind. Cached files that don't look like a .osu file
+//! (for example, ones cached by older versions) are deleted and downloaded
+//! again.
+//!
+//! If enabled, downloads are also checked against the MD5 checksum the osu!
+//! api reports for the beatmap (unless the source is offline).
+//!
+//! The cache can be bounded by size and/or number of files, in which case the
+//! least recently used beatmaps are evicted. Access times are tracked in an
+//! index file on the cache directory, which is rebuilt by scanning the directory
+//! on startup.
+//!
+//! Concurrent requests for the same uncached beatmap are deduplicated: only the
+//! first one fetches it, and the others wait for (and share) its result.
+//!
+//! Beatmaps can also be found by the MD5 checksum of their .osu file, which is
+//! how replays refer to them. Checksums of cached beatmaps are kept on the index.
+use super::beatmap_source::BeatmapSource;
+use super::config_functions::api_key;
+use super::performance_calculator::CalculationError;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The first line of every .osu file starts with this (after an optional BOM).
+const OSU_FILE_HEADER: &str = "osu file format v";
+
+/// The extension of partially written files.
+const TEMP_EXTENSION: &str = "tmp";
+
+/// The name of the index file, on the cache directory.
+const INDEX_FILE: &str = "index.json";
+
+/// How often cache hits alone cause the index file to be saved. Downloads
+/// and evictions always save it.
+const INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Beatmaps accessed more recently than this aren't evicted, even if the cache
+/// is over its limits, as a calculation may still be reading them.
+const EVICTION_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// Used to give every temporary file an unique name.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether `contents` look like a .osu file: that is, if they start with the
+/// .osu file format header.
+pub fn is_osu_file(contents: &[u8]) -> bool {
+    let contents = if contents.starts_with(b"\xEF\xBB\xBF") {
+        &contents[3..]
+    } else {
+        contents
+    };
+
+    let start = contents
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(contents.len());
+
+    contents[start..].starts_with(OSU_FILE_HEADER.as_bytes())
+}
+
+/// The MD5 checksum of `contents`, as a lowercase hex string (the format the
+/// osu! api uses).
+pub fn md5_hex(contents: &[u8]) -> String {
+    format!("{:x}", md5::compute(contents))
+}
+
+/// Writes `contents` into `path` atomically: they're written into a temporary
+/// file on the same directory, synced, and then renamed to `path`.
+///
+/// # Errors
+///
+/// Will error if the temporary file couldn't be written, or renamed.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.to_path_buf();
+    temp_path.set_extension(format!(
+        "{}.{}.{}",
+        process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::SeqCst),
+        TEMP_EXTENSION
+    ));
+
+    let result = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// A beatmap, as returned by the osu! api `get_beatmaps` endpoint. Only the
+/// fields we need are here.
+#[derive(Deserialize)]
+struct ApiBeatmap {
+    file_md5: String,
+}
+
+/// Asks the osu! api for the MD5 checksum of the .osu file of `beatmap_id`.
+///
+/// # Errors
+///
+/// Will return `CalculationError::BeatmapNotFound` if the api doesn't know
+/// about the beatmap, or `CalculationError::ApiFailure` if the request failed.
+fn fetch_file_md5(beatmap_id: i64) -> Result<String, CalculationError> {
+    let mut resp = reqwest::get(&format!(
+        "https://osu.ppy.sh/api/get_beatmaps?k={}&b={}",
+        api_key(),
+        beatmap_id
+    ))?;
+    if !resp.status().is_success() {
+        return Err(CalculationError::ApiFailure(format!(
+            "get_beatmaps returned {}",
+            resp.status()
+        )));
+    }
+
+    let beatmaps: Vec<ApiBeatmap> = resp.json()?;
+    beatmaps
+        .into_iter()
+        .next()
+        .map(|beatmap| beatmap.file_md5.to_lowercase())
+        .ok_or(CalculationError::BeatmapNotFound(beatmap_id))
+}
+
+/// `time`, in milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+/// The beatmap id of a cached .osu file at `path`, if it is one.
+fn cached_beatmap_id(path: &Path) -> Option<i64> {
+    if path.extension().map_or(true, |ext| ext != "osu") {
+        return None;
+    }
+
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// The maximum size of the cache. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CacheLimits {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<usize>,
+}
+
+/// A cached beatmap, as tracked on the index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexEntry {
+    size: u64,
+    /// In milliseconds since the Unix epoch.
+    last_access: u64,
+    /// The MD5 checksum of the file, if it was computed already.
+    #[serde(default)]
+    md5: Option<String>,
+    /// Whether the file is an outdated version of the beatmap, to be replaced
+    /// the next time it's needed.
+    #[serde(default)]
+    stale: bool,
+}
+
+/// The cached beatmaps, and how often they were found in cache.
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<i64, IndexEntry>,
+    hits: u64,
+    misses: u64,
+    last_save: Option<Instant>,
+}
+
+impl CacheIndex {
+    fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    fn is_over(&self, limits: &CacheLimits) -> bool {
+        limits
+            .max_files
+            .map_or(false, |max| self.entries.len() > max)
+            || limits
+                .max_bytes
+                .map_or(false, |max| self.total_bytes() > max)
+    }
+
+    /// The least recently used beatmap, other than `keep` and the ones accessed
+    /// within the `EVICTION_GRACE` period.
+    fn least_recently_used(&self, keep: Option<i64>) -> Option<i64> {
+        let grace_start =
+            millis(SystemTime::now()).saturating_sub(EVICTION_GRACE.as_millis() as u64);
+
+        self.entries
+            .iter()
+            .filter(|(id, entry)| Some(**id) != keep && entry.last_access < grace_start)
+            .min_by_key(|(id, entry)| (entry.last_access, **id))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// The result of fetching a beatmap: the path of its cached file.
+type FetchResult = Result<String, CalculationError>;
+
+/// A fetch that's in progress. Callers that need the same beatmap wait for
+/// `result` to be set.
+#[derive(Default)]
+struct InFlight {
+    result: Mutex<Option<FetchResult>>,
+    done: Condvar,
+}
+
+impl InFlight {
+    fn finish(&self, result: FetchResult) {
+        *self.result.lock().unwrap() = Some(result);
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> FetchResult {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            match *result {
+                Some(ref result) => return result.clone(),
+                None => result = self.done.wait(result).unwrap(),
+            }
+        }
+    }
+}
+
+/// Finishes an in-flight fetch when dropped, and removes it from the cache, so
+/// waiting callers are never left hanging (even if the fetch panics).
+struct FetchGuard<'a> {
+    cache: &'a BeatmapCache,
+    beatmap_id: i64,
+    in_flight: Arc<InFlight>,
+    result: Option<FetchResult>,
+}
+
+impl<'a> Drop for FetchGuard<'a> {
+    fn drop(&mut self) {
+        self.cache
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.beatmap_id);
+
+        let result = self.result.take().unwrap_or_else(|| {
+            Err(CalculationError::Io(format!(
+                "the download of beatmap {} was interrupted",
+                self.beatmap_id
+            )))
+        });
+        self.in_flight.finish(result);
+    }
+}
+
+/// Statistics about the cache, for the admin endpoint. Hits and misses are
+/// counted since startup.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub limits: CacheLimits,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+}
+
+/// A directory of cached .osu files, named after their beatmap ids.
+pub struct BeatmapCache {
+    dir: PathBuf,
+    source: Box<dyn BeatmapSource>,
+    verify_md5: bool,
+    limits: CacheLimits,
+    index: Mutex<CacheIndex>,
+    /// The beatmaps being fetched right now.
+    in_flight: Mutex<HashMap<i64, Arc<InFlight>>>,
+}
+
+impl BeatmapCache {
+    /// Creates a new cache on `dir`, fetching missing beatmaps from `source`.
+    /// If `verify_md5` is set, and `source` is online, downloads are checked
+    /// against the checksum reported by the osu! api, which needs an api key.
+    /// Least recently used beatmaps are evicted to keep the cache within `limits`.
+    ///
+    /// Leftover temporary files (from downloads interrupted by a crash) are
+    /// removed, and the index is rebuilt from the files on `dir`.
+    pub fn new<P: Into<PathBuf>>(
+        dir: P,
+        source: Box<dyn BeatmapSource>,
+        verify_md5: bool,
+        limits: CacheLimits,
+    ) -> Self {
+        let verify_md5 = verify_md5 && source.is_online();
+        let cache = BeatmapCache {
+            dir: dir.into(),
+            source: source,
+            verify_md5: verify_md5,
+            limits: limits,
+            index: Mutex::new(CacheIndex::default()),
+            in_flight: Mutex::new(HashMap::new()),
+        };
+
+        cache.remove_temp_files();
+        cache.rebuild_index();
+        cache
+    }
+
+    /// Loads the access times stored on the index file, if any.
+    fn load_index(&self) -> HashMap<i64, IndexEntry> {
+        fs::read(self.dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Saves the index file, if the cache directory exists.
+    fn save_index(&self, index: &mut CacheIndex) {
+        if !self.dir.is_dir() {
+            return;
+        }
+
+        let result = serde_json::to_vec(&index.entries)
+            .map_err(io::Error::from)
+            .and_then(|contents| write_atomically(&self.dir.join(INDEX_FILE), &contents));
+
+        match result {
+            Ok(_) => index.last_save = Some(Instant::now()),
+            Err(e) => println!("Couldn't save the beatmaps cache index: {}", e),
+        }
+    }
+
+    /// Scans the cache directory, and rebuilds the index from the files on it.
+    /// Access times are taken from the index file when present, and from the
+    /// file modification time otherwise. Then, evicts beatmaps if the cache is
+    /// over its limits.
+    fn rebuild_index(&self) {
+        let stored = self.load_index();
+        let mut entries = HashMap::new();
+
+        if let Ok(dir_entries) = fs::read_dir(&self.dir) {
+            for dir_entry in dir_entries.filter_map(Result::ok) {
+                let path = dir_entry.path();
+                let (beatmap_id, metadata) = match (cached_beatmap_id(&path), dir_entry.metadata())
+                {
+                    (Some(beatmap_id), Ok(metadata)) => (beatmap_id, metadata),
+                    _ => continue,
+                };
+
+                let stored_entry = stored.get(&beatmap_id);
+                let last_access = match stored_entry {
+                    Some(entry) => entry.last_access,
+                    None => millis(metadata.modified().unwrap_or(UNIX_EPOCH)),
+                };
+                // Checksums of files that changed size are computed again.
+                let md5 = stored_entry
+                    .filter(|entry| entry.size == metadata.len())
+                    .and_then(|entry| entry.md5.clone());
+
+                entries.insert(
+                    beatmap_id,
+                    IndexEntry {
+                        size: metadata.len(),
+                        last_access: last_access,
+                        md5: md5,
+                        stale: stored_entry.map_or(false, |entry| entry.stale),
+                    },
+                );
+            }
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.entries = entries;
+        self.evict(&mut index, None);
+        self.save_index(&mut index);
+    }
+
+    /// Evicts least recently used beatmaps (other than `keep`) until the cache
+    /// is within its limits, or only recently used ones are left.
+    fn evict(&self, index: &mut CacheIndex, keep: Option<i64>) {
+        while index.is_over(&self.limits) {
+            let beatmap_id = match index.least_recently_used(keep) {
+                Some(beatmap_id) => beatmap_id,
+                None => break,
+            };
+
+            println!("Evicting beatmap {} from cache.", beatmap_id);
+            let _ = fs::remove_file(self.path(beatmap_id));
+            index.entries.remove(&beatmap_id);
+        }
+    }
+
+    /// Records a cache hit on `beatmap_id`. The index file is saved at most
+    /// once every `INDEX_SAVE_INTERVAL` for hits.
+    fn record_hit(&self, beatmap_id: i64, size: u64) {
+        let mut index = self.index.lock().unwrap();
+        index.hits += 1;
+        let md5 = index
+            .entries
+            .get(&beatmap_id)
+            .filter(|entry| entry.size == size)
+            .and_then(|entry| entry.md5.clone());
+        index.entries.insert(
+            beatmap_id,
+            IndexEntry {
+                size: size,
+                last_access: millis(SystemTime::now()),
+                md5: md5,
+                stale: false,
+            },
+        );
+
+        let save_due = index
+            .last_save
+            .map_or(true, |last_save| last_save.elapsed() >= INDEX_SAVE_INTERVAL);
+        if save_due {
+            self.save_index(&mut index);
+        }
+    }
+
+    /// Records a newly cached `beatmap_id`, with its file `md5`, and evicts
+    /// others if needed.
+    fn record_insert(&self, beatmap_id: i64, size: u64, md5: String) {
+        let mut index = self.index.lock().unwrap();
+        index.entries.insert(
+            beatmap_id,
+            IndexEntry {
+                size: size,
+                last_access: millis(SystemTime::now()),
+                md5: Some(md5),
+                stale: false,
+            },
+        );
+
+        self.evict(&mut index, Some(beatmap_id));
+        self.save_index(&mut index);
+    }
+
+    /// The current size of the cache, its limits, and its hit rate.
+    pub fn stats(&self) -> CacheStats {
+        let index = self.index.lock().unwrap();
+        let lookups = index.hits + index.misses;
+
+        CacheStats {
+            files: index.entries.len(),
+            bytes: index.total_bytes(),
+            limits: self.limits,
+            hits: index.hits,
+            misses: index.misses,
+            hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                index.hits as f64 / lookups as f64
+            },
+        }
+    }
+
+    /// Removes every temporary file on the cache directory.
+    fn remove_temp_files(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == TEMP_EXTENSION) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Where the .osu file of `beatmap_id` is (or would be) cached.
+    pub fn path(&self, beatmap_id: i64) -> PathBuf {
+        self.dir.join(format!("{}.osu", beatmap_id))
+    }
+
+    /// Whether `contents` are what the index has for `beatmap_id`: the same
+    /// size, and the same checksum (if it's known). Beatmaps that aren't on
+    /// the index yet are taken as they are.
+    fn matches_index(&self, beatmap_id: i64, contents: &[u8]) -> bool {
+        let entry = self.index.lock().unwrap().entries.get(&beatmap_id).cloned();
+
+        entry.map_or(true, |entry| {
+            !entry.stale
+                && entry.size == contents.len() as u64
+                && entry.md5.map_or(true, |md5| md5 == md5_hex(contents))
+        })
+    }
+
+    /// The path of the cached .osu file of `beatmap_id`, if it's cached and
+    /// valid. Corrupt files are removed, and files that don't match the index
+    /// (like truncated ones) are left to be fetched again. Counts as a cache
+    /// hit or miss.
+    fn cached(&self, beatmap_id: i64) -> Option<PathBuf> {
+        let path = self.path(beatmap_id);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(_) => {
+                self.index.lock().unwrap().misses += 1;
+                return None;
+            }
+        };
+
+        if is_osu_file(&contents) && !self.matches_index(beatmap_id, &contents) {
+            println!("Cached beatmap {} doesn't match the index.", beatmap_id);
+            self.index.lock().unwrap().misses += 1;
+
+            None
+        } else if is_osu_file(&contents) {
+            self.record_hit(beatmap_id, contents.len() as u64);
+
+            Some(path)
+        } else {
+            println!("Cached beatmap {} is corrupt, removing it.", beatmap_id);
+            let _ = fs::remove_file(&path);
+
+            let mut index = self.index.lock().unwrap();
+            index.entries.remove(&beatmap_id);
+            index.misses += 1;
+
+            None
+        }
+    }
+
+    /// Obtains the path for a beatmap's .osu file. If the beatmap isn't
+    /// currently cached (or its cached file is corrupt), fetches it from
+    /// the source. If it's already being fetched, waits for that instead.
+    ///
+    /// # Errors
+    ///
+    /// Will error if the cache directory doesn't exist and can't be created;
+    /// if the beatmap doesn't exist (`CalculationError::BeatmapNotFound`); if
+    /// it couldn't be downloaded or saved; or if the download isn't a .osu
+    /// file, or doesn't match its checksum (`CalculationError::ApiFailure`).
+    pub fn get(&self, beatmap_id: i64) -> Result<String, CalculationError> {
+        if let Some(path) = self.cached(beatmap_id) {
+            return Ok(path.to_str().unwrap().to_string());
+        }
+
+        let in_flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(fetch) = in_flight.get(&beatmap_id) {
+                let fetch = fetch.clone();
+                drop(in_flight);
+
+                return fetch.wait();
+            }
+
+            let fetch = Arc::new(InFlight::default());
+            in_flight.insert(beatmap_id, fetch.clone());
+            fetch
+        };
+
+        let mut guard = FetchGuard {
+            cache: self,
+            beatmap_id: beatmap_id,
+            in_flight: in_flight,
+            result: None,
+        };
+
+        // Another fetch may have finished since we looked at the cache.
+        let path = self.path(beatmap_id);
+        let fetched = fs::read(&path).map_or(false, |contents| {
+            is_osu_file(&contents) && self.matches_index(beatmap_id, &contents)
+        });
+        let result = if fetched {
+            Ok(path.to_str().unwrap().to_string())
+        } else {
+            self.fetch(beatmap_id)
+        };
+
+        guard.result = Some(result.clone());
+        result
+    }
+
+    /// Gets every beatmap on `beatmap_ids`, so they're cached before they're
+    /// needed. Failures are only logged.
+    pub fn prefetch(&self, beatmap_ids: &[i64]) {
+        for &beatmap_id in beatmap_ids {
+            if let Err(e) = self.get(beatmap_id) {
+                println!("Couldn't prefetch beatmap {}: {}", beatmap_id, e);
+            }
+        }
+    }
+
+    /// Fetches `beatmap_id` from the source, validates it, and caches it.
+    fn fetch(&self, beatmap_id: i64) -> FetchResult {
+        fs::create_dir_all(&self.dir)?;
+
+        let contents = self.source.fetch(beatmap_id)?;
+        if !is_osu_file(&contents) {
+            return Err(CalculationError::ApiFailure(format!(
+                "the download of beatmap {} isn't a .osu file",
+                beatmap_id
+            )));
+        }
+
+        let actual = md5_hex(&contents);
+        if self.verify_md5 {
+            let expected = fetch_file_md5(beatmap_id)?;
+
+            if actual != expected {
+                return Err(CalculationError::ApiFailure(format!(
+                    "the download of beatmap {} has checksum {}, expected {}",
+                    beatmap_id, actual, expected
+                )));
+            }
+        }
+
+        let path = self.path(beatmap_id);
+        write_atomically(&path, &contents)?;
+        self.record_insert(beatmap_id, contents.len() as u64, actual);
+
+        Ok(path.to_str().unwrap().to_string())
+    }
+
+    /// The cached beatmap whose .osu file has the checksum `md5`, if any. The
+    /// checksums missing from the index are computed (without holding its lock)
+    /// and saved on the way.
+    fn find_cached_md5(&self, md5: &str) -> Option<i64> {
+        let unhashed: Vec<i64> = {
+            let index = self.index.lock().unwrap();
+            let found = index.entries.iter().find(|(_, entry)| {
+                !entry.stale && entry.md5.as_ref().map(String::as_str) == Some(md5)
+            });
+            if let Some((beatmap_id, _)) = found {
+                return Some(*beatmap_id);
+            }
+
+            index
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.stale && entry.md5.is_none())
+                .map(|(beatmap_id, _)| *beatmap_id)
+                .collect()
+        };
+        if unhashed.is_empty() {
+            return None;
+        }
+
+        let hashes: Vec<(i64, String)> = unhashed
+            .into_iter()
+            .filter_map(|beatmap_id| {
+                let contents = fs::read(self.path(beatmap_id)).ok()?;
+                Some((beatmap_id, md5_hex(&contents)))
+            })
+            .collect();
+
+        let mut index = self.index.lock().unwrap();
+        for (beatmap_id, hash) in &hashes {
+            if let Some(entry) = index.entries.get_mut(beatmap_id) {
+                entry.md5 = Some(hash.clone());
+            }
+        }
+        self.save_index(&mut index);
+
+        hashes
+            .into_iter()
+            .find(|(_, hash)| hash == md5)
+            .map(|(beatmap_id, _)| beatmap_id)
+    }
+
+    /// Finds the id of the beatmap whose .osu file has the MD5 checksum `md5`:
+    /// first among the cached beatmaps, and then on the source. If the source
+    /// finds a beatmap that's cached with another checksum (an older version of
+    /// it), the cached file is marked as stale, so it's fetched again (and
+    /// replaced) the next time it's needed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::UnknownBeatmapHash` if neither the cache
+    /// nor the source know about the beatmap, or another error if the source
+    /// lookup failed.
+    pub fn find_by_md5(&self, md5: &str) -> Result<i64, CalculationError> {
+        let md5 = md5.to_lowercase();
+        if let Some(beatmap_id) = self.find_cached_md5(&md5) {
+            return Ok(beatmap_id);
+        }
+
+        let beatmap_id = self
+            .source
+            .find_by_md5(&md5)?
+            .ok_or_else(|| CalculationError::UnknownBeatmapHash(md5.clone()))?;
+
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.entries.get_mut(&beatmap_id) {
+            if !entry.stale {
+                println!("Cached beatmap {} is outdated.", beatmap_id);
+                entry.stale = true;
+                self.save_index(&mut index);
+            }
+        }
+
+        Ok(beatmap_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap_source::OfflineSource;
+    use std::env;
+
+    /// A new, empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("beatmap_cache_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_is_osu_file() {
+        assert!(is_osu_file(b"osu file format v14\r\n\r\n[General]"));
+        assert!(is_osu_file(b"\xEF\xBB\xBFosu file format v9\n"));
+        assert!(is_osu_file(b"\r\n  osu file format v3"));
+        assert!(!is_osu_file(b"<html><body>502 Bad Gateway</body></html>"));
+        assert!(!is_osu_file(b"osu file form"));
+        assert!(!is_osu_file(b""));
+    }
+
+    #[test]
+    fn test_md5_hex() {
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+    }
+
+    #[test]
+    fn test_corrupt_files_are_removed() {
+        let dir = test_dir("corrupt");
+        fs::write(dir.join("1.tmp"), "osu file fo").unwrap();
+        fs::write(dir.join("2.osu"), "osu file format v14\n").unwrap();
+        fs::write(dir.join("3.osu"), "<html>").unwrap();
+
+        let cache = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        assert!(!dir.join("1.tmp").exists());
+
+        assert_eq!(cache.cached(2), Some(dir.join("2.osu")));
+        assert_eq!(cache.get(2).unwrap(), dir.join("2.osu").to_str().unwrap());
+        assert_eq!(cache.cached(3), None);
+        assert!(!dir.join("3.osu").exists());
+        assert_eq!(cache.get(3), Err(CalculationError::BeatmapNotFound(3)));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Caches a beatmap with `size` bytes, accessed at `last_access`.
+    fn write_beatmap(dir: &Path, beatmap_id: i64, size: usize, last_access: u64) {
+        let mut contents = b"osu file format v14\n".to_vec();
+        contents.resize(size, b'\n');
+        fs::write(dir.join(format!("{}.osu", beatmap_id)), contents).unwrap();
+
+        let mut index: HashMap<i64, IndexEntry> = fs::read(dir.join(INDEX_FILE))
+            .map(|contents| serde_json::from_slice(&contents).unwrap())
+            .unwrap_or_default();
+        index.insert(
+            beatmap_id,
+            IndexEntry {
+                size: size as u64,
+                last_access: last_access,
+                md5: None,
+                stale: false,
+            },
+        );
+        fs::write(dir.join(INDEX_FILE), serde_json::to_vec(&index).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_startup_eviction() {
+        let dir = test_dir("startup");
+        write_beatmap(&dir, 1, 100, 3000);
+        write_beatmap(&dir, 2, 100, 1000);
+        write_beatmap(&dir, 3, 100, 2000);
+        fs::write(dir.join("notes.txt"), "not a beatmap").unwrap();
+
+        let limits = CacheLimits {
+            max_bytes: None,
+            max_files: Some(2),
+        };
+        let cache = BeatmapCache::new(dir.clone(), Box::new(OfflineSource), false, limits);
+        assert!(!dir.join("2.osu").exists());
+        assert!(dir.join("1.osu").exists() && dir.join("3.osu").exists());
+
+        let stats = cache.stats();
+        assert_eq!((stats.files, stats.bytes), (2, 200));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncated_files_are_fetched_again() {
+        let dir = test_dir("truncated");
+        write_beatmap(&dir, 1, 100, 1000);
+        write_beatmap(&dir, 2, 100, 1000);
+        let cache = BeatmapCache::new(
+            dir.clone(),
+            Box::new(UpdatedSource),
+            false,
+            CacheLimits::default(),
+        );
+        assert!(cache.cached(1).is_some());
+
+        // Still a .osu file, but shorter than the index says.
+        fs::write(dir.join("1.osu"), "osu file format v14\n").unwrap();
+        assert_eq!(cache.cached(1), None);
+        assert_eq!(cache.get(1), Err(CalculationError::BeatmapNotFound(1)));
+
+        // Same size, but not the checksum the index has.
+        cache.index.lock().unwrap().entries.get_mut(&2).unwrap().md5 = Some(md5_hex(b""));
+        assert_eq!(cache.cached(2), None);
+        cache.get(2).unwrap();
+        assert_eq!(
+            fs::read(dir.join("2.osu")).unwrap(),
+            UpdatedSource.fetch(2).unwrap()
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_index_rebuild() {
+        let dir = test_dir("rebuild");
+        write_beatmap(&dir, 1, 100, 1000);
+        write_beatmap(&dir, 2, 50, 2000);
+        // A beatmap added without going through the cache, and a stale entry.
+        fs::write(dir.join("3.osu"), "osu file format v14\n").unwrap();
+        fs::remove_file(dir.join("1.osu")).unwrap();
+
+        let cache = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        let index = cache.index.lock().unwrap();
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.entries[&2].last_access, 2000);
+        assert_eq!(index.entries[&3].size, 20);
+        drop(index);
+
+        let stored = cache.load_index();
+        assert!(stored.contains_key(&3) && !stored.contains_key(&1));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let dir = test_dir("lru");
+        write_beatmap(&dir, 1, 100, 1000);
+        write_beatmap(&dir, 2, 100, 2000);
+
+        let limits = CacheLimits {
+            max_bytes: Some(250),
+            max_files: None,
+        };
+        let cache = BeatmapCache::new(dir.clone(), Box::new(OfflineSource), false, limits);
+
+        // Beatmap 1 becomes the most recently used, so 2 is evicted instead.
+        assert!(cache.cached(1).is_some());
+        cache.record_insert(3, 100, String::new());
+        assert!(dir.join("1.osu").exists());
+        assert!(!dir.join("2.osu").exists());
+
+        // Recently used beatmaps are never evicted, even if the cache is over
+        // its limits, as they may be in use.
+        cache.record_insert(4, 1000, String::new());
+        assert_eq!(cache.stats().files, 3);
+        assert!(dir.join("1.osu").exists());
+
+        cache
+            .index
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&1)
+            .unwrap()
+            .last_access = 0;
+        cache.record_insert(5, 10, String::new());
+        assert!(!dir.join("1.osu").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = test_dir("stats");
+        write_beatmap(&dir, 1, 100, 1000);
+
+        let cache = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        assert_eq!(cache.stats().hit_rate, 0.0);
+
+        assert!(cache.get(1).is_ok());
+        assert!(cache.get(1).is_ok());
+        assert!(cache.get(2).is_err());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert!((stats.hit_rate - 2.0 / 3.0).abs() < 1e-9);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// A source that only knows about the beatmap 2, in its newer version.
+    struct UpdatedSource;
+
+    impl BeatmapSource for UpdatedSource {
+        fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+            match beatmap_id {
+                2 => Ok(b"osu file format v14\n\n[Metadata]\nVersion:2\n".to_vec()),
+                _ => Err(CalculationError::BeatmapNotFound(beatmap_id)),
+            }
+        }
+
+        fn find_by_md5(&self, md5: &str) -> Result<Option<i64>, CalculationError> {
+            Ok(Some(2).filter(|_| md5 == md5_hex(&self.fetch(2).unwrap())))
+        }
+    }
+
+    #[test]
+    fn test_find_by_md5() {
+        let dir = test_dir("md5");
+        write_beatmap(&dir, 1, 100, 1000);
+        write_beatmap(&dir, 2, 50, 2000);
+        let cached_md5 = md5_hex(&fs::read(dir.join("1.osu")).unwrap());
+
+        let cache = BeatmapCache::new(
+            dir.clone(),
+            Box::new(UpdatedSource),
+            false,
+            CacheLimits::default(),
+        );
+        assert_eq!(cache.find_by_md5(&cached_md5.to_uppercase()), Ok(1));
+        assert_eq!(
+            cache.load_index()[&1].md5.as_ref(),
+            Some(&cached_md5),
+            "checksums are saved on the index"
+        );
+        assert_eq!(
+            cache.find_by_md5("0123"),
+            Err(CalculationError::UnknownBeatmapHash("0123".to_string()))
+        );
+
+        // The cached beatmap 2 is outdated, so it's fetched again. Its file is
+        // only replaced once the new one is written.
+        let updated_md5 = md5_hex(&UpdatedSource.fetch(2).unwrap());
+        assert_eq!(cache.find_by_md5(&updated_md5), Ok(2));
+        assert!(dir.join("2.osu").exists());
+        assert!(cache.load_index()[&2].stale);
+        cache.get(2).unwrap();
+        assert_eq!(
+            fs::read(dir.join("2.osu")).unwrap(),
+            UpdatedSource.fetch(2).unwrap()
+        );
+        assert!(!cache.load_index()[&2].stale);
+        assert_eq!(cache.find_by_md5(&updated_md5), Ok(2));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// A source that counts its fetches, and holds each one until it's let
+    /// through the gate (or the gate is dropped).
+    struct GatedSource {
+        fetches: Arc<AtomicUsize>,
+        gate: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl BeatmapSource for GatedSource {
+        fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            let _ = self.gate.lock().unwrap().recv();
+
+            if beatmap_id == 1 {
+                Ok(b"osu file format v14\n".to_vec())
+            } else {
+                Err(CalculationError::BeatmapNotFound(beatmap_id))
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_fetches() {
+        let dir = test_dir("concurrent");
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (gate, receiver) = std::sync::mpsc::channel();
+        let source = GatedSource {
+            fetches: fetches.clone(),
+            gate: Mutex::new(receiver),
+        };
+        let cache = Arc::new(BeatmapCache::new(
+            dir.clone(),
+            Box::new(source),
+            false,
+            CacheLimits::default(),
+        ));
+
+        for &beatmap_id in [1, 2].iter() {
+            fetches.store(0, Ordering::SeqCst);
+
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let cache = cache.clone();
+                    std::thread::spawn(move || cache.get(beatmap_id))
+                })
+                .collect();
+
+            // The fetch is only let through once every other thread waits for
+            // it: the map, the fetching thread and each waiter hold the fetch.
+            loop {
+                let waiting = cache
+                    .in_flight
+                    .lock()
+                    .unwrap()
+                    .get(&beatmap_id)
+                    .map_or(0, Arc::strong_count);
+                if waiting == 9 {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            gate.send(()).unwrap();
+            let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+            assert_eq!(fetches.load(Ordering::SeqCst), 1);
+            assert!(results.iter().all(|result| *result == results[0]));
+            assert!(cache.in_flight.lock().unwrap().is_empty());
+        }
+        drop(gate);
+
+        assert_eq!(cache.get(1).unwrap(), dir.join("1.osu").to_str().unwrap());
+        assert_eq!(cache.get(2), Err(CalculationError::BeatmapNotFound(2)));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = test_dir("atomic");
+        let path = dir.join("1.osu");
+
+        write_atomically(&path, b"osu file format v14\n").unwrap();
+        write_atomically(&path, b"osu file format v14\n[General]\n").unwrap();
+
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            b"osu file format v14\n[General]\n"
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,211 @@
+//! Resolves the ways users refer to a beatmap (plain ids, and osu! site links)
+//! into beatmap ids, so API users and bots don't need to parse links themselves.
+//!
+//! Supported references are:
+//! - plain ids, like `129891`;
+//! - old style links, like `https://osu.ppy.sh/b/129891?m=0`;
+//! - beatmap links, like `https://osu.ppy.sh/beatmaps/129891`;
+//! - difficulty links, like `https://osu.ppy.sh/beatmapsets/39804#osu/129891`.
+//!
+//! Links to a whole beatmap set (like `/beatmapsets/39804` or `/s/39804`) don't
+//! say which difficulty to use, and are rejected; but they're what
+//! `parse_beatmapset_reference` takes, along with plain set ids.
+use super::performance_calculator::CalculationError;
+
+/// Shorthand for an invalid `field`.
+fn invalid(field: &str, message: String) -> CalculationError {
+    CalculationError::InvalidParams {
+        field: field.to_string(),
+        message: message,
+    }
+}
+
+/// Parses a beatmap id, which must be positive.
+fn parse_id(id: &str) -> Option<i64> {
+    id.parse().ok().filter(|id| *id > 0)
+}
+
+/// What a link points to.
+#[derive(Debug, PartialEq)]
+enum Link {
+    /// A beatmap, by its id.
+    Beatmap(i64),
+    /// A beatmap set, and maybe one of its difficulties.
+    Beatmapset(i64, Option<i64>),
+}
+
+/// Parses a link to a beatmap or beatmap set, if `link` is one.
+fn parse_link(link: &str) -> Option<Link> {
+    let without_scheme = match link.find("://") {
+        Some(index) => &link[index + 3..],
+        None => link,
+    };
+    // Whatever the host is, only the path matters.
+    let after_host = &without_scheme[without_scheme.find('/')?..];
+
+    let mut split = after_host.splitn(2, '#');
+    let path = split.next().unwrap_or("");
+    let fragment = split.next().unwrap_or("");
+    let path = path.split(|c| c == '?' || c == '&').next().unwrap_or("");
+
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let kind = segments.get(0).cloned().unwrap_or("");
+    let id = parse_id(segments.get(1).cloned().unwrap_or(""))?;
+
+    match kind {
+        "b" | "beatmaps" => Some(Link::Beatmap(id)),
+        "s" | "beatmapsets" => {
+            // The fragment looks like `osu/129891`.
+            let beatmap_id = fragment.split('/').nth(1).and_then(parse_id);
+
+            Some(Link::Beatmapset(id, beatmap_id))
+        }
+        _ => None,
+    }
+}
+
+/// Resolves `reference` (a beatmap id or link) into a beatmap id.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` (for the `beatmap` field) if
+/// `reference` is a link to a beatmap set, or isn't a beatmap id or link.
+pub fn parse_beatmap_reference(reference: &str) -> Result<i64, CalculationError> {
+    let reference = reference.trim();
+    if let Some(id) = parse_id(reference) {
+        return Ok(id);
+    }
+
+    match parse_link(reference) {
+        Some(Link::Beatmap(beatmap_id)) | Some(Link::Beatmapset(_, Some(beatmap_id))) => {
+            Ok(beatmap_id)
+        }
+        Some(Link::Beatmapset(set_id, None)) => Err(invalid(
+            "beatmap",
+            format!(
+                "links to the beatmap set {}, and not to one of its difficulties \
+                 (pick a difficulty, so the link ends with #<mode>/<beatmap id>)",
+                set_id
+            ),
+        )),
+        None => Err(invalid(
+            "beatmap",
+            format!("{:?} isn't a beatmap id or link", reference),
+        )),
+    }
+}
+
+/// Resolves `reference` (a beatmap set id or link) into a beatmap set id.
+/// Links to one of the set difficulties are fine, too.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` (for the `beatmapset` field)
+/// if `reference` is an old style link to a single beatmap (which doesn't say
+/// what its set is), or isn't a beatmap set id or link.
+pub fn parse_beatmapset_reference(reference: &str) -> Result<i64, CalculationError> {
+    let reference = reference.trim();
+    if let Some(id) = parse_id(reference) {
+        return Ok(id);
+    }
+
+    match parse_link(reference) {
+        Some(Link::Beatmapset(set_id, _)) => Ok(set_id),
+        Some(Link::Beatmap(beatmap_id)) => Err(invalid(
+            "beatmapset",
+            format!(
+                "links to the beatmap {}, and not to its set (use a /beatmapsets/ link)",
+                beatmap_id
+            ),
+        )),
+        None => Err(invalid(
+            "beatmapset",
+            format!("{:?} isn't a beatmap set id or link", reference),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_beatmap_reference() {
+        let references = [
+            "129891",
+            " 129891 ",
+            "https://osu.ppy.sh/b/129891",
+            "osu.ppy.sh/b/129891?m=0",
+            "http://osu.ppy.sh/b/129891&m=0",
+            "https://osu.ppy.sh/beatmaps/129891",
+            "https://osu.ppy.sh/beatmaps/129891?mode=osu",
+            "https://osu.ppy.sh/beatmapsets/39804#osu/129891",
+            "https://osu.ppy.sh/beatmapsets/39804/#taiko/129891",
+            "osu.ppy.sh/beatmapsets/39804#fruits/129891",
+        ];
+
+        for reference in references.iter() {
+            assert_eq!(
+                parse_beatmap_reference(reference),
+                Ok(129891),
+                "{}",
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_references() {
+        let message = |reference| match parse_beatmap_reference(reference) {
+            Err(CalculationError::InvalidParams { field, message }) => {
+                assert_eq!(field, "beatmap");
+                message
+            }
+            other => panic!("{} resolved to {:?}", reference, other),
+        };
+
+        assert!(message("https://osu.ppy.sh/beatmapsets/39804").contains("beatmap set 39804"));
+        assert!(message("https://osu.ppy.sh/beatmapsets/39804#osu").contains("beatmap set"));
+        assert!(message("https://osu.ppy.sh/s/39804").contains("beatmap set"));
+
+        for reference in [
+            "",
+            "abc",
+            "-5",
+            "0",
+            "https://osu.ppy.sh/users/2",
+            "osu.ppy.sh/b/",
+        ]
+        .iter()
+        {
+            assert!(message(reference).contains("isn't a beatmap id or link"));
+        }
+    }
+
+    #[test]
+    fn test_parse_beatmapset_reference() {
+        let references = [
+            "39804",
+            "https://osu.ppy.sh/s/39804",
+            "https://osu.ppy.sh/beatmapsets/39804",
+            "https://osu.ppy.sh/beatmapsets/39804#osu/129891",
+        ];
+        for reference in references.iter() {
+            assert_eq!(
+                parse_beatmapset_reference(reference),
+                Ok(39804),
+                "{}",
+                reference
+            );
+        }
+
+        for reference in ["https://osu.ppy.sh/b/129891", "abc", ""].iter() {
+            match parse_beatmapset_reference(reference) {
+                Err(CalculationError::InvalidParams { field, .. }) => {
+                    assert_eq!(field, "beatmapset")
+                }
+                other => panic!("{} resolved to {:?}", reference, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Where beatmap (.osu) files are fetched from, when they aren't cached yet.
+//!
+//! Besides the official osu! site, beatmaps can come from a mirror, or from a
+//! local directory of .osu files (like an osu! Songs folder), so that the
+//! calculator can run without network access. In "offline" mode, only the
+//! beatmaps that are already cached can be used.
+//!
+//! Sources can also find beatmaps by the MD5 checksum of their .osu file (which
+//! is how replays refer to them): online ones through the osu! api, if there's
+//! an api key, and local directories by hashing their files.
+use super::beatmap_cache::md5_hex;
+use super::config_functions::try_api_key;
+use super::performance_calculator::CalculationError;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a local directory may be scanned again for missing beatmaps,
+/// unless it (or any directory in it) changed, like when a beatmap set is
+/// added.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How coarse directory modification times may be. Directories modified this
+/// close to a scan may have changed again without a new modification time.
+const MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+/// A place .osu files can be fetched from.
+pub trait BeatmapSource: Send + Sync {
+    /// Fetches the contents of the .osu file of `beatmap_id`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::BeatmapNotFound` if the source doesn't
+    /// have the beatmap, or another error if it couldn't be fetched.
+    fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError>;
+
+    /// Whether this source talks to the network. Sources that don't shouldn't
+    /// cause any other network access either, like checksum verification.
+    fn is_online(&self) -> bool {
+        true
+    }
+
+    /// Finds the id of the beatmap whose .osu file has the (lowercase hex) MD5
+    /// checksum `md5`, if the source knows about it. By default, asks the osu!
+    /// api, or finds nothing if there's no api key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::ApiFailure` if the lookup failed.
+    fn find_by_md5(&self, md5: &str) -> Result<Option<i64>, CalculationError> {
+        match try_api_key() {
+            Some(api_key) => lookup_md5(&api_key, md5),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A beatmap, as returned by the osu! api `get_beatmaps` endpoint. Only the
+/// fields we need are here.
+#[derive(Deserialize)]
+struct ApiBeatmap {
+    beatmap_id: String,
+}
+
+/// Asks the osu! api for the id of the beatmap whose .osu file has the MD5
+/// checksum `md5`.
+///
+/// # Errors
+///
+/// Will return `CalculationError::ApiFailure` if the request failed.
+fn lookup_md5(api_key: &str, md5: &str) -> Result<Option<i64>, CalculationError> {
+    let mut resp = reqwest::get(&format!(
+        "https://osu.ppy.sh/api/get_beatmaps?k={}&h={}",
+        api_key, md5
+    ))?;
+    if !resp.status().is_success() {
+        return Err(CalculationError::ApiFailure(format!(
+            "get_beatmaps returned {}",
+            resp.status()
+        )));
+    }
+
+    let beatmaps: Vec<ApiBeatmap> = resp.json()?;
+    match beatmaps.into_iter().next() {
+        Some(beatmap) => beatmap.beatmap_id.parse().map(Some).map_err(|_| {
+            CalculationError::ApiFailure(format!("invalid beatmap id {:?}", beatmap.beatmap_id))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Downloads a .osu file from `url`.
+///
+/// # Errors
+///
+/// Will return `CalculationError::BeatmapNotFound` if there's nothing at
+/// `url`, or `CalculationError::ApiFailure` if the download failed.
+fn download(url: &str, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+    let mut resp = reqwest::get(url)?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(CalculationError::BeatmapNotFound(beatmap_id));
+    } else if !resp.status().is_success() {
+        return Err(CalculationError::ApiFailure(format!(
+            "beatmap download returned {}",
+            resp.status()
+        )));
+    }
+
+    let mut contents = Vec::new();
+    resp.copy_to(&mut contents)?;
+
+    // Unknown beatmaps are served as an empty file.
+    if contents.is_empty() {
+        return Err(CalculationError::BeatmapNotFound(beatmap_id));
+    }
+
+    Ok(contents)
+}
+
+/// The official osu! site.
+pub struct OfficialSource;
+
+impl BeatmapSource for OfficialSource {
+    fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+        download(
+            &format!("https://osu.ppy.sh/osu/{}", beatmap_id),
+            beatmap_id,
+        )
+    }
+}
+
+/// A mirror of the official site, serving .osu files by beatmap id.
+pub struct MirrorSource {
+    base_url: String,
+}
+
+impl MirrorSource {
+    /// Creates a new mirror source. The url of a beatmap is `base_url` with
+    /// `{id}` replaced by the beatmap id; or, if there's no `{id}` in it, the
+    /// beatmap id appended to `base_url` as a path segment.
+    pub fn new(base_url: String) -> Self {
+        MirrorSource { base_url: base_url }
+    }
+
+    /// The url of the .osu file of `beatmap_id`.
+    fn url(&self, beatmap_id: i64) -> String {
+        if self.base_url.contains("{id}") {
+            self.base_url.replace("{id}", &beatmap_id.to_string())
+        } else {
+            format!("{}/{}", self.base_url.trim_end_matches('/'), beatmap_id)
+        }
+    }
+}
+
+impl BeatmapSource for MirrorSource {
+    fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+        download(&self.url(beatmap_id), beatmap_id)
+    }
+}
+
+/// Reads the `BeatmapID` from the metadata of the .osu file at `path`, without
+/// parsing the whole file.
+fn read_beatmap_id(path: &Path) -> Option<i64> {
+    let file = fs::File::open(path).ok()?;
+
+    for line in BufReader::new(file).lines() {
+        let line = line.ok()?;
+        let line = line.trim();
+
+        if line.starts_with("BeatmapID:") {
+            return line["BeatmapID:".len()..].trim().parse().ok();
+        } else if line == "[Difficulty]" || line == "[HitObjects]" {
+            // Metadata always comes before these.
+            return None;
+        }
+    }
+
+    None
+}
+
+/// Finds every .osu file under `dir`, recursively.
+fn find_osu_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_osu_files(&path, files);
+        } else if path.extension().map_or(false, |ext| ext == "osu") {
+            files.push(path);
+        }
+    }
+}
+
+/// Finds the modification time of `dir`, and of every directory under it.
+fn directory_times(dir: &Path, times: &mut HashMap<PathBuf, SystemTime>) {
+    if let Ok(modified) = fs::metadata(dir).and_then(|m| m.modified()) {
+        times.insert(dir.to_path_buf(), modified);
+    }
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                directory_times(&path, times);
+            }
+        }
+    }
+}
+
+/// The entries of a `ScannedIndex`, and when they were scanned.
+struct ScannedEntries<K, V> {
+    entries: HashMap<K, V>,
+    last_scan: Option<Instant>,
+    /// When the last scan started.
+    scanned_at: SystemTime,
+    /// The modification times of the directories, as of the last scan.
+    dir_times: HashMap<PathBuf, SystemTime>,
+}
+
+impl<K, V> ScannedEntries<K, V> {
+    /// Whether any directory changed since the last scan (which is how files
+    /// are added to, or removed from, them). Directories modified right before
+    /// the scan count as changed, since their modification time may not tell.
+    fn directories_changed(&self) -> bool {
+        let racy = self
+            .scanned_at
+            .checked_sub(MTIME_GRANULARITY)
+            .unwrap_or(self.scanned_at);
+
+        self.dir_times.iter().any(|(dir, modified)| {
+            *modified >= racy
+                || fs::metadata(dir).and_then(|m| m.modified()).ok() != Some(*modified)
+        })
+    }
+}
+
+/// An index of the files of a directory, built by scanning it. Keys that
+/// aren't found cause a new scan, if any directory changed since the last one
+/// or it's been `RESCAN_INTERVAL` since then, and only one at a time. Scans
+/// don't block lookups of the keys that are already there.
+struct ScannedIndex<K, V> {
+    state: Mutex<ScannedEntries<K, V>>,
+    scanning: Mutex<()>,
+}
+
+impl<K: Eq + Hash, V: Clone> ScannedIndex<K, V> {
+    fn new() -> Self {
+        ScannedIndex {
+            state: Mutex::new(ScannedEntries {
+                entries: HashMap::new(),
+                last_scan: None,
+                scanned_at: SystemTime::now(),
+                dir_times: HashMap::new(),
+            }),
+            scanning: Mutex::new(()),
+        }
+    }
+
+    /// The value of `key`, scanning `dir` with `scan` if it's not there (and a
+    /// new scan is due).
+    fn get<F: FnOnce() -> HashMap<K, V>>(&self, dir: &Path, key: &K, scan: F) -> Option<V> {
+        let last_scan = {
+            let state = self.state.lock().unwrap();
+            if let Some(value) = state.entries.get(key) {
+                return Some(value.clone());
+            }
+
+            state.last_scan
+        };
+
+        let _scanning = self.scanning.lock().unwrap();
+        {
+            let state = self.state.lock().unwrap();
+            // Another scan may have finished while we waited for it.
+            let rescanned = state.last_scan != last_scan;
+            let fresh = state
+                .last_scan
+                .map_or(false, |last_scan| last_scan.elapsed() < RESCAN_INTERVAL)
+                && !state.directories_changed();
+
+            if rescanned || fresh {
+                return state.entries.get(key).cloned();
+            }
+        }
+
+        // The times are taken before scanning, so changes during the scan
+        // cause another one.
+        let scanned_at = SystemTime::now();
+        let mut dir_times = HashMap::new();
+        directory_times(dir, &mut dir_times);
+        let entries = scan();
+
+        let mut state = self.state.lock().unwrap();
+        state.entries = entries;
+        state.last_scan = Some(Instant::now());
+        state.scanned_at = scanned_at;
+        state.dir_times = dir_times;
+
+        state.entries.get(key).cloned()
+    }
+}
+
+/// A local directory of .osu files. Files named `<beatmap id>.osu` are found
+/// directly; any other .osu file (like the ones in an osu! Songs folder) is
+/// found by the `BeatmapID` in its metadata.
+pub struct LocalDirectorySource {
+    dir: PathBuf,
+    /// The .osu files found on the last scan of `dir`, by beatmap id.
+    index: ScannedIndex<i64, PathBuf>,
+    /// The beatmap ids of the .osu files found on the last scan of `dir`, by
+    /// their MD5 checksum.
+    md5_index: ScannedIndex<String, i64>,
+    /// The MD5 checksums of the .osu files hashed so far, by path, along with
+    /// the size and modification time they had, so files changed in place are
+    /// hashed again.
+    checksums: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>>,
+}
+
+impl LocalDirectorySource {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        LocalDirectorySource {
+            dir: dir.into(),
+            index: ScannedIndex::new(),
+            md5_index: ScannedIndex::new(),
+            checksums: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Scans `dir` for .osu files, indexing them by beatmap id.
+    fn scan(&self) -> HashMap<i64, PathBuf> {
+        let mut files = Vec::new();
+        find_osu_files(&self.dir, &mut files);
+
+        files
+            .into_iter()
+            .filter_map(|path| read_beatmap_id(&path).map(|id| (id, path)))
+            .collect()
+    }
+
+    /// Scans `dir` for .osu files, indexing their beatmap ids by checksum.
+    /// Files named `<beatmap id>.osu` that don't have a `BeatmapID` are
+    /// indexed by their name.
+    fn scan_checksums(&self) -> HashMap<String, i64> {
+        let mut files = Vec::new();
+        find_osu_files(&self.dir, &mut files);
+
+        files
+            .into_iter()
+            .filter_map(|path| {
+                let by_name = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok());
+                let beatmap_id = read_beatmap_id(&path).or(by_name)?;
+
+                Some((self.checksum(&path)?, beatmap_id))
+            })
+            .collect()
+    }
+
+    /// The MD5 checksum of the .osu file at `path`. Each version of a file is
+    /// only hashed once, without holding the lock while it's read.
+    fn checksum(&self, path: &Path) -> Option<String> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?;
+        if let Some((len, hashed, md5)) = self.checksums.lock().unwrap().get(path) {
+            if *len == metadata.len() && *hashed == modified {
+                return Some(md5.clone());
+            }
+        }
+
+        let md5 = md5_hex(&fs::read(path).ok()?);
+        // Older versions of the file won't be looked up again.
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (metadata.len(), modified, md5.clone()));
+
+        Some(md5)
+    }
+
+    /// Finds the .osu file of `beatmap_id`. The directory is scanned again
+    /// when a beatmap isn't on the index (see `ScannedIndex`), since files may
+    /// have been added.
+    fn find(&self, beatmap_id: i64) -> Option<PathBuf> {
+        let by_name = self.dir.join(format!("{}.osu", beatmap_id));
+        if by_name.is_file() {
+            return Some(by_name);
+        }
+
+        self.index.get(&self.dir, &beatmap_id, || self.scan())
+    }
+}
+
+impl BeatmapSource for LocalDirectorySource {
+    fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+        match self.find(beatmap_id) {
+            Some(path) => Ok(fs::read(path)?),
+            None => Err(CalculationError::BeatmapNotFound(beatmap_id)),
+        }
+    }
+
+    fn is_online(&self) -> bool {
+        false
+    }
+
+    /// Looks the checksum up on an index of the .osu files on the directory,
+    /// since files don't say what their checksum is. The directory is scanned
+    /// again when a checksum isn't on the index (see `ScannedIndex`), and only
+    /// new or changed files are hashed then.
+    fn find_by_md5(&self, md5: &str) -> Result<Option<i64>, CalculationError> {
+        Ok(self
+            .md5_index
+            .get(&self.dir, &md5.to_string(), || self.scan_checksums()))
+    }
+}
+
+/// Never fetches anything, so only already cached beatmaps can be used.
+pub struct OfflineSource;
+
+impl BeatmapSource for OfflineSource {
+    fn fetch(&self, beatmap_id: i64) -> Result<Vec<u8>, CalculationError> {
+        Err(CalculationError::BeatmapNotFound(beatmap_id))
+    }
+
+    fn is_online(&self) -> bool {
+        false
+    }
+
+    fn find_by_md5(&self, _md5: &str) -> Result<Option<i64>, CalculationError> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_mirror_url() {
+        let mirror = MirrorSource::new("https://mirror.example/osu/".to_string());
+        assert_eq!(mirror.url(123), "https://mirror.example/osu/123");
+
+        let mirror = MirrorSource::new("https://mirror.example/b/{id}/download".to_string());
+        assert_eq!(mirror.url(123), "https://mirror.example/b/123/download");
+    }
+
+    #[test]
+    fn test_local_directory() {
+        let dir = env::temp_dir().join(format!("beatmap_source_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("1 Artist - Title")).unwrap();
+
+        fs::write(dir.join("10.osu"), "osu file format v14\n").unwrap();
+        fs::write(
+            dir.join("1 Artist - Title")
+                .join("Artist - Title (Mapper) [Insane].osu"),
+            "osu file format v14\n\n[Metadata]\nTitle:Title\nBeatmapID:20\n\n[Difficulty]\n",
+        )
+        .unwrap();
+
+        let source = LocalDirectorySource::new(dir.clone());
+        assert!(!source.is_online());
+        assert_eq!(source.fetch(10).unwrap(), b"osu file format v14\n");
+        assert!(source
+            .fetch(20)
+            .unwrap()
+            .starts_with(b"osu file format v14"));
+        assert_eq!(source.fetch(30), Err(CalculationError::BeatmapNotFound(30)));
+
+        // Beatmaps added to an existing beatmap set are found right away.
+        let insane = dir.join("1 Artist - Title").join("Insane.osu");
+        fs::write(&insane, "osu file format v14\n\n[Metadata]\nBeatmapID:30\n").unwrap();
+        assert!(source.fetch(30).is_ok());
+        fs::remove_file(insane).unwrap();
+
+        assert_eq!(
+            source.find_by_md5(&md5_hex(b"osu file format v14\n")),
+            Ok(Some(10))
+        );
+        assert_eq!(source.find_by_md5(&md5_hex(b"nope")), Ok(None));
+
+        // New files are found once the index is refreshed.
+        fs::write(dir.join("11.osu"), "osu file format v14\n\n").unwrap();
+        assert_eq!(
+            source.find_by_md5(&md5_hex(b"osu file format v14\n\n")),
+            Ok(Some(11))
+        );
+
+        // Files changed in place are hashed again.
+        fs::write(dir.join("10.osu"), "osu file format v14\n\n\n").unwrap();
+        fs::create_dir_all(dir.join("3 Artist - Another Title")).unwrap();
+        assert_eq!(
+            source.find_by_md5(&md5_hex(b"osu file format v14\n\n\n")),
+            Ok(Some(10))
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_scanned_index_throttle() {
+        let dir = env::temp_dir().join(format!("scanned_index_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let index: ScannedIndex<i64, i64> = ScannedIndex::new();
+        let scans = Mutex::new(0);
+        let scan = || {
+            *scans.lock().unwrap() += 1;
+            HashMap::new()
+        };
+
+        // Nothing changed (there's no directory at all), so missing keys don't
+        // cause another scan right away.
+        assert_eq!(index.get(&dir, &1, scan), None);
+        assert_eq!(index.get(&dir, &1, scan), None);
+        assert_eq!(*scans.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_offline() {
+        assert_eq!(
+            OfflineSource.fetch(1),
+            Err(CalculationError::BeatmapNotFound(1))
+        );
+        assert_eq!(OfflineSource.find_by_md5(&md5_hex(b"")), Ok(None));
+    }
+}
@@ -0,0 +1,188 @@
+//! A scratch area for uploaded .osu files, like unranked or work in progress
+//! difficulties, so plays on them can be simulated like on any cached beatmap.
+//!
+//! Files are content-addressed: each one is stored as `<md5>.osu`, so uploading
+//! the same file twice reuses it. Uploads are size limited and must parse as a
+//! .osu file; when there are too many, the least recently uploaded ones are
+//! removed (except the ones uploaded within `REMOVAL_GRACE`, which may still be
+//! in use).
+use super::beatmap::Beatmap;
+use super::beatmap_cache::{is_osu_file, md5_hex, write_atomically};
+use super::performance_calculator::CalculationError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Uploads more recent than this aren't removed, as a simulation may still be
+/// reading them.
+const REMOVAL_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// Shorthand for an invalid upload.
+fn invalid(message: String) -> CalculationError {
+    CalculationError::InvalidParams {
+        field: "osu_file".to_string(),
+        message: message,
+    }
+}
+
+/// A directory of uploaded .osu files, named after their MD5 checksum.
+pub struct BeatmapUploads {
+    dir: PathBuf,
+    max_bytes: usize,
+    max_files: usize,
+    grace: Duration,
+}
+
+impl BeatmapUploads {
+    /// Creates a new scratch area on `dir`, accepting files of up to `max_bytes`,
+    /// and keeping at most `max_files` of them.
+    pub fn new<P: Into<PathBuf>>(dir: P, max_bytes: usize, max_files: usize) -> Self {
+        BeatmapUploads {
+            dir: dir.into(),
+            max_bytes: max_bytes,
+            max_files: max_files,
+            grace: REMOVAL_GRACE,
+        }
+    }
+
+    /// The largest file that can be uploaded, in bytes.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Validates and stores `contents`, returning the path of the stored file.
+    /// Files that were already uploaded are written again, so they count as
+    /// recently uploaded.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` (for the `osu_file` field)
+    /// if `contents` are too big or aren't a valid .osu file; or another error
+    /// if the file couldn't be saved.
+    pub fn store(&self, contents: &[u8]) -> Result<String, CalculationError> {
+        if contents.len() > self.max_bytes {
+            return Err(invalid(format!(
+                "should be at most {} bytes",
+                self.max_bytes
+            )));
+        }
+
+        if !is_osu_file(contents) {
+            return Err(invalid("isn't a .osu file".to_string()));
+        }
+
+        let beatmap = Beatmap::parse(&String::from_utf8_lossy(contents))
+            .map_err(|e| invalid(e.to_string()))?;
+        if beatmap.hit_objects.is_empty() {
+            return Err(invalid("has no hit objects".to_string()));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+
+        let path = self.dir.join(format!("{}.osu", md5_hex(contents)));
+        write_atomically(&path, contents)?;
+        self.remove_oldest(&path);
+
+        Ok(path.to_str().unwrap().to_string())
+    }
+
+    /// Removes the oldest uploads other than `keep`, until there are at most
+    /// `max_files` (or only recent ones are left).
+    fn remove_oldest(&self, keep: &Path) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        let mut files: Vec<_> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "osu"))
+            .filter(|entry| entry.path() != keep)
+            .map(|entry| {
+                let modified = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(UNIX_EPOCH);
+
+                (modified, entry.path())
+            })
+            .collect();
+
+        // `keep` is one of the files, too.
+        let max_others = self.max_files.saturating_sub(1);
+        if files.len() <= max_others {
+            return;
+        }
+
+        files.sort();
+        let excess = files.len() - max_others;
+        let grace_start = SystemTime::now() - self.grace;
+        for (_, path) in files
+            .into_iter()
+            .take(excess)
+            .filter(|(modified, _)| *modified < grace_start)
+        {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use std::env;
+    use std::process;
+
+    fn field(result: Result<String, CalculationError>) -> Option<String> {
+        match result {
+            Err(CalculationError::InvalidParams { field, .. }) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = env::temp_dir().join(format!("beatmap_uploads_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut uploads = BeatmapUploads::new(dir.clone(), 4096, 1);
+
+        let path = uploads.store(TEST_BEATMAP.as_bytes()).unwrap();
+        assert_eq!(
+            Path::new(&path),
+            dir.join(format!("{}.osu", md5_hex(TEST_BEATMAP.as_bytes())))
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), TEST_BEATMAP);
+        assert_eq!(uploads.store(TEST_BEATMAP.as_bytes()).unwrap(), path);
+
+        // Recent uploads are kept, even if there are too many.
+        let other = TEST_BEATMAP.replace("Insane", "Extra");
+        let other_path = uploads.store(other.as_bytes()).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Otherwise, only one is kept.
+        uploads.grace = Duration::from_secs(0);
+        let another = TEST_BEATMAP.replace("Insane", "Expert");
+        let another_path = uploads.store(another.as_bytes()).unwrap();
+        assert!(Path::new(&another_path).exists());
+        assert!(!Path::new(&other_path).exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert_eq!(
+            field(uploads.store(&vec![b' '; 4097])),
+            Some("osu_file".to_string())
+        );
+        assert!(field(uploads.store(b"<html>")).is_some());
+        assert!(field(uploads.store(b"osu file format v14\n\n[HitObjects]\n1,2")).is_some());
+        assert!(field(uploads.store(b"osu file format v14\n")).is_some());
+
+        // Times that aren't numbers can't be ordered.
+        let nan = TEST_BEATMAP.replace("192,64,500,5", "192,64,NaN,5");
+        assert_eq!(
+            field(uploads.store(nan.as_bytes())),
+            Some("osu_file".to_string())
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
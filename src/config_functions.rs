@@ -39,6 +39,22 @@ where
     }
 }
 
+/// The osu! api key, if one was set (see `api_key`).
+pub fn try_api_key() -> Option<String> {
+    let docker_secret_file = Path::new("/run/secrets/osu_pp_calc_api_key");
+    let key = if docker_secret_file.exists() {
+        api_key()
+    } else {
+        from_env("OSU_PP_CALC_API_KEY", Some(String::new()))
+    };
+
+    if key.is_empty() {
+        None
+    } else {
+        Some(key)
+    }
+}
+
 /// The osu! api key to be used on requests. Can be either on
 /// `/run/secrets/osu_pp_calc_api_key`, if running under Docker, or on the
 /// `OSU_PP_CALC_API_KEY` env variable.
@@ -65,6 +81,59 @@ pub fn dotnet_command() -> String {
     from_env("OSU_PP_CALC_DOTNET_COMMAND", Some("dotnet".to_string()))
 }
 
+/// Which `PerformanceBackend` to use: either "dotnet", which calls into
+/// PerformanceCalculator.dll, or "native", which only supports beatmap
+/// simulations, but doesn't need dotnet to be installed. Is read from
+/// the `OSU_PP_CALC_BACKEND` env variable, and defaults to "dotnet".
+pub fn calculator_backend() -> String {
+    from_env("OSU_PP_CALC_BACKEND", Some("dotnet".to_string()))
+}
+
+/// A JSON file, listing extra PerformanceCalculator builds (rebalance proposals) to be
+/// offered alongside the default one. Is read from the `OSU_PP_CALC_CALCULATORS_FILE`
+/// env variable, and isn't set by default.
+pub fn calculators_file() -> Option<String> {
+    let file: String = from_env("OSU_PP_CALC_CALCULATORS_FILE", Some(String::new()));
+
+    if file.is_empty() {
+        None
+    } else {
+        Some(file)
+    }
+}
+
+/// The name of the calculator that stands for the live PP system, so simulations
+/// on other calculators can be compared against it. Is read from the
+/// `OSU_PP_CALC_LIVE_CALCULATOR` env variable, and isn't set by default.
+pub fn live_calculator() -> Option<String> {
+    let name: String = from_env("OSU_PP_CALC_LIVE_CALCULATOR", Some(String::new()));
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// How long a profile calculation may take before it's killed, in seconds. Is read
+/// from the `OSU_PP_CALC_PROFILE_TIMEOUT_SECS` env variable, and defaults to 10 minutes.
+pub fn profile_timeout() -> Duration {
+    Duration::from_secs(from_env("OSU_PP_CALC_PROFILE_TIMEOUT_SECS", Some(60 * 10)))
+}
+
+/// How long a beatmap simulation may take before it's killed, in seconds. Is read
+/// from the `OSU_PP_CALC_SIMULATE_TIMEOUT_SECS` env variable, and defaults to 1 minute.
+pub fn simulate_timeout() -> Duration {
+    Duration::from_secs(from_env("OSU_PP_CALC_SIMULATE_TIMEOUT_SECS", Some(60)))
+}
+
+/// How long a batch (or curve) of simulations may take, in seconds. The plays
+/// left when it's over fail instead of being simulated. Is read from the
+/// `OSU_PP_CALC_BATCH_TIMEOUT_SECS` env variable, and defaults to 5 minutes.
+pub fn batch_timeout() -> Duration {
+    Duration::from_secs(from_env("OSU_PP_CALC_BATCH_TIMEOUT_SECS", Some(60 * 5)))
+}
+
 /// The number of workers to be used on the profile calculation queue. Is read
 /// from the `OSU_PP_CALC_NUM_THREADS` env variable, and defaults to 2.
 pub fn num_threads() -> usize {
@@ -89,6 +158,128 @@ pub fn beatmaps_cache() -> String {
     from_env("OSU_PP_CALC_BEATMAPS_CACHE", Some("cache".to_string()))
 }
 
+/// Where to fetch beatmaps that aren't cached from: "official" (the osu! site),
+/// "mirror" (`beatmap_mirror_url()`), "local" (`beatmap_source_dir()`), or
+/// "offline" (only use already cached beatmaps). Is read from the
+/// `OSU_PP_CALC_BEATMAP_SOURCE` env variable, and defaults to "official".
+pub fn beatmap_source() -> String {
+    from_env("OSU_PP_CALC_BEATMAP_SOURCE", Some("official".to_string()))
+}
+
+/// The base url of the beatmap mirror, when `beatmap_source()` is "mirror". A `{id}`
+/// in it is replaced by the beatmap id, otherwise the id is appended to it. Is read
+/// from the `OSU_PP_CALC_BEATMAP_MIRROR_URL` env variable, and has no default.
+pub fn beatmap_mirror_url() -> String {
+    from_env("OSU_PP_CALC_BEATMAP_MIRROR_URL", None)
+}
+
+/// The directory of .osu files (like an osu! Songs folder), when `beatmap_source()`
+/// is "local". Is read from the `OSU_PP_CALC_BEATMAP_SOURCE_DIR` env variable, and
+/// has no default.
+pub fn beatmap_source_dir() -> String {
+    from_env("OSU_PP_CALC_BEATMAP_SOURCE_DIR", None)
+}
+
+/// The osu! `scores.db` file local profiles are calculated from. Is read from
+/// the `OSU_PP_CALC_SCORES_DB` env variable, and isn't set by default (local
+/// profiles are disabled).
+pub fn scores_db() -> Option<PathBuf> {
+    let path: String = from_env("OSU_PP_CALC_SCORES_DB", Some(String::new()));
+
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+/// Folder to save uploaded beatmap (.osu) files, that are simulated without
+/// being ranked. Is read from the `OSU_PP_CALC_UPLOADS_DIR` env variable, and
+/// defaults to "uploads".
+pub fn uploads_dir() -> String {
+    from_env("OSU_PP_CALC_UPLOADS_DIR", Some("uploads".to_string()))
+}
+
+/// The maximum size of an uploaded .osu file, in kilobytes. Is read from the
+/// `OSU_PP_CALC_UPLOAD_MAX_KB` env variable, and defaults to 2048 (2 MB).
+pub fn upload_max_kb() -> usize {
+    from_env("OSU_PP_CALC_UPLOAD_MAX_KB", Some(2048))
+}
+
+/// The maximum number of uploaded .osu files to keep; the oldest ones are
+/// removed first. Is read from the `OSU_PP_CALC_UPLOADS_MAX_FILES` env variable,
+/// and defaults to 1000.
+pub fn uploads_max_files() -> usize {
+    from_env("OSU_PP_CALC_UPLOADS_MAX_FILES", Some(1000))
+}
+
+/// The maximum size of the beatmaps cache, in megabytes. Is read from the
+/// `OSU_PP_CALC_BEATMAPS_CACHE_MAX_MB` env variable, and defaults to 0 (unlimited).
+pub fn beatmaps_cache_max_mb() -> u64 {
+    from_env("OSU_PP_CALC_BEATMAPS_CACHE_MAX_MB", Some(0))
+}
+
+/// The maximum number of files on the beatmaps cache. Is read from the
+/// `OSU_PP_CALC_BEATMAPS_CACHE_MAX_FILES` env variable, and defaults to 0 (unlimited).
+pub fn beatmaps_cache_max_files() -> usize {
+    from_env("OSU_PP_CALC_BEATMAPS_CACHE_MAX_FILES", Some(0))
+}
+
+/// Whether the beatmaps of every calculated profile should be fetched into the
+/// beatmaps cache, so simulations on them don't wait for downloads. Calculators
+/// that download them on their own (like PerformanceCalculator) are skipped. Is
+/// read from the `OSU_PP_CALC_PREFETCH_PROFILE_BEATMAPS` env variable, and
+/// defaults to false.
+pub fn prefetch_profile_beatmaps() -> bool {
+    from_env("OSU_PP_CALC_PREFETCH_PROFILE_BEATMAPS", Some(false))
+}
+
+/// How many simulation results are kept in memory. Is read from the
+/// `OSU_PP_CALC_SIMULATION_CACHE_SIZE` env variable, and defaults to 10000 (0
+/// disables the simulation cache).
+pub fn simulation_cache_size() -> usize {
+    from_env("OSU_PP_CALC_SIMULATION_CACHE_SIZE", Some(10000))
+}
+
+/// The folder where simulation results are also kept, so they survive restarts.
+/// Is read from the `OSU_PP_CALC_SIMULATION_CACHE_DIR` env variable, and isn't
+/// set by default (results are only kept in memory).
+pub fn simulation_cache_dir() -> Option<PathBuf> {
+    let dir: String = from_env("OSU_PP_CALC_SIMULATION_CACHE_DIR", Some(String::new()));
+
+    if dir.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(dir))
+    }
+}
+
+/// How many simulation results are kept on `simulation_cache_dir`. Is read from
+/// the `OSU_PP_CALC_SIMULATION_CACHE_MAX_FILES` env variable, and defaults to 100000.
+pub fn simulation_cache_max_files() -> usize {
+    from_env("OSU_PP_CALC_SIMULATION_CACHE_MAX_FILES", Some(100_000))
+}
+
+/// The token needed to access the admin endpoints. Is read from the
+/// `OSU_PP_CALC_ADMIN_TOKEN` env variable, and isn't set by default (which
+/// disables them).
+pub fn admin_token() -> Option<String> {
+    let token: String = from_env("OSU_PP_CALC_ADMIN_TOKEN", Some(String::new()));
+
+    if token.is_empty() {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+/// Whether downloaded beatmaps should be checked against the MD5 checksum reported
+/// by the osu! api (which needs an api key). Is read from the
+/// `OSU_PP_CALC_VERIFY_BEATMAP_MD5` env variable, and defaults to false.
+pub fn verify_beatmap_md5() -> bool {
+    from_env("OSU_PP_CALC_VERIFY_BEATMAP_MD5", Some(false))
+}
+
 /// The minimal "age" for a profile calculation result to be, so that it's allowed to be
 /// forcibly recalculated, in seconds.
 /// Is read from the `OSU_PP_CALC_FORCE_INTERVAL_SECS` env variable, and defaults to 15 minutes.
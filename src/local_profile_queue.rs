@@ -0,0 +1,273 @@
+//! A queue for local profile calculations (see `scores_db`).
+//!
+//! Local profiles can take as long as the profile timeout, so they're
+//! calculated on a thread of their own, one at a time, instead of on the web
+//! server threads, and requests are polled for their status like the ones of
+//! `ProfileQueue`. Only `LOCAL_PROFILE_BACKLOG` requests can be waiting at once.
+//!
+//! Local profiles aren't cached, since `scores.db` changes with every play:
+//! the results of a request are kept until the same profile is requested again,
+//! which calculates it again.
+use super::beatmap_cache::BeatmapCache;
+use super::performance_calculator::{
+    CalculationError, CalculatorRegistry, Ruleset, ScoreListResults,
+};
+use super::profile_queue::RequestStatus;
+use super::scores_db::{calculate_scores_db_profile, ScoresDbFile};
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How many local profiles can be waiting to be calculated.
+const LOCAL_PROFILE_BACKLOG: usize = 4;
+
+/// A calculation job: the name of the calculator, the ruleset, and the player.
+type Job = (String, Ruleset, String);
+
+/// Where a local profile request is at. Pending requests carry the order they
+/// were made in, to tell how many are ahead of them.
+enum Request {
+    Pending(u64),
+    Calculating,
+    Done(Arc<ScoreListResults>),
+    Failed(CalculationError),
+}
+
+/// Calculates local profiles on a single thread.
+pub struct LocalProfileQueue {
+    sender: Mutex<SyncSender<Job>>,
+    requests: Arc<Mutex<HashMap<Job, Request>>>,
+    next_request: Mutex<u64>,
+}
+
+impl LocalProfileQueue {
+    /// Creates a new `LocalProfileQueue`, that calculates profiles from the
+    /// plays on `scores_db` with the calculators in `registry`, taking the
+    /// .osu files from `beatmaps`. Each profile has at most `timeout` (see
+    /// `calculate_scores_db_profile`).
+    pub fn new(
+        scores_db: ScoresDbFile,
+        registry: Arc<CalculatorRegistry>,
+        beatmaps: Arc<BeatmapCache>,
+        timeout: Duration,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<Job>(LOCAL_PROFILE_BACKLOG);
+        let requests = Arc::new(Mutex::new(HashMap::new()));
+
+        let worker_requests = requests.clone();
+        thread::spawn(move || {
+            for job in receiver {
+                worker_requests
+                    .lock()
+                    .unwrap()
+                    .insert(job.clone(), Request::Calculating);
+
+                let request = match calculate(&scores_db, &registry, &beatmaps, &job, timeout) {
+                    Ok(results) => Request::Done(Arc::new(results)),
+                    Err(error) => Request::Failed(error),
+                };
+                worker_requests.lock().unwrap().insert(job, request);
+            }
+        });
+
+        LocalProfileQueue {
+            sender: Mutex::new(sender),
+            requests: requests,
+            next_request: Mutex::new(0),
+        }
+    }
+
+    /// Places the local profile of `player` into the queue, to be calculated by
+    /// `calculator` on `ruleset`. If it already is on the queue, nothing
+    /// happens. Returns false if too many profiles are waiting already.
+    pub fn enqueue(&self, calculator: &str, ruleset: Ruleset, player: String) -> bool {
+        let job = (calculator.to_string(), ruleset, player);
+        let mut requests = self.requests.lock().unwrap();
+        match requests.get(&job) {
+            Some(Request::Pending(_)) | Some(Request::Calculating) => return true,
+            _ => {}
+        }
+
+        if let Err(TrySendError::Full(_)) = self.sender.lock().unwrap().try_send(job.clone()) {
+            return false;
+        }
+
+        let mut next_request = self.next_request.lock().unwrap();
+        requests.insert(job, Request::Pending(*next_request));
+        *next_request += 1;
+
+        true
+    }
+
+    /// Obtains the status of the local profile request for `player`, with
+    /// `calculator` on `ruleset`.
+    pub fn status(
+        &self,
+        calculator: &str,
+        ruleset: Ruleset,
+        player: String,
+    ) -> Option<RequestStatus> {
+        let job = (calculator.to_string(), ruleset, player);
+        let requests = self.requests.lock().unwrap();
+
+        Some(match requests.get(&job)? {
+            Request::Pending(order) => RequestStatus::Pending(
+                requests
+                    .values()
+                    .filter(|request| match request {
+                        Request::Pending(other) => other < order,
+                        _ => false,
+                    })
+                    .count(),
+            ),
+            Request::Calculating => RequestStatus::Calculating,
+            Request::Done(_) => RequestStatus::Done,
+            Request::Failed(error) => RequestStatus::Error(error.clone()),
+        })
+    }
+
+    /// The results of the local profile of `player`, with `calculator` on
+    /// `ruleset`, once they're done.
+    pub fn results(
+        &self,
+        calculator: &str,
+        ruleset: Ruleset,
+        player: String,
+    ) -> Option<Arc<ScoreListResults>> {
+        let job = (calculator.to_string(), ruleset, player);
+
+        match self.requests.lock().unwrap().get(&job) {
+            Some(Request::Done(results)) => Some(results.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Calculates the local profile of `job`.
+fn calculate(
+    scores_db: &ScoresDbFile,
+    registry: &CalculatorRegistry,
+    beatmaps: &BeatmapCache,
+    job: &Job,
+    timeout: Duration,
+) -> Result<ScoreListResults, CalculationError> {
+    let (calculator, ruleset, player) = job;
+    let backend = registry
+        .get(calculator)
+        .ok_or_else(|| CalculationError::UnknownCalculator(calculator.clone()))?;
+    let db = scores_db.scores()?;
+    let live = registry.live();
+
+    let results = calculate_scores_db_profile(
+        &*backend,
+        live.as_ref().map(|(_, live)| &**live),
+        beatmaps,
+        &db,
+        player,
+        *ruleset,
+        timeout,
+    )?;
+    for failed in &results.failed {
+        println!("Skipping a local play of {}: {}", player, failed.message);
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::beatmap_cache::{md5_hex, CacheLimits};
+    use crate::beatmap_source::OfflineSource;
+    use crate::performance_calculator::{NativeBackend, DEFAULT_CALCULATOR};
+    use crate::scores_db::test::{score_entry, scores_db};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Polls the `queue` until the request for `player` with `calculator` is
+    /// either done or errored.
+    fn wait_for(
+        queue: &LocalProfileQueue,
+        calculator: &str,
+        player: &str,
+    ) -> Option<RequestStatus> {
+        for _ in 0..500 {
+            match queue.status(calculator, Ruleset::Osu, player.to_string()) {
+                Some(RequestStatus::Pending(_)) | Some(RequestStatus::Calculating) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                status => return status,
+            }
+        }
+
+        None
+    }
+
+    #[test]
+    fn test_local_profile_queue() {
+        let dir = env::temp_dir().join(format!("local_profile_queue_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.osu"), TEST_BEATMAP).unwrap();
+
+        let md5 = md5_hex(TEST_BEATMAP.as_bytes());
+        let path = dir.join("scores.db");
+        let scores = vec![score_entry("someone", &md5, 1000, 0, 0)];
+        fs::write(&path, scores_db(&[(&md5, scores)])).unwrap();
+
+        let beatmaps = Arc::new(BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        ));
+        let registry = Arc::new(CalculatorRegistry::new(
+            "Native".to_string(),
+            Arc::new(NativeBackend::new()),
+        ));
+        let queue = LocalProfileQueue::new(
+            ScoresDbFile::new(path),
+            registry,
+            beatmaps,
+            Duration::from_secs(60),
+        );
+
+        assert!(queue
+            .status(DEFAULT_CALCULATOR, Ruleset::Osu, "someone".to_string())
+            .is_none());
+        assert!(queue.enqueue(DEFAULT_CALCULATOR, Ruleset::Osu, "someone".to_string()));
+        assert!(queue.enqueue(DEFAULT_CALCULATOR, Ruleset::Osu, "nobody".to_string()));
+        assert!(queue.enqueue("missing", Ruleset::Osu, "someone".to_string()));
+
+        assert_eq!(
+            wait_for(&queue, DEFAULT_CALCULATOR, "someone"),
+            Some(RequestStatus::Done)
+        );
+        let results = queue
+            .results(DEFAULT_CALCULATOR, Ruleset::Osu, "someone".to_string())
+            .unwrap();
+        assert_eq!(results.profile.beatmap_ids(), vec![1]);
+
+        assert_eq!(
+            wait_for(&queue, DEFAULT_CALCULATOR, "nobody"),
+            Some(RequestStatus::Error(CalculationError::UserNotFound(
+                "nobody".to_string()
+            )))
+        );
+        assert_eq!(
+            wait_for(&queue, "missing", "someone"),
+            Some(RequestStatus::Error(CalculationError::UnknownCalculator(
+                "missing".to_string()
+            )))
+        );
+        assert!(queue
+            .results("missing", Ruleset::Osu, "someone".to_string())
+            .is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
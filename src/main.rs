@@ -15,59 +15,257 @@ use rocket::Rocket;
 use rocket_contrib::json::{Json, JsonValue};
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
-use std::collections::HashMap;
 use std::sync::Arc;
 
 pub mod config_functions;
 use config_functions::{
-    api_key, load_save_results, minimal_force_interval, num_threads, results_file,
+    admin_token, api_key, batch_timeout, beatmap_mirror_url, beatmap_source, beatmap_source_dir,
+    beatmaps_cache, beatmaps_cache_max_files, beatmaps_cache_max_mb, calculator_backend,
+    calculators_file, dotnet_command, live_calculator, load_save_results, minimal_force_interval,
+    num_threads, performance_calculator_path, prefetch_profile_beatmaps, profile_timeout,
+    results_file, scores_db, simulate_timeout, simulation_cache_dir, simulation_cache_max_files,
+    simulation_cache_size, upload_max_kb, uploads_dir, uploads_max_files, verify_beatmap_md5,
 };
+pub mod beatmap;
+pub mod beatmap_cache;
+pub mod beatmap_reference;
+pub mod beatmap_source;
+pub mod beatmap_uploads;
 pub mod handlebars_helpers;
+pub mod local_profile_queue;
 pub mod performance_calculator;
 pub mod profile_cache;
 pub mod profile_queue;
+pub mod replay;
+pub mod scores_db;
+pub mod simulation_cache;
 
-use performance_calculator::{simulate_play, SimulationParams};
+use beatmap_cache::{BeatmapCache, CacheLimits};
+use beatmap_reference::{parse_beatmap_reference, parse_beatmapset_reference};
+use beatmap_source::{
+    BeatmapSource, LocalDirectorySource, MirrorSource, OfficialSource, OfflineSource,
+};
+use beatmap_uploads::BeatmapUploads;
+use local_profile_queue::LocalProfileQueue;
+use performance_calculator::registry::load_builds;
+use performance_calculator::{
+    calculate_score_list, fetch_beatmapset, parse_score_list, simulate_batch, simulate_beatmapset,
+    simulate_curve, simulate_play, simulate_play_file, Batch, CalculationError, CalculatorInfo,
+    CalculatorRegistry, CurveSpec, DotnetBackend, LiveComparison, NativeBackend,
+    PerformanceBackend, ProfileResults, Ruleset, SimulationParams, SimulationResults, Timeouts,
+    DEFAULT_CALCULATOR,
+};
 use profile_cache::ProfileCache;
 use profile_queue::{ProfileQueue, RequestStatus};
+use replay::Replay;
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+use rocket::response::status::BadRequest;
 use rocket::response::Redirect;
-use rocket::State;
+use rocket::{Data, State};
+use scores_db::ScoresDbFile;
+use simulation_cache::{CachingBackend, SimulationCache};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::process;
 
-#[get("/?<user>")]
-fn index(user: Option<String>) -> Template {
+#[derive(Serialize)]
+struct IndexContext {
+    user: String,
+    calculator: String,
+    calculators: Vec<CalculatorInfo>,
+    multiple_calculators: bool,
+    ruleset: Ruleset,
+    /// Whether there's a `scores.db` to calculate local profiles from.
+    local_profiles: bool,
+}
+
+#[get("/?<user>&<calculator>&<ruleset>")]
+fn index(
+    registry: State<Arc<CalculatorRegistry>>,
+    local_queue: State<Option<LocalProfileQueue>>,
+    user: Option<String>,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+) -> Template {
     let mut _user = user.unwrap_or(String::new()).clone();
     _user.make_ascii_lowercase();
 
-    let mut context: HashMap<String, String> = HashMap::new();
-    context.insert("user".to_string(), _user.clone());
+    let calculators = registry.calculators();
+    let context = IndexContext {
+        user: _user,
+        calculator: calculator
+            .unwrap_or(DEFAULT_CALCULATOR.to_string())
+            .to_lowercase(),
+        multiple_calculators: calculators.len() > 1,
+        calculators: calculators,
+        ruleset: Ruleset::resolve(ruleset).unwrap_or_default(),
+        local_profiles: local_queue.is_some(),
+    };
 
     Template::render("index", &context)
 }
 
-#[get("/pp?<user>")]
-fn pp(cache: State<Arc<ProfileCache>>, mut user: String) -> Result<Template, Redirect> {
+#[derive(Serialize)]
+struct PpContext<'a> {
+    #[serde(flatten)]
+    results: &'a ProfileResults,
+    calculator: CalculatorInfo,
+    ruleset: &'static str,
+}
+
+#[get("/pp?<user>&<calculator>&<ruleset>")]
+fn pp(
+    cache: State<Arc<ProfileCache>>,
+    registry: State<Arc<CalculatorRegistry>>,
+    mut user: String,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+) -> Result<Template, Redirect> {
     user.make_ascii_lowercase();
 
-    if let Some((results, _)) = cache.get(user.clone()) {
-        Ok(Template::render("pp", &results))
-    } else {
-        Err(Redirect::to(uri!(index: user)))
+    let resolved_ruleset = Ruleset::resolve(ruleset.clone());
+    if let (Ok((info, _)), Ok(resolved_ruleset)) =
+        (registry.resolve(calculator.clone()), resolved_ruleset)
+    {
+        if let Some((results, _)) = cache.get(&info.name, resolved_ruleset, user.clone()) {
+            let context = PpContext {
+                results: &results,
+                calculator: info,
+                ruleset: resolved_ruleset.display_name(),
+            };
+
+            return Ok(Template::render("pp", &context));
+        }
+    }
+
+    let calculator = calculator.unwrap_or(DEFAULT_CALCULATOR.to_string());
+    let ruleset = ruleset.unwrap_or(Ruleset::default().to_string());
+    Err(Redirect::to(uri!(index: user, calculator, ruleset)))
+}
+
+/// The profile of `user` calculated from their plays on the local `scores.db`
+/// (see `scores_db`), shown like the other profiles, once it's been requested
+/// on `/pp_local_request` and is done.
+#[get("/pp_local?<user>&<calculator>&<ruleset>")]
+fn pp_local(
+    registry: State<Arc<CalculatorRegistry>>,
+    local_queue: State<Option<LocalProfileQueue>>,
+    user: String,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+) -> Result<Template, Redirect> {
+    let resolved_ruleset = Ruleset::resolve(ruleset.clone());
+    if let (Some(local_queue), Ok((info, _)), Ok(resolved_ruleset)) = (
+        local_queue.as_ref(),
+        registry.resolve(calculator.clone()),
+        resolved_ruleset,
+    ) {
+        if let Some(results) = local_queue.results(&info.name, resolved_ruleset, user.clone()) {
+            let context = PpContext {
+                results: &results.profile,
+                calculator: info,
+                ruleset: resolved_ruleset.display_name(),
+            };
+
+            return Ok(Template::render("pp", &context));
+        }
+    }
+
+    let calculator = calculator.unwrap_or(DEFAULT_CALCULATOR.to_string());
+    let ruleset = ruleset.unwrap_or(Ruleset::default().to_string());
+    Err(Redirect::to(uri!(index: user, calculator, ruleset)))
+}
+
+/// Requests the local profile of `user` (see `pp_local`). Local profiles
+/// aren't cached, since the file changes with every play, so they're always
+/// calculated again.
+#[get("/pp_local_request?<user>&<calculator>&<ruleset>")]
+fn pp_local_request(
+    registry: State<Arc<CalculatorRegistry>>,
+    local_queue: State<Option<LocalProfileQueue>>,
+    user: String,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+) -> JsonValue {
+    let local_queue = match local_queue.as_ref() {
+        Some(local_queue) => local_queue,
+        None => {
+            return error_json(&CalculationError::Unsupported(
+                "Calculating local profiles without a scores.db".to_string(),
+            ))
+        }
+    };
+    let calculator = match registry.resolve(calculator) {
+        Ok((info, _)) => info.name,
+        Err(error) => return error_json(&error),
+    };
+    let ruleset = match Ruleset::resolve(ruleset) {
+        Ok(ruleset) => ruleset,
+        Err(error) => return error_json(&error),
+    };
+
+    println!(
+        "Local profile request for {} ({}, {})",
+        user, calculator, ruleset
+    );
+    if !local_queue.enqueue(&calculator, ruleset, user) {
+        return json!({ "status": "busy" });
     }
+
+    json!({ "status": "accepted" })
+}
+
+/// The status of a local profile request, like `/pp_check`.
+#[get("/pp_local_check?<user>&<calculator>&<ruleset>")]
+fn pp_local_check(
+    registry: State<Arc<CalculatorRegistry>>,
+    local_queue: State<Option<LocalProfileQueue>>,
+    user: String,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+) -> JsonValue {
+    let calculator = match registry.resolve(calculator) {
+        Ok((info, _)) => info.name,
+        Err(error) => return error_json(&error),
+    };
+    let ruleset = match Ruleset::resolve(ruleset) {
+        Ok(ruleset) => ruleset,
+        Err(error) => return error_json(&error),
+    };
+
+    let status = local_queue
+        .as_ref()
+        .and_then(|local_queue| local_queue.status(&calculator, ruleset, user));
+    request_status_json(status)
 }
 
-#[get("/pp_request?<user>&<force>")]
+#[get("/pp_request?<user>&<force>&<calculator>&<ruleset>")]
 fn pp_request(
     cache: State<Arc<ProfileCache>>,
     queue: State<ProfileQueue>,
+    registry: State<Arc<CalculatorRegistry>>,
     mut user: String,
     force: Option<bool>,
+    calculator: Option<String>,
+    ruleset: Option<String>,
 ) -> JsonValue {
     let _force = force.unwrap_or(false);
     user.make_ascii_lowercase();
 
-    println!("PP-request for {}", user);
+    let calculator = match registry.resolve(calculator) {
+        Ok((info, _)) => info.name,
+        Err(error) => return error_json(&error),
+    };
+    let ruleset = match Ruleset::resolve(ruleset) {
+        Ok(ruleset) => ruleset,
+        Err(error) => return error_json(&error),
+    };
+
+    println!("PP-request for {} ({}, {})", user, calculator, ruleset);
     // This logic is still a bit convoluted...
-    match cache.get(user.clone()) {
+    match cache.get(&calculator, ruleset, user.clone()) {
         Some((_, time)) => {
             if !_force {
                 return json!({ "status": "done" });
@@ -88,43 +286,597 @@ fn pp_request(
         None => {}
     }
 
-    queue.enqueue(user);
+    queue.enqueue(&calculator, ruleset, user);
     json!({ "status": "accepted" })
 }
 
-#[get("/pp_check?<user>")]
-fn pp_check(queue: State<ProfileQueue>, mut user: String) -> JsonValue {
+/// The JSON response for a failed calculation. Timeouts have their own status,
+/// and every error carries a machine-readable code and a message. Invalid
+/// params also name the offending field.
+fn error_json(error: &CalculationError) -> JsonValue {
+    let status = match *error {
+        CalculationError::Timeout => "timed_out",
+        _ => "error",
+    };
+
+    let mut response = json!({
+        "status": status,
+        "error": { "code": error.code(), "message": error.to_string() }
+    });
+    if let CalculationError::InvalidParams { ref field, .. } = *error {
+        response["error"]["field"] = serde_json::Value::String(field.clone());
+    }
+
+    response
+}
+
+#[get("/pp_check?<user>&<calculator>&<ruleset>")]
+fn pp_check(
+    queue: State<ProfileQueue>,
+    registry: State<Arc<CalculatorRegistry>>,
+    mut user: String,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+) -> JsonValue {
     user.make_ascii_lowercase();
 
-    if let Some(status) = queue.status(user) {
+    let calculator = match registry.resolve(calculator) {
+        Ok((info, _)) => info.name,
+        Err(error) => return error_json(&error),
+    };
+    let ruleset = match Ruleset::resolve(ruleset) {
+        Ok(ruleset) => ruleset,
+        Err(error) => return error_json(&error),
+    };
+
+    request_status_json(queue.status(&calculator, ruleset, user))
+}
+
+/// The JSON response for the `status` of a profile request, if there's one.
+fn request_status_json(status: Option<RequestStatus>) -> JsonValue {
+    if let Some(status) = status {
         match status {
             RequestStatus::Pending(pos) => json!( { "status": "pending", "pos": pos } ),
             RequestStatus::Calculating => json!( { "status": "calculating" } ),
             RequestStatus::Done => json!( { "status": "done" } ),
-            RequestStatus::Error => json!( { "status": "error" } ),
+            RequestStatus::Error(error) => error_json(&error),
         }
     } else {
         json!( { "status": "error" } )
     }
 }
 
+/// A simulation request. The beatmap is either given by `beatmap_id`, or by
+/// `beatmap`, a beatmap id or link (see `parse_beatmap_reference`).
 #[derive(Deserialize)]
 struct SimulateData {
-    beatmap_id: i64,
+    beatmap_id: Option<i64>,
+    beatmap: Option<String>,
     params: SimulationParams,
+    calculator: Option<String>,
+}
+
+/// The id of the beatmap of a request, given either by `beatmap_id`, or by
+/// `beatmap`, a beatmap id or link.
+fn resolve_beatmap_id(
+    beatmap_id: Option<i64>,
+    beatmap: &Option<String>,
+) -> Result<i64, CalculationError> {
+    match (beatmap_id, beatmap) {
+        (Some(beatmap_id), _) => Ok(beatmap_id),
+        (None, Some(reference)) => parse_beatmap_reference(reference),
+        (None, None) => Err(CalculationError::InvalidParams {
+            field: "beatmap".to_string(),
+            message: "either beatmap_id or beatmap should be given".to_string(),
+        }),
+    }
+}
+
+/// Responds with `400 Bad Request` for invalid params, and with the error
+/// JSON otherwise.
+fn simulate_error(error: CalculationError) -> Result<JsonValue, BadRequest<JsonValue>> {
+    match error {
+        CalculationError::InvalidParams { .. } => Err(BadRequest(Some(error_json(&error)))),
+        _ => Ok(error_json(&error)),
+    }
+}
+
+/// Compares `results`, simulated by `calculator`, with the live PP system,
+/// simulating the same play on the live calculator with `simulate`. Returns
+/// `None` if there's no live calculator, if `calculator` is the live one, or if
+/// the live simulation failed.
+fn compare_with_live<F>(
+    registry: &CalculatorRegistry,
+    calculator: &CalculatorInfo,
+    results: &SimulationResults,
+    simulate: F,
+) -> Option<LiveComparison>
+where
+    F: FnOnce(&dyn PerformanceBackend) -> Result<SimulationResults, CalculationError>,
+{
+    let (live, backend) = registry.live()?;
+    if live.name == calculator.name {
+        return None;
+    }
+
+    match simulate(&*backend) {
+        Ok(live_results) => Some(LiveComparison::new(live.name, live_results.pp, results.pp)),
+        Err(error) => {
+            println!("Live simulation failed! {:?}", error);
+            None
+        }
+    }
 }
 
 #[post("/simulate", data = "<json_data>")]
-fn simulate(json_data: Json<SimulateData>) -> JsonValue {
+fn simulate(
+    registry: State<Arc<CalculatorRegistry>>,
+    beatmaps: State<Arc<BeatmapCache>>,
+    json_data: Json<SimulateData>,
+) -> Result<JsonValue, BadRequest<JsonValue>> {
+    let data = json_data.into_inner();
+    let beatmap_id = match resolve_beatmap_id(data.beatmap_id, &data.beatmap) {
+        Ok(beatmap_id) => beatmap_id,
+        Err(error) => return simulate_error(error),
+    };
+    let (info, backend) = match registry.resolve(data.calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error),
+    };
+
+    println!(
+        "Simul request for {} ({}, {})",
+        beatmap_id, info.name, data.params.ruleset
+    );
+    match simulate_play(&*backend, &beatmaps, beatmap_id, data.params.clone()) {
+        Ok(res) => {
+            let live = compare_with_live(&registry, &info, &res, |live| {
+                simulate_play(live, &beatmaps, beatmap_id, data.params)
+            });
+
+            Ok(json!( { "status": "ok", "calculator": info, "results": res, "live": live } ))
+        }
+        Err(error) => simulate_error(error),
+    }
+}
+
+#[derive(Deserialize)]
+struct SimulateFileData {
+    osu_file: String,
+    params: SimulationParams,
+    calculator: Option<String>,
+}
+
+/// Like `/simulate`, but on an uploaded .osu file instead of a beatmap id. The
+/// body is read here (instead of through `Json`), so that it's limited by the
+/// upload size, and not by Rocket's JSON limit.
+#[post("/simulate_file", data = "<data>")]
+fn simulate_file(
+    registry: State<Arc<CalculatorRegistry>>,
+    uploads: State<BeatmapUploads>,
+    data: Data,
+) -> Result<JsonValue, BadRequest<JsonValue>> {
+    // Escaping may make the .osu file bigger once in JSON.
+    let limit = uploads.max_bytes() as u64 * 2 + 64 * 1024;
+    let mut body = Vec::new();
+    if let Err(e) = data.open().take(limit + 1).read_to_end(&mut body) {
+        return simulate_error(CalculationError::from(e));
+    }
+    if body.len() as u64 > limit {
+        return simulate_error(CalculationError::InvalidParams {
+            field: "osu_file".to_string(),
+            message: format!("should be at most {} bytes", uploads.max_bytes()),
+        });
+    }
+
+    let data: SimulateFileData = match serde_json::from_slice(&body) {
+        Ok(data) => data,
+        Err(e) => {
+            return simulate_error(CalculationError::InvalidParams {
+                field: "body".to_string(),
+                message: e.to_string(),
+            })
+        }
+    };
+    let (info, backend) = match registry.resolve(data.calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error),
+    };
+    let beatmap_path = match uploads.store(data.osu_file.as_bytes()) {
+        Ok(path) => path,
+        Err(error) => return simulate_error(error),
+    };
+
+    println!(
+        "Simul request for uploaded {} ({}, {})",
+        beatmap_path, info.name, data.params.ruleset
+    );
+    match simulate_play_file(&*backend, &beatmap_path, data.params.clone()) {
+        Ok(res) => {
+            let live = compare_with_live(&registry, &info, &res, |live| {
+                simulate_play_file(live, &beatmap_path, data.params)
+            });
+
+            Ok(json!( { "status": "ok", "calculator": info, "results": res, "live": live } ))
+        }
+        Err(error) => simulate_error(error),
+    }
+}
+
+/// How much of an uploaded replay is read. Only the header is needed, which is
+/// way smaller than this, unless the player name is absurdly long.
+const MAX_REPLAY_HEADER_BYTES: u64 = 64 * 1024;
+
+/// Like `/simulate`, but on the play recorded in an uploaded replay (.osr) file,
+/// which is the request body. The beatmap is found by the checksum the replay
+/// has, and the play is described by its hits, combo and mods.
+#[post("/simulate_replay?<calculator>", data = "<data>")]
+fn simulate_replay(
+    registry: State<Arc<CalculatorRegistry>>,
+    beatmaps: State<Arc<BeatmapCache>>,
+    calculator: Option<String>,
+    data: Data,
+) -> Result<JsonValue, BadRequest<JsonValue>> {
+    let mut header = Vec::new();
+    if let Err(e) = data
+        .open()
+        .take(MAX_REPLAY_HEADER_BYTES)
+        .read_to_end(&mut header)
+    {
+        return simulate_error(CalculationError::from(e));
+    }
+
+    let replay = match Replay::parse(&header) {
+        Ok(replay) => replay,
+        Err(error) => return simulate_error(error),
+    };
+    let (info, backend) = match registry.resolve(calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error),
+    };
+    let beatmap_id = match beatmaps.find_by_md5(&replay.beatmap_md5) {
+        Ok(beatmap_id) => beatmap_id,
+        Err(error) => return simulate_error(error),
+    };
+    let params = replay.simulation_params();
+
+    println!(
+        "Simul request for a replay on {} ({}, {})",
+        beatmap_id, info.name, params.ruleset
+    );
+    match simulate_play(&*backend, &beatmaps, beatmap_id, params.clone()) {
+        Ok(res) => {
+            let live = compare_with_live(&registry, &info, &res, |live| {
+                simulate_play(live, &beatmaps, beatmap_id, params.clone())
+            });
+
+            Ok(json!( {
+                "status": "ok",
+                "calculator": info,
+                "beatmap_id": beatmap_id,
+                "replay": replay,
+                "params": params,
+                "results": res,
+                "live": live
+            } ))
+        }
+        Err(error) => simulate_error(error),
+    }
+}
+
+/// A request to simulate a play on every difficulty of a set. `beatmapset` is
+/// a set id or link (see `parse_beatmapset_reference`).
+#[derive(Deserialize)]
+struct SimulateSetData {
+    beatmapset: String,
+    params: SimulationParams,
+    calculator: Option<String>,
+}
+
+#[post("/simulate_set", data = "<json_data>")]
+fn simulate_set(
+    registry: State<Arc<CalculatorRegistry>>,
+    beatmaps: State<Arc<BeatmapCache>>,
+    json_data: Json<SimulateSetData>,
+) -> Result<JsonValue, BadRequest<JsonValue>> {
+    let data = json_data.into_inner();
+    let set_id = match parse_beatmapset_reference(&data.beatmapset) {
+        Ok(set_id) => set_id,
+        Err(error) => return simulate_error(error),
+    };
+    let (info, backend) = match registry.resolve(data.calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error),
+    };
+    if let Err(error) = data.params.validate() {
+        return simulate_error(error);
+    }
+    let difficulties = match fetch_beatmapset(set_id) {
+        Ok(difficulties) => difficulties,
+        Err(error) => return simulate_error(error),
+    };
+
+    println!(
+        "Simul request for set {} ({}, {})",
+        set_id, info.name, data.params.ruleset
+    );
+    match simulate_beatmapset(&*backend, &beatmaps, set_id, &difficulties, data.params) {
+        Ok(res) => Ok(json!( { "status": "ok", "calculator": info, "results": res } )),
+        Err(error) => simulate_error(error),
+    }
+}
+
+/// A request to simulate many plays on the same beatmap, given like in
+/// `SimulateData`. The plays are either listed as `params`, or given as a
+/// `sweep` (see `Batch`).
+#[derive(Deserialize)]
+struct SimulateBatchData {
+    beatmap_id: Option<i64>,
+    beatmap: Option<String>,
+    #[serde(flatten)]
+    batch: Batch,
+    calculator: Option<String>,
+}
+
+#[post("/simulate_batch", data = "<json_data>")]
+fn simulate_batch_route(
+    registry: State<Arc<CalculatorRegistry>>,
+    beatmaps: State<Arc<BeatmapCache>>,
+    json_data: Json<SimulateBatchData>,
+) -> Result<JsonValue, BadRequest<JsonValue>> {
+    let data = json_data.into_inner();
+    let beatmap_id = match resolve_beatmap_id(data.beatmap_id, &data.beatmap) {
+        Ok(beatmap_id) => beatmap_id,
+        Err(error) => return simulate_error(error),
+    };
+    let (info, backend) = match registry.resolve(data.calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error),
+    };
+
+    println!("Batch simul request for {} ({})", beatmap_id, info.name);
+    match simulate_batch(
+        &*backend,
+        &beatmaps,
+        beatmap_id,
+        &data.batch,
+        batch_timeout(),
+    ) {
+        Ok(res) => Ok(json!( { "status": "ok", "calculator": info, "results": res } )),
+        Err(error) => simulate_error(error),
+    }
+}
+
+/// A request for a PP curve on a beatmap, given like in `SimulateData`. The
+/// plays are like `params`, except for the value the `curve` is drawn over.
+#[derive(Deserialize)]
+struct SimulateCurveData {
+    beatmap_id: Option<i64>,
+    beatmap: Option<String>,
+    params: SimulationParams,
+    curve: CurveSpec,
+    calculator: Option<String>,
+}
+
+/// Serves `json` as a string, for routes that may serve other formats.
+fn json_content(json: JsonValue) -> Content<String> {
+    Content(ContentType::JSON, json.to_string())
+}
+
+/// A PP curve, as JSON (like the other simulation routes), or as CSV with
+/// `?format=csv`. Errors are always JSON.
+#[post("/simulate_curve?<format>", data = "<json_data>")]
+fn simulate_curve_route(
+    registry: State<Arc<CalculatorRegistry>>,
+    beatmaps: State<Arc<BeatmapCache>>,
+    format: Option<String>,
+    json_data: Json<SimulateCurveData>,
+) -> Result<Content<String>, BadRequest<JsonValue>> {
     let data = json_data.into_inner();
-    println!("Simul request for {}", data.beatmap_id);
-    match simulate_play(data.beatmap_id, data.params) {
-        Ok(res) => json!( { "status": "ok", "results": res } ),
-        Err(_) => json!( { "status": "error" } ),
+    let beatmap_id = match resolve_beatmap_id(data.beatmap_id, &data.beatmap) {
+        Ok(beatmap_id) => beatmap_id,
+        Err(error) => return simulate_error(error).map(json_content),
+    };
+    let (info, backend) = match registry.resolve(data.calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error).map(json_content),
+    };
+
+    println!(
+        "Curve request for {} ({}, {})",
+        beatmap_id,
+        info.name,
+        data.curve.axis.name()
+    );
+    let curve = match simulate_curve(
+        &*backend,
+        &beatmaps,
+        beatmap_id,
+        data.params,
+        &data.curve,
+        batch_timeout(),
+    ) {
+        Ok(curve) => curve,
+        Err(error) => return simulate_error(error).map(json_content),
+    };
+
+    match format.as_ref().map(String::as_str) {
+        Some("csv") => Ok(Content(ContentType::CSV, curve.to_csv())),
+        _ => Ok(json_content(
+            json!( { "status": "ok", "calculator": info, "results": curve } ),
+        )),
+    }
+}
+
+/// How big the score list of `/profile_from_scores` can be.
+const MAX_SCORE_LIST_BYTES: u64 = 1024 * 1024;
+
+/// Calculates a profile from the score list (JSON or CSV, see `parse_score_list`)
+/// on the request body, instead of from the user top plays on the osu! api.
+/// Results aren't cached, since they're only as real as the list is. Scores
+/// left once the profile timeout is over are reported as failed.
+#[post(
+    "/profile_from_scores?<user>&<calculator>&<ruleset>&<bonus_pp>",
+    data = "<data>"
+)]
+fn profile_from_scores(
+    registry: State<Arc<CalculatorRegistry>>,
+    beatmaps: State<Arc<BeatmapCache>>,
+    user: Option<String>,
+    calculator: Option<String>,
+    ruleset: Option<String>,
+    bonus_pp: Option<f64>,
+    data: Data,
+) -> Result<JsonValue, BadRequest<JsonValue>> {
+    let mut body = Vec::new();
+    if let Err(e) = data
+        .open()
+        .take(MAX_SCORE_LIST_BYTES + 1)
+        .read_to_end(&mut body)
+    {
+        return simulate_error(CalculationError::from(e));
+    }
+    if body.len() as u64 > MAX_SCORE_LIST_BYTES {
+        return simulate_error(CalculationError::InvalidParams {
+            field: "scores".to_string(),
+            message: format!("should be at most {} bytes", MAX_SCORE_LIST_BYTES),
+        });
+    }
+
+    let scores = match String::from_utf8(body)
+        .map_err(|_| CalculationError::InvalidParams {
+            field: "scores".to_string(),
+            message: "should be UTF-8 text".to_string(),
+        })
+        .and_then(|body| parse_score_list(&body))
+    {
+        Ok(scores) => scores,
+        Err(error) => return simulate_error(error),
+    };
+    let ruleset = match Ruleset::resolve(ruleset) {
+        Ok(ruleset) => ruleset,
+        Err(error) => return simulate_error(error),
+    };
+    let (info, backend) = match registry.resolve(calculator) {
+        Ok(resolved) => resolved,
+        Err(error) => return simulate_error(error),
+    };
+    let user = user.unwrap_or_else(|| "Score list".to_string());
+
+    println!(
+        "Score list request for {} ({}, {}, {} scores)",
+        user,
+        info.name,
+        ruleset,
+        scores.len()
+    );
+    match calculate_score_list(
+        &*backend,
+        &beatmaps,
+        user,
+        ruleset,
+        &scores,
+        bonus_pp,
+        profile_timeout(),
+    ) {
+        Ok(res) => Ok(json!( {
+            "status": "ok",
+            "calculator": info,
+            "results": res.profile,
+            "failed": res.failed
+        } )),
+        Err(error) => simulate_error(error),
+    }
+}
+
+/// Runs `score-list <file> [user] [ruleset] [calculator]`: calculates a profile
+/// from the score list on `file`, like `/profile_from_scores`, and prints it as
+/// JSON.
+fn score_list_command(
+    registry: &CalculatorRegistry,
+    beatmaps: &BeatmapCache,
+    args: &[String],
+) -> Result<(), String> {
+    let file = args.get(0).ok_or_else(|| {
+        "Usage: osu-pp-rebalance score-list <file> [user] [ruleset] [calculator]".to_string()
+    })?;
+    let contents =
+        fs::read_to_string(file).map_err(|e| format!("Couldn't read {}: {}", file, e))?;
+
+    let scores = parse_score_list(&contents).map_err(|e| e.to_string())?;
+    let user = args.get(1).cloned().unwrap_or_else(|| file.clone());
+    let ruleset = Ruleset::resolve(args.get(2).cloned()).map_err(|e| e.to_string())?;
+    let (_, backend) = registry
+        .resolve(args.get(3).cloned())
+        .map_err(|e| e.to_string())?;
+
+    let results = calculate_score_list(
+        &*backend,
+        beatmaps,
+        user,
+        ruleset,
+        &scores,
+        None,
+        profile_timeout(),
+    )
+    .map_err(|e| e.to_string())?;
+    println!("{}", serde_json::to_string_pretty(&results).unwrap());
+
+    Ok(())
+}
+
+/// Whether `token` is the admin token. It's compared in constant time, so
+/// response times don't give away how much of it was right.
+fn is_admin(token: Option<String>) -> bool {
+    match (admin_token(), token) {
+        (Some(expected), Some(token)) => {
+            expected.len() == token.len()
+                && expected
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |difference, (a, b)| difference | (a ^ b))
+                    == 0
+        }
+        _ => false,
+    }
+}
+
+/// Size and hit rate of the beatmaps cache. Needs the admin token.
+#[get("/admin/beatmap_cache?<token>")]
+fn beatmap_cache_stats(
+    beatmaps: State<Arc<BeatmapCache>>,
+    token: Option<String>,
+) -> Result<JsonValue, Status> {
+    if !is_admin(token) {
+        return Err(Status::Forbidden);
     }
+
+    Ok(json!( { "status": "ok", "stats": beatmaps.stats() } ))
+}
+
+/// Size and hit rate of the simulation results cache. Needs the admin token.
+#[get("/admin/simulation_cache?<token>")]
+fn simulation_cache_stats(
+    simulations: State<Option<Arc<SimulationCache>>>,
+    token: Option<String>,
+) -> Result<JsonValue, Status> {
+    if !is_admin(token) {
+        return Err(Status::Forbidden);
+    }
+
+    let stats = simulations.as_ref().map(|simulations| simulations.stats());
+    Ok(json!( { "status": "ok", "stats": stats } ))
 }
 
-fn build_rocket(cache: Arc<ProfileCache>, queue: ProfileQueue) -> Rocket {
+fn build_rocket(
+    cache: Arc<ProfileCache>,
+    queue: ProfileQueue,
+    registry: Arc<CalculatorRegistry>,
+    beatmaps: Arc<BeatmapCache>,
+    uploads: BeatmapUploads,
+    simulations: Option<Arc<SimulationCache>>,
+    local_queue: Option<LocalProfileQueue>,
+) -> Rocket {
     rocket::ignite()
         .attach(Template::custom(|engines| {
             engines
@@ -136,19 +888,138 @@ fn build_rocket(cache: Arc<ProfileCache>, queue: ProfileQueue) -> Rocket {
         }))
         .manage(cache)
         .manage(queue)
+        .manage(registry)
+        .manage(beatmaps)
+        .manage(uploads)
+        .manage(simulations)
+        .manage(local_queue)
         .mount("/", routes![index])
         .mount("/", routes![pp])
+        .mount("/", routes![pp_local])
+        .mount("/", routes![pp_local_request])
+        .mount("/", routes![pp_local_check])
         .mount("/", routes![pp_request])
         .mount("/", routes![pp_check])
         .mount("/", routes![simulate])
+        .mount("/", routes![simulate_file])
+        .mount("/", routes![simulate_replay])
+        .mount("/", routes![simulate_set])
+        .mount("/", routes![simulate_batch_route])
+        .mount("/", routes![simulate_curve_route])
+        .mount("/", routes![profile_from_scores])
+        .mount("/", routes![beatmap_cache_stats])
+        .mount("/", routes![simulation_cache_stats])
         .mount(
             "/static",
             StaticFiles::from(concat!(env!("CARGO_MANIFEST_DIR"), "/static")),
         )
 }
 
+/// Creates a backend for the PerformanceCalculator.dll at `calculator_path`.
+fn dotnet_backend(calculator_path: String, timeouts: Timeouts) -> Arc<dyn PerformanceBackend> {
+    Arc::new(DotnetBackend::new(
+        dotnet_command(),
+        calculator_path,
+        timeouts,
+    ))
+}
+
+/// Wraps `backend`, registered as `name`, so its simulations are looked up on
+/// `simulations` first, if the simulation cache is enabled.
+fn cached_backend(
+    name: &str,
+    backend: Arc<dyn PerformanceBackend>,
+    simulations: &Option<Arc<SimulationCache>>,
+) -> Arc<dyn PerformanceBackend> {
+    match simulations {
+        Some(simulations) => Arc::new(CachingBackend::new(
+            name.to_string(),
+            backend,
+            simulations.clone(),
+        )),
+        None => backend,
+    }
+}
+
 fn main() {
-    if api_key() == "" {
+    let timeouts = Timeouts {
+        profile: profile_timeout(),
+        simulate: simulate_timeout(),
+    };
+
+    let backend: Arc<dyn PerformanceBackend> = match calculator_backend().as_str() {
+        "dotnet" => dotnet_backend(performance_calculator_path(), timeouts),
+        "native" => Arc::new(NativeBackend::new()),
+        other => panic!("Unknown calculator backend {}! Exiting!", other),
+    };
+
+    let simulations = if simulation_cache_size() > 0 {
+        Some(Arc::new(SimulationCache::new(
+            simulation_cache_size(),
+            simulation_cache_dir(),
+            simulation_cache_max_files(),
+        )))
+    } else {
+        None
+    };
+
+    let backend = cached_backend(DEFAULT_CALCULATOR, backend, &simulations);
+    let mut registry = CalculatorRegistry::new("Default".to_string(), backend);
+    if let Some(file) = calculators_file() {
+        let builds = match load_builds(&file) {
+            Ok(builds) => builds,
+            Err(e) => panic!("Couldn't load calculators from {}: {}", file, e),
+        };
+
+        for build in builds {
+            println!("Registering calculator {} ({})", build.name, build.label);
+            let backend = cached_backend(
+                &build.name,
+                dotnet_backend(build.path, timeouts),
+                &simulations,
+            );
+            registry.register(build.name, build.label, backend);
+        }
+    }
+    if let Some(name) = live_calculator() {
+        if let Err(e) = registry.set_live(&name) {
+            panic!("Couldn't use {} as the live calculator: {}", name, e);
+        }
+    }
+    let registry = Arc::new(registry);
+
+    let source: Box<dyn BeatmapSource> = match beatmap_source().as_str() {
+        "official" => Box::new(OfficialSource),
+        "mirror" => Box::new(MirrorSource::new(beatmap_mirror_url())),
+        "local" => Box::new(LocalDirectorySource::new(beatmap_source_dir())),
+        "offline" => Box::new(OfflineSource),
+        other => panic!("Unknown beatmap source {}! Exiting!", other),
+    };
+    let limits = CacheLimits {
+        max_bytes: Some(beatmaps_cache_max_mb() * 1024 * 1024).filter(|&max| max > 0),
+        max_files: Some(beatmaps_cache_max_files()).filter(|&max| max > 0),
+    };
+    let beatmaps = Arc::new(BeatmapCache::new(
+        beatmaps_cache(),
+        source,
+        verify_beatmap_md5(),
+        limits,
+    ));
+
+    // `score-list` calculates a profile offline, instead of starting the server.
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.get(0).map(String::as_str) == Some("score-list") {
+        if let Err(e) = score_list_command(&registry, &beatmaps, &args[1..]) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // The native backend doesn't talk to the osu! api.
+    let needs_api =
+        calculator_backend() == "dotnet" || calculators_file().is_some() || verify_beatmap_md5();
+    if needs_api && api_key() == "" {
         panic!("No api key was set! Exiting!")
     }
 
@@ -162,7 +1033,32 @@ fn main() {
         cache.setup_save_results_handler(results_file());
     }
 
-    let queue = ProfileQueue::new(cache.clone(), num_threads());
+    let prefetch = if prefetch_profile_beatmaps() {
+        Some(beatmaps.clone())
+    } else {
+        None
+    };
+    let queue = ProfileQueue::new(cache.clone(), registry.clone(), prefetch, num_threads());
+
+    let uploads = BeatmapUploads::new(uploads_dir(), upload_max_kb() * 1024, uploads_max_files());
+
+    let local_queue = scores_db().map(|path| {
+        LocalProfileQueue::new(
+            ScoresDbFile::new(path),
+            registry.clone(),
+            beatmaps.clone(),
+            profile_timeout(),
+        )
+    });
 
-    build_rocket(cache, queue).launch();
+    build_rocket(
+        cache,
+        queue,
+        registry,
+        beatmaps,
+        uploads,
+        simulations,
+        local_queue,
+    )
+    .launch();
 }
@@ -0,0 +1,241 @@
+//! Accuracy of plays, and conversions between accuracy percentages and hit
+//! counts.
+//!
+//! Plays can be described either by an accuracy percentage, or by their
+//! number of non-perfect hits. Converting between both needs the number of
+//! objects that count for accuracy, which is read from the .osu file.
+use super::{CalculationError, Ruleset};
+use crate::beatmap::Beatmap;
+
+/// A data type that represents the Accuracy of a play.
+///
+/// Can either be a *Percentage*, or *Hits*, which contains the number of
+/// non-300s (perfect) hits of a play: good (100s) and meh (50s). In
+/// osu!taiko, there are no mehs; and in osu!catch, goods are the droplets
+/// and mehs are the tiny droplets caught. osu!mania plays are described by
+/// their score instead (see `SimulationParams`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Accuracy {
+    Percentage(f64),
+    Hits { good: usize, meh: usize },
+}
+
+impl Default for Accuracy {
+    /// A SS, which is what osu!mania plays (that don't use accuracy) report.
+    fn default() -> Self {
+        Accuracy::Percentage(100.0)
+    }
+}
+
+/// The number of 300s, 100s, 50s and misses of a play. In osu!taiko, greats
+/// and goods are the only hits.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HitResults {
+    pub great: usize,
+    pub good: usize,
+    pub meh: usize,
+    pub miss: usize,
+}
+
+impl HitResults {
+    pub fn total(&self) -> usize {
+        self.great + self.good + self.meh + self.miss
+    }
+
+    /// The accuracy of these hit results on `ruleset`, between 0 and 1.
+    pub fn accuracy(&self, ruleset: Ruleset) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+
+        match ruleset {
+            Ruleset::Taiko => (self.great * 2 + self.good) as f64 / (total * 2) as f64,
+            _ => (self.great * 300 + self.good * 100 + self.meh * 50) as f64 / (total * 300) as f64,
+        }
+    }
+}
+
+/// The number of objects of `beatmap` that count for accuracy when played on
+/// `ruleset`, or `None` if that isn't known. Only beatmaps made for `ruleset`
+/// are supported (converts aren't), and only on osu!standard and osu!taiko,
+/// where every object (or every circle, in osu!taiko) is a single judgement.
+pub fn object_count(beatmap: &Beatmap, ruleset: Ruleset) -> Option<usize> {
+    if beatmap.mode != ruleset.id() {
+        return None;
+    }
+
+    match ruleset {
+        Ruleset::Osu => Some(beatmap.hit_objects.len()),
+        Ruleset::Taiko => Some(beatmap.hit_objects.iter().filter(|h| h.is_circle()).count()),
+        Ruleset::Catch | Ruleset::Mania => None,
+    }
+}
+
+/// Generates the hit results of a play on `ruleset`, on a beatmap with `total`
+/// objects, with `misses` misses and `accuracy`. When `accuracy` is a percentage,
+/// the 100s and 50s are distributed the same way osu-tools does.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` if the hits (or misses) don't
+/// fit in `total` objects.
+pub fn hit_results(
+    accuracy: Accuracy,
+    ruleset: Ruleset,
+    total: usize,
+    misses: usize,
+) -> Result<HitResults, CalculationError> {
+    let invalid = |field: &str, count: usize| CalculationError::InvalidParams {
+        field: field.to_string(),
+        message: format!(
+            "{} hits don't fit in a beatmap with {} objects",
+            count, total
+        ),
+    };
+
+    if misses > total {
+        return Err(invalid("misses", misses));
+    }
+
+    match accuracy {
+        Accuracy::Hits { good, meh } => {
+            if good + meh + misses > total {
+                return Err(invalid("accuracy", good + meh + misses));
+            }
+
+            Ok(HitResults {
+                great: total - good - meh - misses,
+                good: good,
+                meh: meh,
+                miss: misses,
+            })
+        }
+        Accuracy::Percentage(pct) if ruleset == Ruleset::Taiko => {
+            let hits = (total - misses) as i64;
+
+            // Let great = 2, good = 1, and miss = 0. The total should be this.
+            let target_total = (pct / 100.0 * total as f64 * 2.0).round() as i64;
+
+            // Every hit is a great, unless the total is too high.
+            let good = (hits * 2 - target_total).max(0).min(hits);
+
+            Ok(HitResults {
+                great: (hits - good) as usize,
+                good: good as usize,
+                meh: 0,
+                miss: misses,
+            })
+        }
+        Accuracy::Percentage(pct) => {
+            let hits = (total - misses) as i64;
+
+            // Let great = 6, good = 2, meh = 1, and miss = 0. The total
+            // should be this, as far as the hits can get to it.
+            let target_total = (pct / 100.0 * total as f64 * 6.0).round() as i64;
+            let target_total = target_total.max(hits).min(hits * 6);
+
+            // Start by assuming every hit is a meh. This is how much increase
+            // is needed by greats and goods.
+            let delta = target_total - hits;
+
+            // Each great increases the total by 5, and each good by 1.
+            let great = delta / 5;
+            let good = (delta % 5).min(hits - great);
+            let meh = hits - great - good;
+
+            Ok(HitResults {
+                great: great as usize,
+                good: good as usize,
+                meh: meh as usize,
+                miss: misses,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hit_results() {
+        let osu = Ruleset::Osu;
+
+        let hits = hit_results(Accuracy::Percentage(100.0), osu, 500, 0).unwrap();
+        assert_eq!(hits.great, 500);
+        assert_eq!(hits.good + hits.meh + hits.miss, 0);
+
+        let hits = hit_results(Accuracy::Percentage(98.0), osu, 500, 2).unwrap();
+        assert_eq!(hits.total(), 500);
+        assert_eq!(hits.miss, 2);
+        assert!((hits.accuracy(osu) - 0.98).abs() < 0.001);
+
+        let hits = hit_results(Accuracy::Hits { good: 10, meh: 2 }, osu, 500, 1).unwrap();
+        assert_eq!(hits.great, 487);
+        assert!((hits.accuracy(osu) - (487.0 * 300.0 + 1100.0) / 150000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_hit_results_fit_the_beatmap() {
+        for ruleset in [Ruleset::Osu, Ruleset::Taiko].iter() {
+            for (accuracy, misses) in [
+                (100.0, 2),
+                (99.9, 5),
+                (95.0, 0),
+                (80.0, 50),
+                (50.0, 499),
+                (0.0, 10),
+                (100.0, 500),
+            ]
+            .iter()
+            {
+                let hits =
+                    hit_results(Accuracy::Percentage(*accuracy), *ruleset, 500, *misses).unwrap();
+                assert_eq!(hits.total(), 500, "{:?} {} {}", ruleset, accuracy, misses);
+                assert_eq!(hits.miss, *misses);
+            }
+        }
+
+        let hits = hit_results(Accuracy::Percentage(100.0), Ruleset::Osu, 500, 2).unwrap();
+        assert_eq!((hits.great, hits.good, hits.meh), (498, 0, 0));
+    }
+
+    #[test]
+    fn test_taiko_hit_results() {
+        let taiko = Ruleset::Taiko;
+
+        let hits = hit_results(Accuracy::Percentage(99.0), taiko, 1000, 0).unwrap();
+        assert_eq!(hits.good, 20);
+        assert_eq!(hits.meh, 0);
+        assert!((hits.accuracy(taiko) - 0.99).abs() < 1e-9);
+
+        let hits = hit_results(Accuracy::Percentage(0.0), taiko, 10, 2).unwrap();
+        assert_eq!((hits.great, hits.good, hits.miss), (0, 8, 2));
+    }
+
+    #[test]
+    fn test_impossible_hits() {
+        let field = |result: Result<HitResults, CalculationError>| match result {
+            Err(CalculationError::InvalidParams { field, .. }) => Some(field),
+            _ => None,
+        };
+
+        let hits = Accuracy::Hits { good: 8, meh: 2 };
+        assert!(hit_results(hits, Ruleset::Osu, 11, 1).is_ok());
+        assert_eq!(
+            field(hit_results(hits, Ruleset::Osu, 10, 1)),
+            Some("accuracy".to_string())
+        );
+        assert_eq!(
+            field(hit_results(
+                Accuracy::Percentage(90.0),
+                Ruleset::Osu,
+                10,
+                11
+            )),
+            Some("misses".to_string())
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! The `PerformanceBackend` trait, an abstraction over whatever actually
+//! calculates the PP values.
+//!
+//! The default implementation is `DotnetBackend`, which calls into
+//! osu-tools' PerformanceCalculator.dll, but any type implementing this
+//! trait can be plugged into `ProfileQueue` and the `/simulate` route.
+use super::{CalculationError, ProfileResults, Ruleset, SimulationParams, SimulationResults};
+use std::time::Duration;
+
+/// How long each kind of calculation is allowed to take, for backends that
+/// run external processes. Processes that exceed these are killed, and the
+/// calculation fails with `CalculationError::Timeout`.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub profile: Duration,
+    pub simulate: Duration,
+}
+
+/// Something that can calculate profiles and simulate plays under the new
+/// PP system.
+///
+/// Implementations are shared between the profile queue workers and the
+/// Rocket request threads, so they must be both `Send` and `Sync`.
+pub trait PerformanceBackend: Send + Sync {
+    /// Calculates the new PP system scores for a osu! user profile, on `ruleset`.
+    /// `user`, preferably, should be a user id, but it can also be the user name.
+    fn calculate_profile(
+        &self,
+        user: &str,
+        ruleset: Ruleset,
+    ) -> Result<ProfileResults, CalculationError>;
+
+    /// Simulates a play on the .osu file located at `beatmap_path`, under the
+    /// conditions specified by `params`.
+    fn simulate_play(
+        &self,
+        beatmap_path: &str,
+        params: &SimulationParams,
+    ) -> Result<SimulationResults, CalculationError>;
+
+    /// Identifies the build of the calculator, so results of older builds can
+    /// be told apart (see `SimulationCache`). Backends that can't tell when
+    /// they change return `None`, and their results aren't cached.
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether profile calculations download the beatmaps they need on their
+    /// own, so there's no point in prefetching them (see `ProfileQueue`).
+    fn fetches_profile_beatmaps(&self) -> bool {
+        false
+    }
+
+    /// Simulates several plays on the same .osu file, returning a result for
+    /// each of `params`, in order. Backends that can reuse work between plays
+    /// (like a parsed beatmap, or a running process) should override this; by
+    /// default, each play is simulated on its own.
+    fn simulate_plays(
+        &self,
+        beatmap_path: &str,
+        params: &[SimulationParams],
+    ) -> Vec<Result<SimulationResults, CalculationError>> {
+        params
+            .iter()
+            .map(|params| self.simulate_play(beatmap_path, params))
+            .collect()
+    }
+}
@@ -0,0 +1,449 @@
+//! Simulating many plays on the same beatmap at once, like the rows of an
+//! accuracy table.
+//!
+//! A batch is either a list of `SimulationParams`, or a `Sweep`, which expands
+//! into every combination of some mods, accuracies and misses. Either way, the
+//! beatmap is downloaded and parsed only once, and the plays are handed to the
+//! backend together (see `PerformanceBackend::simulate_plays`), so it can reuse
+//! its work between them.
+//!
+//! Batches have a deadline, checked every `BATCH_CHUNK_SIZE` plays: the plays
+//! left when it's over fail with `CalculationError::Timeout`.
+use super::simulate::{read_beatmap, simulate_parsed_plays, SimulationParams, SimulationResults};
+use super::{Accuracy, CalculationError, Mods, PerformanceBackend, Ruleset};
+use crate::beatmap_cache::BeatmapCache;
+use std::time::{Duration, Instant};
+
+/// The most plays a single batch can have.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// How many plays of a batch are handed to the backend at once.
+const BATCH_CHUNK_SIZE: usize = 10;
+
+/// Shorthand for a `CalculationError::InvalidParams`.
+fn invalid(field: &str, message: String) -> CalculationError {
+    CalculationError::InvalidParams {
+        field: field.to_string(),
+        message: message,
+    }
+}
+
+/// Accuracy percentages, from `from` to `to` (both included), in steps of `step`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AccuracyRange {
+    pub from: f64,
+    pub to: f64,
+    pub step: f64,
+}
+
+/// The values from `from` to `to` (both included), in steps of `step`.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` (for `field`) if the range is
+/// empty, its step isn't positive, or it has more than `MAX_BATCH_SIZE` values.
+pub(super) fn range_values(
+    field: &str,
+    from: f64,
+    to: f64,
+    step: f64,
+) -> Result<Vec<f64>, CalculationError> {
+    if !(step > 0.0) {
+        return Err(invalid(field, "step should be positive".to_string()));
+    }
+    if !(from <= to) {
+        return Err(invalid(field, "from should be at most to".to_string()));
+    }
+
+    // Allow for rounding errors, so 95 to 100 in steps of 0.1 includes 100.
+    let steps = ((to - from) / step + 1e-9).floor();
+    if steps >= MAX_BATCH_SIZE as f64 {
+        return Err(invalid(
+            field,
+            format!("should have at most {} values", MAX_BATCH_SIZE),
+        ));
+    }
+
+    Ok((0..=steps as usize)
+        .map(|i| (from + step * i as f64).min(to))
+        .collect())
+}
+
+impl AccuracyRange {
+    /// The percentages in this range, in order. See `range_values`.
+    fn values(&self) -> Result<Vec<f64>, CalculationError> {
+        range_values("sweep.accuracy", self.from, self.to, self.step)
+    }
+}
+
+/// A number of misses, from `from` to `to` (both included), in steps of `step`
+/// (1, if not specified).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MissRange {
+    pub from: usize,
+    pub to: usize,
+    #[serde(default = "MissRange::default_step")]
+    pub step: usize,
+}
+
+impl MissRange {
+    fn default_step() -> usize {
+        1
+    }
+
+    /// The miss counts in this range, in order.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` (for `sweep.misses`) if
+    /// the range is empty, its step is 0, or it has more than `MAX_BATCH_SIZE`
+    /// values.
+    fn values(&self) -> Result<Vec<usize>, CalculationError> {
+        let field = "sweep.misses";
+        if self.step == 0 {
+            return Err(invalid(field, "step should be positive".to_string()));
+        }
+        if self.from > self.to {
+            return Err(invalid(field, "from should be at most to".to_string()));
+        }
+        if (self.to - self.from) / self.step >= MAX_BATCH_SIZE {
+            return Err(invalid(
+                field,
+                format!("should have at most {} values", MAX_BATCH_SIZE),
+            ));
+        }
+
+        Ok((self.from..=self.to).step_by(self.step).collect())
+    }
+}
+
+/// Every combination of some mods, accuracies and misses, on `ruleset`.
+/// Accuracy is 100% if not swept, and there are no misses if they aren't
+/// swept; `combo` applies to every play.
+///
+/// osu!mania plays are described by their score, so they can't be swept; they
+/// should be listed instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sweep {
+    #[serde(default)]
+    pub ruleset: Ruleset,
+    /// The mod combinations to sweep over. No mods, if empty.
+    #[serde(default)]
+    pub mods: Vec<Mods>,
+    pub accuracy: Option<AccuracyRange>,
+    pub misses: Option<MissRange>,
+    pub combo: Option<usize>,
+}
+
+impl Sweep {
+    /// Expands this sweep into the params of each play, ordered by mods, then
+    /// accuracy, and then misses.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` if a range is invalid, if
+    /// the sweep has more than `MAX_BATCH_SIZE` plays, or if it's on osu!mania.
+    fn expand(&self) -> Result<Vec<SimulationParams>, CalculationError> {
+        if self.ruleset == Ruleset::Mania {
+            return Err(invalid(
+                "sweep.ruleset",
+                "osu!mania plays can't be swept, list their params instead".to_string(),
+            ));
+        }
+
+        let mods = if self.mods.is_empty() {
+            vec![Mods::default()]
+        } else {
+            self.mods.clone()
+        };
+        let accuracies = match self.accuracy {
+            Some(range) => range.values()?,
+            None => vec![100.0],
+        };
+        let misses = match self.misses {
+            Some(range) => range.values()?.into_iter().map(Some).collect(),
+            None => vec![None],
+        };
+
+        let size = mods.len() * accuracies.len() * misses.len();
+        if size > MAX_BATCH_SIZE {
+            return Err(invalid(
+                "sweep",
+                format!(
+                    "has {} plays, but should have at most {}",
+                    size, MAX_BATCH_SIZE
+                ),
+            ));
+        }
+
+        let mut params = Vec::with_capacity(size);
+        for mods in &mods {
+            for accuracy in &accuracies {
+                for misses in &misses {
+                    params.push(SimulationParams {
+                        ruleset: self.ruleset,
+                        accuracy: Accuracy::Percentage(*accuracy),
+                        mods: mods.clone(),
+                        combo: self.combo,
+                        misses: *misses,
+                        score: None,
+                    });
+                }
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+/// The plays of a batch: either listed one by one (`{"params": [...]}`), or
+/// swept (`{"sweep": {...}}`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Batch {
+    Params(Vec<SimulationParams>),
+    Sweep(Sweep),
+}
+
+impl Batch {
+    /// The params of each play of this batch, validated.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` if the batch is empty or
+    /// has more than `MAX_BATCH_SIZE` plays, or if any of them is invalid (the
+    /// field is then prefixed by the play, like `params[2].accuracy`).
+    pub fn plays(&self) -> Result<Vec<SimulationParams>, CalculationError> {
+        let params = match self {
+            Batch::Params(params) => params.clone(),
+            Batch::Sweep(sweep) => sweep.expand()?,
+        };
+
+        if params.is_empty() {
+            return Err(invalid("params", "should have some plays".to_string()));
+        }
+        if params.len() > MAX_BATCH_SIZE {
+            return Err(invalid(
+                "params",
+                format!("should have at most {} plays", MAX_BATCH_SIZE),
+            ));
+        }
+
+        for (i, params) in params.iter().enumerate() {
+            params.validate().map_err(|error| match error {
+                CalculationError::InvalidParams { field, message } => {
+                    invalid(&format!("params[{}].{}", i, field), message)
+                }
+                error => error,
+            })?;
+        }
+
+        Ok(params)
+    }
+}
+
+/// Why a play of a batch couldn't be simulated.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedPlay {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// A play of a batch, and its results (or why it failed).
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchPlay {
+    /// The params, as given (or expanded from the sweep).
+    pub params: SimulationParams,
+    pub results: Option<SimulationResults>,
+    pub error: Option<FailedPlay>,
+}
+
+/// The plays of a batch, in the same order they were given.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchResults {
+    pub beatmap_id: i64,
+    pub plays: Vec<BatchPlay>,
+}
+
+/// Simulates every play of `batch` on `beatmap_id` (whose .osu file is taken
+/// from `beatmaps`), using `backend`. Plays that fail (for example, because
+/// their combo doesn't fit in the beatmap, or because the batch took longer
+/// than `timeout`) are reported alongside the others, instead of failing the
+/// whole batch.
+///
+/// # Errors
+///
+/// Will error if the batch is invalid (see `Batch::plays`), or if the beatmap
+/// isn't cached and couldn't be downloaded, or couldn't be read.
+pub fn simulate_batch(
+    backend: &dyn PerformanceBackend,
+    beatmaps: &BeatmapCache,
+    beatmap_id: i64,
+    batch: &Batch,
+    timeout: Duration,
+) -> Result<BatchResults, CalculationError> {
+    let deadline = Instant::now() + timeout;
+    let params = batch.plays()?;
+
+    let beatmap_path = beatmaps.get(beatmap_id)?;
+    let beatmap = read_beatmap(&beatmap_path)?;
+    let mut results = Vec::with_capacity(params.len());
+    for chunk in params.chunks(BATCH_CHUNK_SIZE) {
+        if Instant::now() >= deadline {
+            results.extend(chunk.iter().map(|_| Err(CalculationError::Timeout)));
+        } else {
+            results.extend(simulate_parsed_plays(
+                backend,
+                &beatmap_path,
+                beatmap.as_ref(),
+                chunk.to_vec(),
+            ));
+        }
+    }
+
+    let plays = params
+        .into_iter()
+        .zip(results)
+        .map(|(params, results)| match results {
+            Ok(results) => BatchPlay {
+                params: params,
+                results: Some(results),
+                error: None,
+            },
+            Err(error) => BatchPlay {
+                params: params,
+                results: None,
+                error: Some(FailedPlay {
+                    code: error.code(),
+                    message: error.to_string(),
+                }),
+            },
+        })
+        .collect();
+
+    Ok(BatchResults {
+        beatmap_id: beatmap_id,
+        plays: plays,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::beatmap_cache::CacheLimits;
+    use crate::beatmap_source::OfflineSource;
+    use crate::performance_calculator::{Mod, NativeBackend};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn field(result: Result<Vec<SimulationParams>, CalculationError>) -> String {
+        match result {
+            Err(CalculationError::InvalidParams { field, .. }) => field,
+            other => panic!("expected invalid params, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sweep() {
+        let sweep = Sweep {
+            ruleset: Ruleset::Osu,
+            mods: vec![Mods::default(), mods![Mod::HD, Mod::DT]],
+            accuracy: Some(AccuracyRange {
+                from: 95.0,
+                to: 100.0,
+                step: 0.5,
+            }),
+            misses: Some(MissRange {
+                from: 0,
+                to: 5,
+                step: 1,
+            }),
+            combo: None,
+        };
+
+        let plays = Batch::Sweep(sweep.clone()).plays().unwrap();
+        assert_eq!(plays.len(), 2 * 11 * 6);
+        assert_eq!(plays[0].accuracy, Accuracy::Percentage(95.0));
+        assert_eq!(plays[5].misses, Some(5));
+        assert_eq!(plays[65].accuracy, Accuracy::Percentage(100.0));
+        assert_eq!(plays[66].mods, mods![Mod::HD, Mod::DT]);
+
+        let mut too_big = sweep.clone();
+        too_big.accuracy = Some(AccuracyRange {
+            from: 0.0,
+            to: 100.0,
+            step: 0.1,
+        });
+        assert_eq!(field(Batch::Sweep(too_big).plays()), "sweep.accuracy");
+
+        let mut huge = sweep.clone();
+        huge.misses = Some(MissRange {
+            from: 0,
+            to: 1_000_000_000_000_000_000,
+            step: 1,
+        });
+        assert_eq!(field(Batch::Sweep(huge).plays()), "sweep.misses");
+
+        let mut backwards = sweep.clone();
+        backwards.misses = Some(MissRange {
+            from: 5,
+            to: 0,
+            step: 1,
+        });
+        assert_eq!(field(Batch::Sweep(backwards).plays()), "sweep.misses");
+
+        let mut invalid_mods = sweep.clone();
+        invalid_mods.mods = vec![mods![Mod::HR, Mod::EZ]];
+        assert_eq!(field(Batch::Sweep(invalid_mods).plays()), "params[0].mods");
+
+        assert_eq!(field(Batch::Params(Vec::new()).plays()), "params");
+    }
+
+    #[test]
+    fn test_simulate_batch() {
+        let dir = env::temp_dir().join(format!("batch_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.osu"), TEST_BEATMAP).unwrap();
+
+        let beatmaps = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        let batch: Batch = serde_json::from_str(
+            r#"{"sweep": {"accuracy": {"from": 98, "to": 100, "step": 1}, "misses": {"from": 0, "to": 20, "step": 10}}}"#,
+        )
+        .unwrap();
+
+        let timeout = Duration::from_secs(60);
+        let results = simulate_batch(&NativeBackend::new(), &beatmaps, 1, &batch, timeout).unwrap();
+        assert_eq!(results.plays.len(), 9);
+
+        // The test beatmap has 5 objects, so 10 and 20 misses don't fit in it.
+        let ok: Vec<_> = results
+            .plays
+            .iter()
+            .filter_map(|play| play.results.as_ref())
+            .collect();
+        assert_eq!(ok.len(), 3);
+        assert!(ok.iter().all(|results| results.beatmap.is_some()));
+        assert!(ok[0].pp < ok[2].pp);
+        assert_eq!(
+            results.plays[1].error.as_ref().unwrap().code,
+            "invalid_params"
+        );
+
+        let timeout = Duration::from_secs(0);
+        let results = simulate_batch(&NativeBackend::new(), &beatmaps, 1, &batch, timeout).unwrap();
+        assert!(results
+            .plays
+            .iter()
+            .all(|play| play.error.as_ref().unwrap().code == "timeout"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,149 @@
+//! Typed beatmap metadata and difficulty settings, read from the cached .osu
+//! file, to describe the beatmap of a simulated play.
+//!
+//! Settings are reported both as they are on the beatmap, and as they end up
+//! after applying the play's mods: HR and EZ change CS/AR/OD/HP, while DT/NC
+//! and HT change the BPM and length, and the perceived AR and OD.
+use super::native::difficulty::{
+    adjusted_difficulty, clock_rate, rate_adjusted_approach_rate, rate_adjusted_overall_difficulty,
+};
+use super::{Mods, Ruleset};
+use crate::beatmap::Beatmap;
+
+/// The difficulty settings, BPM and length of a beatmap.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BeatmapStats {
+    pub circle_size: f64,
+    pub approach_rate: f64,
+    pub overall_difficulty: f64,
+    pub hp_drain_rate: f64,
+    /// The most common BPM.
+    pub bpm: f64,
+    pub min_bpm: f64,
+    pub max_bpm: f64,
+    /// From the first hit object to the end of the last one, in seconds.
+    pub length: f64,
+}
+
+/// Describes a beatmap: its metadata, object counts, and its settings with
+/// and without mods.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BeatmapInfo {
+    pub beatmap_id: Option<i64>,
+    pub beatmap_set_id: Option<i64>,
+    pub artist: String,
+    pub title: String,
+    /// The difficulty name.
+    pub version: String,
+    /// The mapper.
+    pub creator: String,
+    /// The ruleset the beatmap was made for.
+    pub ruleset: Option<Ruleset>,
+    pub circle_count: usize,
+    pub slider_count: usize,
+    pub spinner_count: usize,
+    /// Only known for osu!standard beatmaps.
+    pub max_combo: Option<usize>,
+    pub stats: BeatmapStats,
+    /// `stats`, after applying the mods of the play.
+    pub adjusted_stats: BeatmapStats,
+}
+
+impl BeatmapInfo {
+    /// Describes `beatmap`, with its settings adjusted for `mods`. AR and OD
+    /// are adjusted for the clock rate with the osu!standard formulas.
+    pub fn new(beatmap: &Beatmap, mods: &Mods) -> Self {
+        let (min_bpm, max_bpm) = beatmap.bpm_range();
+        let difficulty = &beatmap.difficulty;
+        let stats = BeatmapStats {
+            circle_size: difficulty.circle_size,
+            approach_rate: difficulty.approach_rate,
+            overall_difficulty: difficulty.overall_difficulty,
+            hp_drain_rate: difficulty.hp_drain_rate,
+            bpm: beatmap.bpm(),
+            min_bpm: min_bpm,
+            max_bpm: max_bpm,
+            length: beatmap.length() / 1000.0,
+        };
+
+        let rate = clock_rate(mods);
+        let adjusted = adjusted_difficulty(difficulty, mods);
+        let mut adjusted_stats = BeatmapStats {
+            circle_size: adjusted.circle_size,
+            approach_rate: adjusted.approach_rate,
+            overall_difficulty: adjusted.overall_difficulty,
+            hp_drain_rate: adjusted.hp_drain_rate,
+            bpm: stats.bpm * rate,
+            min_bpm: stats.min_bpm * rate,
+            max_bpm: stats.max_bpm * rate,
+            length: stats.length / rate,
+        };
+        if rate != 1.0 {
+            adjusted_stats.approach_rate =
+                rate_adjusted_approach_rate(adjusted.approach_rate, rate);
+            adjusted_stats.overall_difficulty =
+                rate_adjusted_overall_difficulty(adjusted.overall_difficulty, rate);
+        }
+
+        let metadata = &beatmap.metadata;
+        let ruleset = Ruleset::from_id(beatmap.mode);
+
+        BeatmapInfo {
+            beatmap_id: metadata.beatmap_id,
+            beatmap_set_id: metadata.beatmap_set_id,
+            artist: metadata.artist.clone(),
+            title: metadata.title.clone(),
+            version: metadata.version.clone(),
+            creator: metadata.creator.clone(),
+            ruleset: ruleset,
+            circle_count: beatmap.circle_count(),
+            slider_count: beatmap.slider_count(),
+            spinner_count: beatmap.spinner_count(),
+            max_combo: if ruleset == Some(Ruleset::Osu) {
+                Some(beatmap.max_combo())
+            } else {
+                None
+            },
+            stats: stats,
+            adjusted_stats: adjusted_stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::performance_calculator::Mod;
+
+    #[test]
+    fn test_beatmap_info() {
+        let beatmap = Beatmap::parse(TEST_BEATMAP).unwrap();
+
+        let info = BeatmapInfo::new(&beatmap, &Mods::default());
+        assert_eq!(info.title, "Test Song");
+        assert_eq!(info.creator, "Test Mapper");
+        assert_eq!(info.ruleset, Some(Ruleset::Osu));
+        assert_eq!(
+            (info.circle_count, info.slider_count, info.spinner_count),
+            (2, 2, 1)
+        );
+        assert_eq!(info.max_combo, Some(10));
+        assert_eq!(info.stats, info.adjusted_stats);
+
+        let info = BeatmapInfo::new(&beatmap, &mods![Mod::HR, Mod::DT]);
+        let adjusted = info.adjusted_stats;
+        assert!((adjusted.circle_size - 5.2).abs() < 1e-9);
+        assert!((adjusted.hp_drain_rate - 7.0).abs() < 1e-9);
+        assert_eq!(adjusted.bpm, 180.0);
+        assert!((adjusted.length - 5.0 / 1.5).abs() < 1e-9);
+        assert!(adjusted.approach_rate > 10.0);
+        assert!(adjusted.overall_difficulty > 10.0);
+
+        let info = BeatmapInfo::new(&beatmap, &mods![Mod::EZ, Mod::HT]);
+        let adjusted = info.adjusted_stats;
+        assert_eq!(adjusted.circle_size, 2.0);
+        assert_eq!(adjusted.bpm, 90.0);
+        assert!(adjusted.approach_rate < 4.5);
+    }
+}
@@ -0,0 +1,248 @@
+//! Simulating the same play on every difficulty of a beatmap set, so they can
+//! be compared.
+//!
+//! The difficulties of a set are looked up with the osu! api, and each one is
+//! simulated with `simulate_play`, like any single beatmap.
+use super::simulate::{simulate_play, SimulationParams, SimulationResults};
+use super::{CalculationError, PerformanceBackend, Ruleset};
+use crate::beatmap_cache::BeatmapCache;
+use crate::config_functions::try_api_key;
+use std::cmp::Ordering;
+
+/// A beatmap, as returned by the osu! api `get_beatmaps` endpoint. Only the
+/// fields we need are here; the api returns every one of them as a string.
+#[derive(Deserialize)]
+struct ApiBeatmap {
+    beatmap_id: String,
+    mode: String,
+    version: String,
+}
+
+/// A difficulty of a beatmap set.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SetDifficulty {
+    pub beatmap_id: i64,
+    /// The difficulty name.
+    pub version: String,
+    /// The ruleset the difficulty was made for.
+    pub ruleset: Ruleset,
+}
+
+/// Converts the `get_beatmaps` response for the set `set_id` into its
+/// difficulties. Difficulties of unknown rulesets are skipped.
+fn parse_api_beatmaps(
+    set_id: i64,
+    beatmaps: Vec<ApiBeatmap>,
+) -> Result<Vec<SetDifficulty>, CalculationError> {
+    if beatmaps.is_empty() {
+        return Err(CalculationError::BeatmapsetNotFound(set_id));
+    }
+
+    let mut difficulties = Vec::new();
+    for beatmap in beatmaps {
+        let beatmap_id = beatmap.beatmap_id.parse().map_err(|_| {
+            CalculationError::ApiFailure(format!("invalid beatmap id {:?}", beatmap.beatmap_id))
+        })?;
+        let ruleset = beatmap.mode.parse().ok().and_then(Ruleset::from_id);
+
+        if let Some(ruleset) = ruleset {
+            difficulties.push(SetDifficulty {
+                beatmap_id: beatmap_id,
+                version: beatmap.version,
+                ruleset: ruleset,
+            });
+        }
+    }
+
+    Ok(difficulties)
+}
+
+/// Asks the osu! api for the difficulties of the beatmap set `set_id`.
+///
+/// # Errors
+///
+/// Will return `CalculationError::Unsupported` if there's no api key;
+/// `CalculationError::BeatmapsetNotFound` if the api doesn't know about the set;
+/// or `CalculationError::ApiFailure` if the request failed.
+pub fn fetch_beatmapset(set_id: i64) -> Result<Vec<SetDifficulty>, CalculationError> {
+    let api_key = try_api_key().ok_or_else(|| {
+        CalculationError::Unsupported("Looking up beatmap sets without an api key".to_string())
+    })?;
+
+    let mut resp = reqwest::get(&format!(
+        "https://osu.ppy.sh/api/get_beatmaps?k={}&s={}",
+        api_key, set_id
+    ))?;
+    if !resp.status().is_success() {
+        return Err(CalculationError::ApiFailure(format!(
+            "get_beatmaps returned {}",
+            resp.status()
+        )));
+    }
+
+    parse_api_beatmaps(set_id, resp.json()?)
+}
+
+/// The simulated play on a single difficulty.
+#[derive(Debug, Clone, Serialize)]
+pub struct DifficultyResults {
+    pub beatmap_id: i64,
+    pub version: String,
+    pub results: SimulationResults,
+}
+
+/// A difficulty the play couldn't be simulated on, and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedDifficulty {
+    pub beatmap_id: i64,
+    pub version: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// The same play, simulated on every difficulty of a set.
+#[derive(Debug, Clone, Serialize)]
+pub struct BeatmapsetResults {
+    pub beatmapset_id: i64,
+    /// Sorted by PP, highest first.
+    pub difficulties: Vec<DifficultyResults>,
+    pub failed: Vec<FailedDifficulty>,
+}
+
+/// Simulates the play described by `params` on each of the `difficulties` of
+/// the set `set_id` made for `params.ruleset`, using `backend`. Difficulties
+/// that fail (for example, because the combo doesn't fit in them) are reported
+/// separately, instead of failing the whole set.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` if `params` are invalid for
+/// any beatmap (see `SimulationParams::validate`).
+pub fn simulate_beatmapset(
+    backend: &dyn PerformanceBackend,
+    beatmaps: &BeatmapCache,
+    set_id: i64,
+    difficulties: &[SetDifficulty],
+    params: SimulationParams,
+) -> Result<BeatmapsetResults, CalculationError> {
+    params.validate()?;
+
+    let mut results = BeatmapsetResults {
+        beatmapset_id: set_id,
+        difficulties: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    for difficulty in difficulties {
+        if difficulty.ruleset != params.ruleset {
+            continue;
+        }
+
+        match simulate_play(backend, beatmaps, difficulty.beatmap_id, params.clone()) {
+            Ok(simulation) => results.difficulties.push(DifficultyResults {
+                beatmap_id: difficulty.beatmap_id,
+                version: difficulty.version.clone(),
+                results: simulation,
+            }),
+            Err(error) => results.failed.push(FailedDifficulty {
+                beatmap_id: difficulty.beatmap_id,
+                version: difficulty.version.clone(),
+                code: error.code(),
+                message: error.to_string(),
+            }),
+        }
+    }
+
+    results.difficulties.sort_by(|a, b| {
+        b.results
+            .pp
+            .partial_cmp(&a.results.pp)
+            .unwrap_or(Ordering::Equal)
+    });
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::beatmap_cache::CacheLimits;
+    use crate::beatmap_source::OfflineSource;
+    use crate::performance_calculator::{Accuracy, NativeBackend};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_parse_api_beatmaps() {
+        let raw = r#"[
+            {"beatmap_id": "129891", "mode": "0", "version": "FOUR DIMENSIONS"},
+            {"beatmap_id": "129892", "mode": "1", "version": "Oni"},
+            {"beatmap_id": "129893", "mode": "9", "version": "Unknown"}
+        ]"#;
+        let difficulties = parse_api_beatmaps(39804, serde_json::from_str(raw).unwrap()).unwrap();
+
+        assert_eq!(difficulties.len(), 2);
+        assert_eq!(difficulties[0].beatmap_id, 129891);
+        assert_eq!(difficulties[1].ruleset, Ruleset::Taiko);
+        assert_eq!(
+            parse_api_beatmaps(1, Vec::new()),
+            Err(CalculationError::BeatmapsetNotFound(1))
+        );
+    }
+
+    #[test]
+    fn test_simulate_beatmapset() {
+        let dir = env::temp_dir().join(format!("beatmapset_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // A harder difficulty, with the same objects and a higher AR and OD.
+        fs::write(dir.join("1.osu"), TEST_BEATMAP).unwrap();
+        let harder = TEST_BEATMAP
+            .replace("OverallDifficulty:8", "OverallDifficulty:10")
+            .replace("ApproachRate:9", "ApproachRate:10");
+        fs::write(dir.join("2.osu"), harder).unwrap();
+
+        let beatmaps = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        let difficulty = |beatmap_id, version: &str, ruleset| SetDifficulty {
+            beatmap_id: beatmap_id,
+            version: version.to_string(),
+            ruleset: ruleset,
+        };
+        let difficulties = vec![
+            difficulty(1, "Insane", Ruleset::Osu),
+            difficulty(2, "Extra", Ruleset::Osu),
+            difficulty(3, "Missing", Ruleset::Osu),
+            difficulty(4, "Oni", Ruleset::Taiko),
+        ];
+        let params = SimulationParams {
+            ruleset: Ruleset::Osu,
+            accuracy: Accuracy::Percentage(100.0),
+            mods: Default::default(),
+            combo: None,
+            misses: None,
+            score: None,
+        };
+
+        let results =
+            simulate_beatmapset(&NativeBackend::new(), &beatmaps, 10, &difficulties, params)
+                .unwrap();
+        let versions: Vec<&str> = results
+            .difficulties
+            .iter()
+            .map(|d| d.version.as_str())
+            .collect();
+        assert_eq!(versions, vec!["Extra", "Insane"]);
+        assert_eq!(results.failed.len(), 1);
+        assert_eq!(results.failed[0].code, "beatmap_not_found");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
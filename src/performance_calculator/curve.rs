@@ -0,0 +1,310 @@
+//! PP curves: how the PP of a play on a beatmap changes over its accuracy,
+//! misses or combo, with everything else fixed.
+//!
+//! Curves are simulated as a batch (see `simulate_batch`), and can be exported
+//! as JSON or CSV, to judge how harsh a rebalance is on each of them.
+use super::batch::{range_values, simulate_batch, Batch};
+use super::simulate::SimulationParams;
+use super::{Accuracy, CalculationError, PerformanceBackend, Ruleset};
+use crate::beatmap::Beatmap;
+use crate::beatmap_cache::BeatmapCache;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// What a curve is drawn over.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CurveAxis {
+    /// Accuracy percentages.
+    Accuracy,
+    /// Number of misses.
+    Misses,
+    /// Maximum combo.
+    Combo,
+}
+
+impl CurveAxis {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CurveAxis::Accuracy => "accuracy",
+            CurveAxis::Misses => "misses",
+            CurveAxis::Combo => "combo",
+        }
+    }
+}
+
+/// The range of a curve, from `from` to `to` (both included), in steps of
+/// `step`. Missing values default to 90% to 100% in steps of 0.5 for accuracy;
+/// 0 to 10 misses; and 20 steps up to the beatmap max combo for combo.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurveSpec {
+    pub axis: CurveAxis,
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub step: Option<f64>,
+}
+
+/// Shorthand for an invalid `curve` field.
+fn invalid(message: String) -> CalculationError {
+    CalculationError::InvalidParams {
+        field: "curve".to_string(),
+        message: message,
+    }
+}
+
+impl CurveSpec {
+    /// The values of the curve, filling in the defaults. Only combo curves need
+    /// to know the `max_combo` of the beatmap, and only if `to` isn't given.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` if the range is invalid,
+    /// or if misses or combo aren't whole numbers.
+    fn values(&self, max_combo: Option<usize>) -> Result<Vec<f64>, CalculationError> {
+        let (from, to, step) = match self.axis {
+            CurveAxis::Accuracy => (
+                self.from.unwrap_or(90.0),
+                self.to.unwrap_or(100.0),
+                self.step.unwrap_or(0.5),
+            ),
+            CurveAxis::Misses => (
+                self.from.unwrap_or(0.0),
+                self.to.unwrap_or(10.0),
+                self.step.unwrap_or(1.0),
+            ),
+            CurveAxis::Combo => {
+                let to = match (self.to, max_combo) {
+                    (Some(to), _) => to,
+                    (None, Some(max_combo)) => max_combo as f64,
+                    (None, None) => {
+                        return Err(invalid(
+                            "to is needed for combo curves on this beatmap".to_string(),
+                        ))
+                    }
+                };
+                let step = self.step.unwrap_or_else(|| (to / 20.0).ceil().max(1.0));
+
+                (self.from.unwrap_or(step), to, step)
+            }
+        };
+
+        let values = range_values("curve", from, to, step)?;
+        let whole = values.iter().all(|v| v.fract() == 0.0 && *v >= 0.0);
+        if self.axis != CurveAxis::Accuracy && !whole {
+            return Err(invalid(format!(
+                "{} should be whole numbers",
+                self.axis.name()
+            )));
+        }
+
+        Ok(values)
+    }
+}
+
+/// A point of a curve: the PP of the play with `value`, and its actual
+/// accuracy (which may differ from the requested one, once converted into
+/// hits).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CurvePoint {
+    pub value: f64,
+    pub pp: f64,
+    pub accuracy: f64,
+}
+
+/// A PP curve over `axis`, for plays on `beatmap_id` otherwise like `params`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PpCurve {
+    pub beatmap_id: i64,
+    pub axis: CurveAxis,
+    pub params: SimulationParams,
+    pub points: Vec<CurvePoint>,
+    /// The values whose plays couldn't be simulated (like combos that don't fit
+    /// with the misses of `params`).
+    pub failed: Vec<f64>,
+}
+
+impl PpCurve {
+    /// The points of this curve as CSV, with a header row.
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{},pp,accuracy\n", self.axis.name());
+        for point in &self.points {
+            writeln!(csv, "{},{:.3},{:.3}", point.value, point.pp, point.accuracy).unwrap();
+        }
+
+        csv
+    }
+}
+
+/// Simulates the play described by `params` on `beatmap_id` (whose .osu file
+/// is taken from `beatmaps`) over each value of `spec`, using `backend`. The
+/// value replaces the accuracy, misses or combo of `params`.
+///
+/// # Errors
+///
+/// Will error if `spec` or `params` are invalid (`CalculationError::InvalidParams`),
+/// or if the beatmap isn't cached and couldn't be downloaded. Points that
+/// aren't simulated within `timeout` are reported as failed.
+pub fn simulate_curve(
+    backend: &dyn PerformanceBackend,
+    beatmaps: &BeatmapCache,
+    beatmap_id: i64,
+    params: SimulationParams,
+    spec: &CurveSpec,
+    timeout: Duration,
+) -> Result<PpCurve, CalculationError> {
+    params.validate()?;
+    if params.ruleset == Ruleset::Mania {
+        return Err(invalid(
+            "osu!mania plays are described by their score, and have no curves".to_string(),
+        ));
+    }
+
+    let max_combo = if spec.axis == CurveAxis::Combo && spec.to.is_none() {
+        let beatmap_path = beatmaps.get(beatmap_id)?;
+        Beatmap::from_file(beatmap_path)
+            .ok()
+            .filter(|beatmap| beatmap.mode == Ruleset::Osu.id())
+            .map(|beatmap| beatmap.max_combo())
+    } else {
+        None
+    };
+    let values = spec.values(max_combo)?;
+
+    let plays = values
+        .iter()
+        .map(|value| {
+            let mut play = params.clone();
+            match spec.axis {
+                CurveAxis::Accuracy => play.accuracy = Accuracy::Percentage(*value),
+                CurveAxis::Misses => play.misses = Some(*value as usize),
+                CurveAxis::Combo => play.combo = Some(*value as usize),
+            }
+
+            play
+        })
+        .collect();
+    let results = simulate_batch(
+        backend,
+        beatmaps,
+        beatmap_id,
+        &Batch::Params(plays),
+        timeout,
+    )?;
+
+    let mut curve = PpCurve {
+        beatmap_id: beatmap_id,
+        axis: spec.axis,
+        params: params,
+        points: Vec::new(),
+        failed: Vec::new(),
+    };
+    for (value, play) in values.into_iter().zip(results.plays) {
+        match play.results {
+            Some(results) => curve.points.push(CurvePoint {
+                value: value,
+                pp: results.pp,
+                accuracy: results.play_info.accuracy,
+            }),
+            None => curve.failed.push(value),
+        }
+    }
+
+    Ok(curve)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::beatmap_cache::CacheLimits;
+    use crate::beatmap_source::OfflineSource;
+    use crate::performance_calculator::NativeBackend;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_curve_values() {
+        let spec = |axis, from, to, step| CurveSpec {
+            axis: axis,
+            from: from,
+            to: to,
+            step: step,
+        };
+
+        assert_eq!(
+            spec(CurveAxis::Accuracy, None, None, None)
+                .values(None)
+                .unwrap()
+                .len(),
+            21
+        );
+        assert_eq!(
+            spec(CurveAxis::Misses, Some(2.0), Some(4.0), None).values(None),
+            Ok(vec![2.0, 3.0, 4.0])
+        );
+        assert_eq!(
+            spec(CurveAxis::Combo, None, None, None).values(Some(100)),
+            Ok((1..=20).map(|i| (i * 5) as f64).collect())
+        );
+        assert!(spec(CurveAxis::Combo, None, None, None)
+            .values(None)
+            .is_err());
+        assert!(spec(CurveAxis::Misses, None, None, Some(0.5))
+            .values(None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_simulate_curve() {
+        let dir = env::temp_dir().join(format!("curve_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.osu"), TEST_BEATMAP).unwrap();
+
+        let beatmaps = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        let params = SimulationParams {
+            ruleset: Ruleset::Osu,
+            accuracy: Accuracy::Hits { good: 0, meh: 0 },
+            mods: Default::default(),
+            combo: None,
+            misses: None,
+            score: None,
+        };
+        let spec = CurveSpec {
+            axis: CurveAxis::Misses,
+            from: None,
+            to: Some(8.0),
+            step: None,
+        };
+
+        let timeout = Duration::from_secs(60);
+        let curve = simulate_curve(
+            &NativeBackend::new(),
+            &beatmaps,
+            1,
+            params.clone(),
+            &spec,
+            timeout,
+        )
+        .unwrap();
+        // The test beatmap has 5 objects, so there can't be more misses.
+        assert_eq!(curve.points.len(), 6);
+        assert_eq!(curve.failed, vec![6.0, 7.0, 8.0]);
+        assert!(curve
+            .points
+            .windows(2)
+            .all(|points| points[0].pp >= points[1].pp));
+
+        let csv = curve.to_csv();
+        assert!(csv.starts_with("misses,pp,accuracy\n0,"));
+        assert_eq!(csv.lines().count(), 7);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,350 @@
+//! A `PerformanceBackend` that calls into osu-tools' PerformanceCalculator.dll.
+//!
+//! Every request spawns a new `dotnet PerformanceCalculator.dll` process, and
+//! parses its `--json` output. Processes that take longer than the configured
+//! `Timeouts` are killed.
+use super::process::output_with_timeout;
+use super::{
+    Accuracy, CalculationError, PerformanceBackend, ProfileResults, Ruleset, SimulationParams,
+    SimulationResults, Timeouts,
+};
+use crate::config_functions::api_key;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::UNIX_EPOCH;
+
+/// A backend that runs PerformanceCalculator.dll through the dotnet runtime.
+pub struct DotnetBackend {
+    dotnet_command: String,
+    calculator_path: String,
+    timeouts: Timeouts,
+}
+
+impl DotnetBackend {
+    /// Creates a new `DotnetBackend`, that will run the PerformanceCalculator.dll
+    /// located at `calculator_path` using the `dotnet_command` executable, and
+    /// kill it once it exceeds `timeouts`.
+    pub fn new(dotnet_command: String, calculator_path: String, timeouts: Timeouts) -> Self {
+        DotnetBackend {
+            dotnet_command: dotnet_command,
+            calculator_path: calculator_path,
+            timeouts: timeouts,
+        }
+    }
+
+    /// Creates a `Command` that calls PerformanceCalculator, without any
+    /// arguments besides the .dll path.
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.dotnet_command);
+        cmd.arg(&self.calculator_path);
+        cmd
+    }
+}
+
+/// Parses the output from PerformanceCalculator (`raw_results`) into a ProfileResults struct.
+///
+/// # Errors
+///
+/// Will error if `raw_results` can't be parsed into a valid `ProfileResults`.
+fn parse_profile_results(raw_results: String) -> Result<ProfileResults, CalculationError> {
+    Ok(serde_json::from_str(raw_results.as_str())?)
+}
+
+/// Parses the output of PerformanceCalculator's `simulate` command (contained into
+/// `raw_results`) into a SimulationResults.
+///
+/// # Errors
+///
+/// Will error if the contents of `raw_results` can't be parsed into a valid
+/// `SimulationResults`.
+fn parse_simulation_results(raw_results: String) -> Result<SimulationResults, CalculationError> {
+    Ok(serde_json::from_str(raw_results.as_str())?)
+}
+
+/// Makes sense of a failed PerformanceCalculator run with `args`, given what it
+/// wrote to `stderr`. PerformanceCalculator doesn't have meaningful exit codes,
+/// so this relies on the .NET exceptions it reports.
+pub(super) fn classify_failure(
+    args: &[String],
+    exit_code: Option<i32>,
+    stderr: String,
+) -> CalculationError {
+    if stderr.contains("WebException") || stderr.contains("HttpRequestException") {
+        let message = stderr.lines().next().unwrap_or("").to_string();
+
+        return CalculationError::ApiFailure(message);
+    }
+
+    match args.split_first() {
+        // The profile command indexes into the osu! api response, which is
+        // empty for unknown users.
+        Some((command, rest))
+            if command == "profile" && stderr.contains("ArgumentOutOfRangeException") =>
+        {
+            CalculationError::UserNotFound(rest.first().cloned().unwrap_or_default())
+        }
+        _ => CalculationError::ProcessFailed {
+            exit_code: exit_code,
+            stderr: stderr,
+        },
+    }
+}
+
+/// Turns the `output` of a failed PerformanceCalculator run with `args` into a
+/// `CalculationError`.
+fn failure_from_output(args: &[String], output: &Output) -> CalculationError {
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    classify_failure(args, output.status.code(), stderr)
+}
+
+/// Identifies the PerformanceCalculator.dll at `calculator_path` by the name,
+/// size and modification time of every DLL next to it, since the difficulty
+/// and PP formulas live in the rulesets' DLLs, which can be rebuilt on their
+/// own. Returns `None` if the files can't be read.
+pub(super) fn build_version(calculator_path: &str) -> Option<String> {
+    let dir = Path::new(calculator_path).parent()?;
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    let mut dlls = Vec::new();
+    for entry in fs::read_dir(dir).ok()? {
+        let path = entry.ok()?.path();
+        if path.extension().map_or(false, |ext| ext == "dll") {
+            dlls.push(path);
+        }
+    }
+    if dlls.is_empty() {
+        return None;
+    }
+    dlls.sort();
+
+    let mut description = String::new();
+    for path in dlls {
+        let metadata = fs::metadata(&path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        description.push_str(&format!(
+            "{}:{}-{}.{:09}\n",
+            path.file_name()?.to_string_lossy(),
+            metadata.len(),
+            modified.as_secs(),
+            modified.subsec_nanos()
+        ));
+    }
+
+    Some(format!("{:x}", md5::compute(description)))
+}
+
+/// The PerformanceCalculator arguments for a `profile` command.
+pub(super) fn profile_args(user: &str, ruleset: Ruleset) -> Vec<String> {
+    vec![
+        "profile".to_string(),
+        user.to_string(),
+        api_key(),
+        "-r".to_string(),
+        ruleset.id().to_string(),
+        "--json".to_string(),
+    ]
+}
+
+/// The PerformanceCalculator arguments for a `simulate` command. Each ruleset
+/// has its own subcommand, with slightly different options.
+pub(super) fn simulate_args(beatmap_path: &str, params: &SimulationParams) -> Vec<String> {
+    let mut args = vec![
+        "simulate".to_string(),
+        params.ruleset.name().to_string(),
+        beatmap_path.to_string(),
+    ];
+
+    if params.ruleset == Ruleset::Mania {
+        // osu!mania plays are only described by their score.
+        if let Some(score) = params.score {
+            args.push("-s".to_string());
+            args.push(score.to_string());
+        }
+    } else {
+        match (params.ruleset, params.accuracy) {
+            (_, Accuracy::Percentage(pct)) => {
+                args.push("-a".to_string());
+                args.push(format!("{:.*}", 2, pct));
+            }
+            (Ruleset::Taiko, Accuracy::Hits { good, .. }) => {
+                args.push("-G".to_string());
+                args.push(good.to_string());
+            }
+            (Ruleset::Catch, Accuracy::Hits { good, meh }) => {
+                args.push("-D".to_string());
+                args.push(good.to_string());
+                args.push("-T".to_string());
+                args.push(meh.to_string());
+            }
+            (_, Accuracy::Hits { good, meh }) => {
+                args.push("-G".to_string());
+                args.push(good.to_string());
+                args.push("-M".to_string());
+                args.push(meh.to_string());
+            }
+        };
+
+        if let Some(combo) = params.combo {
+            args.push("-c".to_string());
+            args.push(combo.to_string());
+        }
+
+        if let Some(misses) = params.misses {
+            args.push("-X".to_string());
+            args.push(misses.to_string());
+        }
+    }
+
+    for m in &params.mods {
+        args.push("-m".to_string());
+        args.push(m.to_arg());
+    }
+
+    args.push("--json".to_string());
+
+    args
+}
+
+impl PerformanceBackend for DotnetBackend {
+    fn calculate_profile(
+        &self,
+        user: &str,
+        ruleset: Ruleset,
+    ) -> Result<ProfileResults, CalculationError> {
+        let args = profile_args(user, ruleset);
+        let output = output_with_timeout(self.command().args(&args), self.timeouts.profile)?;
+
+        if output.status.success() {
+            let raw = String::from_utf8_lossy(&output.stdout).to_string();
+
+            Ok(parse_profile_results(raw)?)
+        } else {
+            let error = failure_from_output(&args, &output);
+
+            println!("calculate_profile failed! {:?}", error);
+
+            Err(error)
+        }
+    }
+
+    fn version(&self) -> Option<String> {
+        build_version(&self.calculator_path)
+    }
+
+    fn fetches_profile_beatmaps(&self) -> bool {
+        true
+    }
+
+    fn simulate_play(
+        &self,
+        beatmap_path: &str,
+        params: &SimulationParams,
+    ) -> Result<SimulationResults, CalculationError> {
+        let args = simulate_args(beatmap_path, params);
+        let output = output_with_timeout(self.command().args(&args), self.timeouts.simulate)?;
+
+        if output.status.success() {
+            let raw = String::from_utf8_lossy(&output.stdout).to_string();
+
+            Ok(parse_simulation_results(raw)?)
+        } else {
+            let error = failure_from_output(&args, &output);
+
+            println!("simulate_play failed! {:?}", error);
+
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::performance_calculator::{Mod, Mods};
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_classify_failure() {
+        let args = vec!["profile".to_string(), "nobody".to_string()];
+        let stderr =
+            "Unhandled Exception: System.ArgumentOutOfRangeException: Index was out of range.";
+        assert_eq!(
+            classify_failure(&args, Some(134), stderr.to_string()),
+            CalculationError::UserNotFound("nobody".to_string())
+        );
+
+        let stderr = "Unhandled Exception: System.Net.WebException: The operation has timed out.";
+        assert_eq!(
+            classify_failure(&args, Some(134), stderr.to_string()).code(),
+            "api_failure"
+        );
+
+        let args = vec!["simulate".to_string(), "osu".to_string()];
+        let stderr = "Unhandled Exception: System.ArgumentOutOfRangeException";
+        assert_eq!(
+            classify_failure(&args, Some(1), stderr.to_string()),
+            CalculationError::ProcessFailed {
+                exit_code: Some(1),
+                stderr: stderr.to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_simulate_args() {
+        let mut params = SimulationParams {
+            ruleset: Ruleset::Catch,
+            accuracy: Accuracy::Hits { good: 3, meh: 20 },
+            mods: Mods::default(),
+            combo: None,
+            misses: Some(1),
+            score: None,
+        };
+
+        assert_eq!(
+            simulate_args("a.osu", &params),
+            ["simulate", "catch", "a.osu", "-D", "3", "-T", "20", "-X", "1", "--json"]
+        );
+
+        params.ruleset = Ruleset::Mania;
+        params.score = Some(950000);
+        params.mods.insert(Mod::K4);
+
+        assert_eq!(
+            simulate_args("a.osu", &params),
+            ["simulate", "mania", "a.osu", "-s", "950000", "-m", "4k", "--json"]
+        );
+    }
+
+    #[test]
+    fn test_build_version() {
+        let dir = env::temp_dir().join(format!("build_version_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let calculator_path = dir.join("PerformanceCalculator.dll");
+        let calculator_path = calculator_path.to_str().unwrap();
+
+        assert_eq!(build_version(calculator_path), None);
+
+        fs::write(calculator_path, "calculator").unwrap();
+        fs::write(dir.join("osu.Game.Rulesets.Osu.dll"), "formulas").unwrap();
+        fs::write(dir.join("notes.txt"), "not a dll").unwrap();
+        let version = build_version(calculator_path).unwrap();
+
+        fs::write(dir.join("notes.txt"), "still not a dll").unwrap();
+        assert_eq!(build_version(calculator_path), Some(version.clone()));
+
+        // Rebuilding a ruleset alone is a new version.
+        fs::write(dir.join("osu.Game.Rulesets.Osu.dll"), "new formulas").unwrap();
+        assert_ne!(build_version(calculator_path), Some(version));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
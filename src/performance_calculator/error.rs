@@ -0,0 +1,142 @@
+//! The error type returned by PP calculations.
+//!
+//! Every variant has a machine-readable `code`, which is what the web API
+//! reports alongside the human-readable message.
+use crate::beatmap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong when calculating a profile or simulating a play.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalculationError {
+    /// The osu! api doesn't know about this user.
+    UserNotFound(String),
+    /// The beatmap with this id doesn't exist, or couldn't be downloaded.
+    BeatmapNotFound(i64),
+    /// The beatmap set with this id doesn't exist.
+    BeatmapsetNotFound(i64),
+    /// No beatmap is known with this .osu file MD5 checksum.
+    UnknownBeatmapHash(String),
+    /// The osu! api (or the beatmap download) failed, or returned something
+    /// unexpected.
+    ApiFailure(String),
+    /// Some output (calculator results, .osu files) couldn't be parsed.
+    ParseError(String),
+    /// The calculation took longer than it's allowed to, and was killed.
+    Timeout,
+    /// The calculator process exited unsuccessfully.
+    ProcessFailed {
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    /// The backend doesn't support this kind of calculation.
+    Unsupported(String),
+    /// Some local I/O failed, like spawning a process or writing a file.
+    Io(String),
+    /// No calculator is registered with this name.
+    UnknownCalculator(String),
+    /// There's no ruleset with this name.
+    UnknownRuleset(String),
+    /// Some request parameter is invalid. `field` names it.
+    InvalidParams { field: String, message: String },
+}
+
+impl CalculationError {
+    /// A short, machine-readable identifier for this kind of error.
+    pub fn code(&self) -> &'static str {
+        use CalculationError::*;
+
+        match *self {
+            UserNotFound(_) => "user_not_found",
+            BeatmapNotFound(_) => "beatmap_not_found",
+            BeatmapsetNotFound(_) => "beatmapset_not_found",
+            UnknownBeatmapHash(_) => "unknown_beatmap_hash",
+            ApiFailure(_) => "api_failure",
+            ParseError(_) => "parse_error",
+            Timeout => "timeout",
+            ProcessFailed { .. } => "process_failed",
+            Unsupported(_) => "unsupported",
+            Io(_) => "io_error",
+            UnknownCalculator(_) => "unknown_calculator",
+            UnknownRuleset(_) => "unknown_ruleset",
+            InvalidParams { .. } => "invalid_params",
+        }
+    }
+}
+
+impl fmt::Display for CalculationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CalculationError::*;
+
+        match *self {
+            UserNotFound(ref user) => write!(f, "User {} wasn't found", user),
+            BeatmapNotFound(beatmap_id) => write!(f, "Beatmap {} wasn't found", beatmap_id),
+            BeatmapsetNotFound(set_id) => write!(f, "Beatmap set {} wasn't found", set_id),
+            UnknownBeatmapHash(ref md5) => write!(f, "No beatmap has the checksum {}", md5),
+            ApiFailure(ref message) => write!(f, "osu! api request failed: {}", message),
+            ParseError(ref message) => write!(f, "Couldn't parse results: {}", message),
+            Timeout => write!(f, "Calculation timed out"),
+            ProcessFailed {
+                exit_code: Some(code),
+                ..
+            } => write!(f, "Calculator exited with code {}", code),
+            ProcessFailed {
+                exit_code: None, ..
+            } => write!(f, "Calculator was terminated"),
+            Unsupported(ref what) => write!(f, "{} isn't supported by this calculator", what),
+            Io(ref message) => write!(f, "I/O error: {}", message),
+            UnknownCalculator(ref name) => write!(f, "Unknown calculator {}", name),
+            UnknownRuleset(ref name) => write!(f, "Unknown ruleset {}", name),
+            InvalidParams {
+                ref field,
+                ref message,
+            } => write!(f, "Invalid {}: {}", field, message),
+        }
+    }
+}
+
+impl Error for CalculationError {}
+
+impl From<io::Error> for CalculationError {
+    fn from(e: io::Error) -> Self {
+        CalculationError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for CalculationError {
+    fn from(e: serde_json::Error) -> Self {
+        CalculationError::ParseError(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for CalculationError {
+    fn from(e: reqwest::Error) -> Self {
+        CalculationError::ApiFailure(e.to_string())
+    }
+}
+
+impl From<beatmap::ParseError> for CalculationError {
+    fn from(e: beatmap::ParseError) -> Self {
+        CalculationError::ParseError(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_codes_and_messages() {
+        let error = CalculationError::ProcessFailed {
+            exit_code: Some(134),
+            stderr: "Unhandled Exception".to_string(),
+        };
+
+        assert_eq!(error.code(), "process_failed");
+        assert_eq!(error.to_string(), "Calculator exited with code 134");
+
+        let error: CalculationError = serde_json::from_str::<i32>("nope").unwrap_err().into();
+        assert_eq!(error.code(), "parse_error");
+    }
+}
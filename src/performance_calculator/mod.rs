@@ -1,20 +1,18 @@
-//! A interface to osu-tools' PerformanceCalculator.dll
+//! A interface to osu-tools' PerformanceCalculator.dll, and other PP calculators.
 //!
 //! This module contains a few data structures/enums common to both
 //! profile calculation and simulation requests. Specialized functions
-//! can be found into the `profile` and `simulate` modules.
+//! can be found into the `profile` and `simulate` modules, and the
+//! calculators themselves implement the `PerformanceBackend` trait.
 extern crate reqwest;
 extern crate serde;
 extern crate serde_json;
 
-use std::error::Error;
-use std::fmt;
-
 #[macro_use]
 macro_rules! mods {
     ( $( $mod:expr ),* ) => {
         {
-            let mut temp_mods : BTreeSet<Mod> = BTreeSet::new();
+            let mut temp_mods = crate::performance_calculator::Mods::default();
 
             $(
                 temp_mods.insert($mod);
@@ -24,94 +22,54 @@ macro_rules! mods {
     }
 }
 
-/// A enum, representing all possible mods in osu!standard.
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
-pub enum Mod {
-    HD,
-    HR,
-    DT,
-    NC,
-    FL,
-    NF,
-    EZ,
-    HT,
-    SO,
-    SD,
-    PF,
-    TD,
-}
+pub mod accuracy;
+pub use accuracy::{Accuracy, HitResults};
 
-impl Mod {
-    /// Obtain a string representation of the mod, suitable to pass as a mod
-    /// parameter to PerformanceCalculator.
-    fn to_arg(&self) -> &'static str {
-        use Mod::*;
-
-        match *self {
-            HD => "hd",
-            HR => "hr",
-            DT => "dt",
-            NC => "nc",
-            FL => "fl",
-            NF => "nf",
-            EZ => "ez",
-            HT => "ht",
-            SO => "so",
-            SD => "sd",
-            PF => "pf",
-            TD => "td",
-        }
-    }
+pub mod batch;
+pub use batch::{simulate_batch, Batch, BatchResults};
 
-    /// Obtain a human-readable representation for the mod.
-    fn to_string(&self) -> &'static str {
-        use Mod::*;
-
-        // uhh
-        match *self {
-            HD => "HD",
-            HR => "HR",
-            DT => "DT",
-            NC => "NC",
-            FL => "FL",
-            NF => "NF",
-            EZ => "EZ",
-            HT => "HT",
-            SO => "SO",
-            SD => "SD",
-            PF => "PF",
-            TD => "TD",
-        }
-    }
-}
+pub mod beatmapset;
+pub use beatmapset::{fetch_beatmapset, simulate_beatmapset, BeatmapsetResults};
 
-/// A data type that represents the Accuracy of a play in osu!standard.
-///
-/// Can either be a *Percentage*, or *Hits*, which contains the number of
-/// non-300s (perfect) hits of a play: good (100s) and meh (50s).
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum Accuracy {
-    Percentage(f64),
-    Hits { good: usize, meh: usize },
-}
+pub mod beatmap_info;
+pub use beatmap_info::{BeatmapInfo, BeatmapStats};
 
-/// An error that can be returned when the external command call fails.
-#[derive(Debug)]
-struct UnsuccessfulCommandError;
-impl fmt::Display for UnsuccessfulCommandError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Unsuccessful command")
-    }
-}
+pub mod backend;
+pub use backend::{PerformanceBackend, Timeouts};
+
+pub mod curve;
+pub use curve::{simulate_curve, CurveSpec, PpCurve};
+
+pub mod dotnet;
+pub use dotnet::DotnetBackend;
+
+pub mod error;
+pub use error::CalculationError;
+
+pub mod native;
+pub use native::NativeBackend;
 
-impl Error for UnsuccessfulCommandError {}
+pub mod mods;
+pub use mods::{Mod, Mods, UnknownModError};
+
+mod process;
 
 pub mod profile;
 pub use profile::{calculate_profile, ProfileResults};
 
+pub mod ruleset;
+pub use ruleset::Ruleset;
+
+pub mod registry;
+pub use registry::{CalculatorInfo, CalculatorRegistry, DEFAULT_CALCULATOR};
+
+pub mod score_list;
+pub use score_list::{calculate_score_list, parse_score_list, ScoreListResults};
+
 pub mod simulate;
-pub use simulate::{simulate_play, SimulationParams, SimulationResults};
+pub use simulate::{
+    simulate_play, simulate_play_file, LiveComparison, SimulationParams, SimulationResults,
+};
 
 #[cfg(test)]
 mod test {
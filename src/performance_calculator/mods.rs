@@ -0,0 +1,578 @@
+//! osu! mods, and conversions from/to the formats they're found in.
+//!
+//! Mods are usually written as a string of acronyms (like "HDDTHR"), but
+//! the osu! api (v1), replays and osu!'s databases store them as a bitmask.
+//! `Mods` (de)serializes from either form, and serializes as a list of
+//! acronyms; use `#[serde(with = "bitmask")]` to serialize it as a bitmask.
+use super::Ruleset;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+/// A enum, representing all possible mods, in every ruleset. Not every mod
+/// is available in every ruleset, see `Mod::is_available`.
+///
+/// Mods are ordered the way they're usually displayed (HD before HR before DT).
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub enum Mod {
+    HD,
+    HR,
+    DT,
+    NC,
+    FL,
+    NF,
+    EZ,
+    HT,
+    SO,
+    SD,
+    PF,
+    TD,
+    RX,
+    AP,
+    // osu!mania only.
+    FI,
+    RD,
+    MR,
+    DS,
+    K1,
+    K2,
+    K3,
+    K4,
+    K5,
+    K6,
+    K7,
+    K8,
+    K9,
+    // Mods that don't affect pp, but can still be found in scores.
+    V2,
+    AT,
+    CN,
+    TP,
+}
+
+impl Mod {
+    /// All mods, in display order.
+    pub fn all() -> &'static [Mod] {
+        use Mod::*;
+
+        &[
+            HD, HR, DT, NC, FL, NF, EZ, HT, SO, SD, PF, TD, RX, AP, FI, RD, MR, DS, K1, K2, K3, K4,
+            K5, K6, K7, K8, K9, V2, AT, CN, TP,
+        ]
+    }
+
+    /// The acronym of this mod, as displayed by osu!.
+    pub fn acronym(&self) -> &'static str {
+        use Mod::*;
+
+        match *self {
+            HD => "HD",
+            HR => "HR",
+            DT => "DT",
+            NC => "NC",
+            FL => "FL",
+            NF => "NF",
+            EZ => "EZ",
+            HT => "HT",
+            SO => "SO",
+            SD => "SD",
+            PF => "PF",
+            TD => "TD",
+            RX => "RX",
+            AP => "AP",
+            FI => "FI",
+            RD => "RD",
+            MR => "MR",
+            DS => "DS",
+            K1 => "1K",
+            K2 => "2K",
+            K3 => "3K",
+            K4 => "4K",
+            K5 => "5K",
+            K6 => "6K",
+            K7 => "7K",
+            K8 => "8K",
+            K9 => "9K",
+            V2 => "V2",
+            AT => "AT",
+            CN => "CN",
+            TP => "TP",
+        }
+    }
+
+    /// Obtain a string representation of the mod, suitable to pass as a mod
+    /// parameter to PerformanceCalculator.
+    pub(super) fn to_arg(&self) -> String {
+        match *self {
+            // lazer calls it SV2.
+            Mod::V2 => "sv2".to_string(),
+            _ => self.acronym().to_lowercase(),
+        }
+    }
+
+    /// The bits of this mod, in the osu! api (v1) bitmask. Mods that imply
+    /// others (NC implies DT, PF implies SD) set the implied bit as well.
+    pub fn bits(&self) -> u32 {
+        use Mod::*;
+
+        match *self {
+            NF => 1 << 0,
+            EZ => 1 << 1,
+            TD => 1 << 2,
+            HD => 1 << 3,
+            HR => 1 << 4,
+            SD => 1 << 5,
+            DT => 1 << 6,
+            RX => 1 << 7,
+            HT => 1 << 8,
+            NC => 1 << 9 | DT.bits(),
+            FL => 1 << 10,
+            AT => 1 << 11,
+            SO => 1 << 12,
+            AP => 1 << 13,
+            PF => 1 << 14 | SD.bits(),
+            K4 => 1 << 15,
+            K5 => 1 << 16,
+            K6 => 1 << 17,
+            K7 => 1 << 18,
+            K8 => 1 << 19,
+            FI => 1 << 20,
+            RD => 1 << 21,
+            CN => 1 << 22,
+            TP => 1 << 23,
+            K9 => 1 << 24,
+            DS => 1 << 25,
+            K1 => 1 << 26,
+            K3 => 1 << 27,
+            K2 => 1 << 28,
+            V2 => 1 << 29,
+            MR => 1 << 30,
+        }
+    }
+
+    /// Whether this is one of the osu!mania key count mods.
+    pub fn is_key_mod(&self) -> bool {
+        use Mod::*;
+
+        match *self {
+            K1 | K2 | K3 | K4 | K5 | K6 | K7 | K8 | K9 => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this mod can be used in `ruleset`.
+    pub fn is_available(&self, ruleset: Ruleset) -> bool {
+        use Mod::*;
+
+        match *self {
+            HD | DT | NC | FL | NF | EZ | HT | HR | SD | PF | V2 | AT | CN => true,
+            RX => ruleset != Ruleset::Mania,
+            SO | TD | AP | TP => ruleset == Ruleset::Osu,
+            _ => ruleset == Ruleset::Mania,
+        }
+    }
+}
+
+impl fmt::Display for Mod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.acronym())
+    }
+}
+
+/// An error returned when parsing unknown mods.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownModError(String);
+
+impl fmt::Display for UnknownModError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown mod {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownModError {}
+
+impl FromStr for Mod {
+    type Err = UnknownModError;
+
+    /// Parses a single mod acronym, case-insensitively. "K4" is accepted as
+    /// well as "4K", and "SV2" as well as "V2".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_uppercase();
+        let acronym = match upper.as_str() {
+            "SV2" => "V2".to_string(),
+            keys if keys.len() == 2 && keys.starts_with('K') => format!("{}K", &keys[1..]),
+            other => other.to_string(),
+        };
+
+        Mod::all()
+            .iter()
+            .find(|m| m.acronym() == acronym)
+            .cloned()
+            .ok_or(UnknownModError(s.to_string()))
+    }
+}
+
+impl Serialize for Mod {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.acronym())
+    }
+}
+
+impl<'de> Deserialize<'de> for Mod {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let acronym = String::deserialize(deserializer)?;
+        acronym.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A combination of mods. Derefs to a `BTreeSet<Mod>`, which keeps them in
+/// display order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Mods(pub BTreeSet<Mod>);
+
+impl Mods {
+    /// The mods set in an osu! api (v1) `bitmask`. Unknown bits are ignored.
+    pub fn from_bits(bitmask: u32) -> Mods {
+        let mut mods: BTreeSet<Mod> = Mod::all()
+            .iter()
+            .cloned()
+            .filter(|m| bitmask & m.bits() == m.bits())
+            .collect();
+
+        // NC and PF also set the bits of the mods they imply.
+        if mods.contains(&Mod::NC) {
+            mods.remove(&Mod::DT);
+        }
+        if mods.contains(&Mod::PF) {
+            mods.remove(&Mod::SD);
+        }
+
+        Mods(mods)
+    }
+
+    /// The osu! api (v1) bitmask for these mods.
+    pub fn to_bits(&self) -> u32 {
+        self.0.iter().fold(0, |bitmask, m| bitmask | m.bits())
+    }
+
+    /// These mods, plus the ones they imply (NC implies DT, PF implies SD).
+    pub fn with_implied(&self) -> Mods {
+        let mut mods = self.clone();
+        if mods.contains(&Mod::NC) {
+            mods.insert(Mod::DT);
+        }
+        if mods.contains(&Mod::PF) {
+            mods.insert(Mod::SD);
+        }
+
+        mods
+    }
+
+    /// Checks that these mods can be played together on `ruleset`. Implied
+    /// mods are taken into account, so NC can't be combined with HT, nor PF
+    /// with NF.
+    ///
+    /// # Errors
+    ///
+    /// Will return a message describing the problem, if a mod isn't available
+    /// on `ruleset`, if more than one key mod is set, or if two mods are
+    /// incompatible with each other.
+    pub fn check(&self, ruleset: Ruleset) -> Result<(), String> {
+        if let Some(m) = self.0.iter().find(|m| !m.is_available(ruleset)) {
+            return Err(format!(
+                "{} isn't available in {}",
+                m,
+                ruleset.display_name()
+            ));
+        }
+
+        let keys: Vec<_> = self.0.iter().filter(|m| m.is_key_mod()).collect();
+        if keys.len() > 1 {
+            return Err(format!(
+                "{} and {} can't be used together",
+                keys[0], keys[1]
+            ));
+        }
+
+        let mods = self.with_implied();
+        for (a, b) in INCOMPATIBLE_MODS {
+            if mods.contains(a) && mods.contains(b) {
+                // Name the mods the way they were given.
+                let given = |m: &Mod| match *m {
+                    Mod::DT if self.contains(&Mod::NC) => Mod::NC,
+                    Mod::SD if self.contains(&Mod::PF) => Mod::PF,
+                    other => other,
+                };
+
+                return Err(format!(
+                    "{} and {} can't be used together",
+                    given(a),
+                    given(b)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Pairs of mods that can't be used together.
+const INCOMPATIBLE_MODS: &[(Mod, Mod)] = &[
+    (Mod::HR, Mod::EZ),
+    (Mod::DT, Mod::HT),
+    (Mod::SD, Mod::NF),
+    (Mod::RX, Mod::AP),
+    (Mod::RX, Mod::NF),
+    (Mod::RX, Mod::SD),
+    (Mod::AP, Mod::SO),
+    (Mod::AT, Mod::RX),
+    (Mod::AT, Mod::AP),
+    (Mod::FI, Mod::HD),
+    (Mod::FI, Mod::FL),
+];
+
+impl Deref for Mods {
+    type Target = BTreeSet<Mod>;
+
+    fn deref(&self) -> &BTreeSet<Mod> {
+        &self.0
+    }
+}
+
+impl DerefMut for Mods {
+    fn deref_mut(&mut self) -> &mut BTreeSet<Mod> {
+        &mut self.0
+    }
+}
+
+impl From<BTreeSet<Mod>> for Mods {
+    fn from(mods: BTreeSet<Mod>) -> Self {
+        Mods(mods)
+    }
+}
+
+impl FromIterator<Mod> for Mods {
+    fn from_iter<I: IntoIterator<Item = Mod>>(iter: I) -> Self {
+        Mods(iter.into_iter().collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a Mods {
+    type Item = &'a Mod;
+    type IntoIter = std::collections::btree_set::Iter<'a, Mod>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl fmt::Display for Mods {
+    /// Writes the acronyms of the mods together, like "HDDT".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for m in &self.0 {
+            write!(f, "{}", m)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Mods {
+    type Err = UnknownModError;
+
+    /// Parses a mod combination like "HDDTHR", "+HD,DT" or "hd dt". "NM" and
+    /// empty strings mean no mods.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let acronyms: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ',' && *c != '+')
+            .collect::<String>()
+            .to_uppercase();
+
+        if acronyms == "NM" {
+            return Ok(Mods::default());
+        }
+        if !acronyms.is_ascii() {
+            return Err(UnknownModError(s.to_string()));
+        }
+
+        let mut mods = Mods::default();
+        let mut rest = acronyms.as_str();
+        while !rest.is_empty() {
+            // Every acronym is two characters long, except for SV2.
+            let len = if rest.starts_with("SV2") { 3 } else { 2 };
+            if rest.len() < len {
+                return Err(UnknownModError(rest.to_string()));
+            }
+
+            let (acronym, tail) = rest.split_at(len);
+            mods.insert(acronym.parse()?);
+            rest = tail;
+        }
+
+        Ok(mods)
+    }
+}
+
+impl Serialize for Mods {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+/// Deserializes `Mods` from a list of acronyms, a string like "HDDT", or a bitmask.
+struct ModsVisitor;
+
+impl<'de> Visitor<'de> for ModsVisitor {
+    type Value = Mods;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a list of mods, a string of mod acronyms, or a mod bitmask"
+        )
+    }
+
+    fn visit_u64<E: de::Error>(self, bitmask: u64) -> Result<Mods, E> {
+        if bitmask > u64::from(u32::max_value()) {
+            return Err(E::custom(format!("invalid mod bitmask {}", bitmask)));
+        }
+
+        Ok(Mods::from_bits(bitmask as u32))
+    }
+
+    fn visit_i64<E: de::Error>(self, bitmask: i64) -> Result<Mods, E> {
+        if bitmask < 0 {
+            return Err(E::custom(format!("invalid mod bitmask {}", bitmask)));
+        }
+
+        self.visit_u64(bitmask as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, acronyms: &str) -> Result<Mods, E> {
+        acronyms.parse().map_err(E::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Mods, A::Error> {
+        let mut mods = Mods::default();
+        while let Some(m) = seq.next_element()? {
+            mods.insert(m);
+        }
+
+        Ok(mods)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mods {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ModsVisitor)
+    }
+}
+
+/// (De)serializes `Mods` as an osu! api (v1) bitmask, for use with
+/// `#[serde(with = "bitmask")]`.
+pub mod bitmask {
+    use super::Mods;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mods: &Mods, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(mods.to_bits())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Mods, D::Error> {
+        Mods::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_mods() {
+        let mods: Mods = "HDDTHR".parse().unwrap();
+        assert_eq!(mods, mods![Mod::HD, Mod::HR, Mod::DT]);
+        assert_eq!(mods.to_string(), "HDHRDT");
+
+        assert_eq!("+hd,4k".parse(), Ok(mods![Mod::HD, Mod::K4]));
+        assert_eq!("NM".parse(), Ok(Mods::default()));
+        assert_eq!("".parse(), Ok(Mods::default()));
+        assert_eq!("HDSV2".parse(), Ok(mods![Mod::HD, Mod::V2]));
+        assert_eq!("HDX".parse::<Mods>(), Err(UnknownModError("X".to_string())));
+        assert!("HDXX".parse::<Mods>().is_err());
+    }
+
+    #[test]
+    fn test_bits() {
+        // HDNC, as the osu! api reports it.
+        let mods = Mods::from_bits(8 | 64 | 512);
+        assert_eq!(mods, mods![Mod::HD, Mod::NC]);
+        assert_eq!(mods.to_bits(), 8 | 64 | 512);
+
+        assert_eq!(Mods::from_bits(32 | 16384), mods![Mod::PF]);
+        assert_eq!(Mods::from_bits(32), mods![Mod::SD]);
+
+        for m in Mod::all() {
+            let mods = mods![*m];
+            assert_eq!(Mods::from_bits(mods.to_bits()), mods);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let check = |mods: &str, ruleset| mods.parse::<Mods>().unwrap().check(ruleset);
+
+        assert_eq!(check("HDDTHR", Ruleset::Osu), Ok(()));
+        assert_eq!(check("HDNC", Ruleset::Osu), Ok(()));
+        assert_eq!(check("DTNC", Ruleset::Osu), Ok(()));
+        assert_eq!(
+            check("HREZ", Ruleset::Osu),
+            Err("HR and EZ can't be used together".to_string())
+        );
+        assert_eq!(
+            check("NCHT", Ruleset::Osu),
+            Err("NC and HT can't be used together".to_string())
+        );
+        assert_eq!(
+            check("PFNF", Ruleset::Osu),
+            Err("PF and NF can't be used together".to_string())
+        );
+        assert_eq!(
+            check("4K7K", Ruleset::Mania),
+            Err("4K and 7K can't be used together".to_string())
+        );
+        assert_eq!(
+            check("RX", Ruleset::Mania),
+            Err("RX isn't available in osu!mania".to_string())
+        );
+        assert_eq!(check("HR", Ruleset::Mania), Ok(()));
+    }
+
+    #[test]
+    fn test_serde() {
+        #[derive(Serialize, Deserialize)]
+        struct Bitmask {
+            #[serde(with = "bitmask")]
+            mods: Mods,
+        }
+
+        let mods = mods![Mod::HD, Mod::DT, Mod::K7];
+        let acronyms = serde_json::to_string(&mods).unwrap();
+        assert_eq!(acronyms, r#"["HD","DT","7K"]"#);
+        assert_eq!(serde_json::from_str::<Mods>(&acronyms).unwrap(), mods);
+
+        let bits = serde_json::to_string(&Bitmask { mods: mods.clone() }).unwrap();
+        assert_eq!(bits, format!(r#"{{"mods":{}}}"#, 8 | 64 | (1 << 18)));
+        assert_eq!(serde_json::from_str::<Bitmask>(&bits).unwrap().mods, mods);
+
+        assert_eq!(
+            serde_json::from_str::<Mods>(r#""hdhr""#).unwrap(),
+            mods![Mod::HD, Mod::HR]
+        );
+        assert!(serde_json::from_str::<Mods>(r#"["HD","XX"]"#).is_err());
+    }
+}
@@ -0,0 +1,635 @@
+//! osu!standard difficulty calculation, following osu!lazer's
+//! `OsuDifficultyCalculator`.
+//!
+//! The beatmap is turned into a list of `DifficultyObject`s (one for each
+//! pair of consecutive hit objects), which are then fed into the aim and
+//! speed skills. Each skill keeps a decaying strain, and its difficulty is a
+//! weighted sum of the highest strains of each 400ms section.
+use super::path::SliderPath;
+use crate::beatmap::{difficulty_range, Beatmap, Difficulty, HitObjectKind, Position};
+use crate::performance_calculator::{Mod, Mods};
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+const SECTION_LENGTH: f64 = 400.0;
+const DIFFICULTY_MULTIPLIER: f64 = 0.0675;
+const NORMALIZED_RADIUS: f64 = 52.0;
+const STACK_DISTANCE: f64 = 3.0;
+
+/// The result of a difficulty calculation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DifficultyAttributes {
+    pub star_rating: f64,
+    pub aim_strain: f64,
+    pub speed_strain: f64,
+    pub approach_rate: f64,
+    pub overall_difficulty: f64,
+    pub max_combo: usize,
+    pub circle_count: usize,
+    pub object_count: usize,
+}
+
+/// The clock rate a mod combination plays at.
+pub fn clock_rate(mods: &Mods) -> f64 {
+    if mods.contains(&Mod::DT) || mods.contains(&Mod::NC) {
+        1.5
+    } else if mods.contains(&Mod::HT) {
+        0.75
+    } else {
+        1.0
+    }
+}
+
+/// The approach rate that, at normal speed, gives the same preempt time that
+/// `approach_rate` gives when played at `rate`.
+pub fn rate_adjusted_approach_rate(approach_rate: f64, rate: f64) -> f64 {
+    let preempt = difficulty_range(approach_rate, 1800.0, 1200.0, 450.0) / rate;
+
+    if preempt > 1200.0 {
+        (1800.0 - preempt) / 120.0
+    } else {
+        (1200.0 - preempt) / 150.0 + 5.0
+    }
+}
+
+/// The overall difficulty that, at normal speed, gives the same 300 hit
+/// window that `overall_difficulty` gives when played at `rate`.
+pub fn rate_adjusted_overall_difficulty(overall_difficulty: f64, rate: f64) -> f64 {
+    let great_window =
+        (difficulty_range(overall_difficulty, 160.0, 100.0, 40.0) / 2.0).trunc() / rate;
+
+    (80.0 - great_window) / 6.0
+}
+
+/// The difficulty settings of `difficulty`, after applying HR or EZ.
+pub fn adjusted_difficulty(difficulty: &Difficulty, mods: &Mods) -> Difficulty {
+    let mut adjusted = *difficulty;
+
+    if mods.contains(&Mod::HR) {
+        adjusted.circle_size = (adjusted.circle_size * 1.3).min(10.0);
+        adjusted.approach_rate = (adjusted.approach_rate * 1.4).min(10.0);
+        adjusted.overall_difficulty = (adjusted.overall_difficulty * 1.4).min(10.0);
+        adjusted.hp_drain_rate = (adjusted.hp_drain_rate * 1.4).min(10.0);
+    } else if mods.contains(&Mod::EZ) {
+        adjusted.circle_size *= 0.5;
+        adjusted.approach_rate *= 0.5;
+        adjusted.overall_difficulty *= 0.5;
+        adjusted.hp_drain_rate *= 0.5;
+    }
+
+    adjusted
+}
+
+/// A hit object, with its stacked position and lazy slider data precomputed.
+struct OsuObject {
+    start_time: f64,
+    stacked_position: Position,
+    /// Where the cursor ends up after this object. For sliders, this is the
+    /// end of the "lazy" cursor movement, otherwise it's the object position.
+    end_cursor_position: Position,
+    lazy_travel_distance: f64,
+    is_slider: bool,
+    is_spinner: bool,
+}
+
+/// A pair of consecutive hit objects, and the movement between them.
+struct DifficultyObject {
+    start_time: f64,
+    delta_time: f64,
+    strain_time: f64,
+    jump_distance: f64,
+    travel_distance: f64,
+    angle: Option<f64>,
+    is_spinner: bool,
+}
+
+/// Stacking information for a hit object.
+struct StackObject {
+    position: Position,
+    end_position: Position,
+    start_time: f64,
+    end_time: f64,
+    is_slider: bool,
+    is_spinner: bool,
+    stack_height: i32,
+}
+
+/// Applies osu!stable's stacking, for beatmaps with format version 6 or later.
+fn apply_stacking(objects: &mut [StackObject], stack_threshold: f64) {
+    if objects.is_empty() {
+        return;
+    }
+
+    // Only the whole beatmap is ever stacked, so there's no need to extend
+    // the index range like osu!stable does for partial ranges.
+    for i in (1..objects.len()).rev() {
+        let mut n = i;
+        let mut object_i = i;
+
+        if objects[object_i].stack_height != 0 || objects[object_i].is_spinner {
+            continue;
+        }
+
+        if !objects[object_i].is_slider {
+            while n > 0 {
+                n -= 1;
+
+                if objects[n].is_spinner {
+                    continue;
+                }
+
+                if objects[object_i].start_time - objects[n].end_time > stack_threshold {
+                    break;
+                }
+
+                if objects[n].is_slider
+                    && objects[n].end_position.distance(objects[object_i].position) < STACK_DISTANCE
+                {
+                    let offset = objects[object_i].stack_height - objects[n].stack_height + 1;
+
+                    for j in n + 1..=i {
+                        if objects[n].end_position.distance(objects[j].position) < STACK_DISTANCE {
+                            objects[j].stack_height -= offset;
+                        }
+                    }
+
+                    break;
+                }
+
+                if objects[n].position.distance(objects[object_i].position) < STACK_DISTANCE {
+                    objects[n].stack_height = objects[object_i].stack_height + 1;
+                    object_i = n;
+                }
+            }
+        } else {
+            while n > 0 {
+                n -= 1;
+
+                if objects[n].is_spinner {
+                    continue;
+                }
+
+                if objects[object_i].start_time - objects[n].start_time > stack_threshold {
+                    break;
+                }
+
+                if objects[n].end_position.distance(objects[object_i].position) < STACK_DISTANCE {
+                    objects[n].stack_height = objects[object_i].stack_height + 1;
+                    object_i = n;
+                }
+            }
+        }
+    }
+}
+
+/// Applies osu!stable's stacking, for beatmaps older than format version 6.
+fn apply_stacking_old(objects: &mut [StackObject], stack_threshold: f64) {
+    for i in 0..objects.len() {
+        if objects[i].stack_height != 0 && !objects[i].is_slider {
+            continue;
+        }
+
+        let mut start_time = objects[i].end_time;
+        let mut slider_stack = 0;
+
+        for j in i + 1..objects.len() {
+            if objects[j].start_time - stack_threshold > start_time {
+                break;
+            }
+
+            let position2 = if objects[i].is_slider {
+                objects[i].end_position
+            } else {
+                objects[i].position
+            };
+
+            if objects[j].position.distance(objects[i].position) < STACK_DISTANCE {
+                objects[i].stack_height += 1;
+                start_time = objects[j].end_time;
+            } else if objects[j].position.distance(position2) < STACK_DISTANCE {
+                slider_stack += 1;
+                objects[j].stack_height -= slider_stack;
+                start_time = objects[j].end_time;
+            }
+        }
+    }
+}
+
+/// Builds the `OsuObject`s for a beatmap: applies stacking, and computes the
+/// lazy cursor movement of sliders.
+fn osu_objects(beatmap: &Beatmap, difficulty: &Difficulty) -> Vec<OsuObject> {
+    let scale = (1.0 - 0.7 * (difficulty.circle_size - 5.0) / 5.0) / 2.0;
+    let radius = 64.0 * scale;
+
+    let paths: Vec<Option<SliderPath>> = beatmap
+        .hit_objects
+        .iter()
+        .map(|h| match h.kind {
+            HitObjectKind::Slider(ref slider) => Some(SliderPath::new(
+                slider.curve_type,
+                &slider.control_points,
+                slider.pixel_length,
+            )),
+            _ => None,
+        })
+        .collect();
+
+    let mut stack_objects: Vec<StackObject> = beatmap
+        .hit_objects
+        .iter()
+        .zip(paths.iter())
+        .map(|(h, path)| {
+            let end_position = match (&h.kind, path) {
+                (HitObjectKind::Slider(ref slider), Some(path)) => {
+                    let progress = if slider.span_count % 2 == 0 { 0.0 } else { 1.0 };
+                    h.position.add(path.position_at(progress))
+                }
+                _ => h.position,
+            };
+
+            StackObject {
+                position: h.position,
+                end_position: end_position,
+                start_time: h.start_time,
+                end_time: h.end_time(),
+                is_slider: h.is_slider(),
+                is_spinner: h.is_spinner(),
+                stack_height: 0,
+            }
+        })
+        .collect();
+
+    let preempt = difficulty_range(difficulty.approach_rate, 1800.0, 1200.0, 450.0);
+    let stack_threshold = preempt * beatmap.stack_leniency;
+
+    if beatmap.format_version >= 6 {
+        apply_stacking(&mut stack_objects, stack_threshold);
+    } else {
+        apply_stacking_old(&mut stack_objects, stack_threshold);
+    }
+
+    beatmap
+        .hit_objects
+        .iter()
+        .zip(paths.iter())
+        .zip(stack_objects.iter())
+        .map(|((h, path), stack)| {
+            let stack_offset = -6.4 * scale * stack.stack_height as f64;
+            let stacked_position = h.position.add(Position::new(stack_offset, stack_offset));
+
+            let mut end_cursor_position = stacked_position;
+            let mut lazy_travel_distance = 0.0;
+
+            if let (HitObjectKind::Slider(ref slider), Some(path)) = (&h.kind, path) {
+                let follow_circle_radius = radius * 3.0;
+
+                for nested in &slider.nested {
+                    let mut progress = (nested.time - h.start_time) / slider.span_duration;
+                    if progress % 2.0 >= 1.0 {
+                        progress = 1.0 - progress % 1.0;
+                    } else {
+                        progress %= 1.0;
+                    }
+
+                    let diff = stacked_position
+                        .add(path.position_at(progress))
+                        .sub(end_cursor_position);
+                    let dist = diff.length();
+
+                    if dist > follow_circle_radius {
+                        let moved = dist - follow_circle_radius;
+                        end_cursor_position = end_cursor_position.add(diff.scale(moved / dist));
+                        lazy_travel_distance += moved;
+                    }
+                }
+            }
+
+            OsuObject {
+                start_time: h.start_time,
+                stacked_position: stacked_position,
+                end_cursor_position: end_cursor_position,
+                lazy_travel_distance: lazy_travel_distance,
+                is_slider: h.is_slider(),
+                is_spinner: h.is_spinner(),
+            }
+        })
+        .collect()
+}
+
+/// Builds the `DifficultyObject`s from the hit objects of a beatmap.
+fn difficulty_objects(objects: &[OsuObject], radius: f64, rate: f64) -> Vec<DifficultyObject> {
+    let mut scaling_factor = NORMALIZED_RADIUS / radius;
+    if radius < 30.0 {
+        let small_circle_bonus = (30.0 - radius).min(5.0) / 50.0;
+        scaling_factor *= 1.0 + small_circle_bonus;
+    }
+
+    (1..objects.len())
+        .map(|i| {
+            let current = &objects[i];
+            let last = &objects[i - 1];
+            let last_last = if i > 1 { Some(&objects[i - 2]) } else { None };
+
+            let delta_time = (current.start_time - last.start_time) / rate;
+
+            let travel_distance = if last.is_slider {
+                last.lazy_travel_distance * scaling_factor
+            } else {
+                0.0
+            };
+
+            let jump_distance = current
+                .stacked_position
+                .scale(scaling_factor)
+                .sub(last.end_cursor_position.scale(scaling_factor))
+                .length();
+
+            let angle = last_last.map(|last_last| {
+                let v1 = last_last.end_cursor_position.sub(last.stacked_position);
+                let v2 = current.stacked_position.sub(last.end_cursor_position);
+
+                let dot = v1.dot(v2);
+                let det = v1.x * v2.y - v1.y * v2.x;
+
+                det.atan2(dot).abs()
+            });
+
+            DifficultyObject {
+                start_time: current.start_time,
+                delta_time: delta_time,
+                strain_time: delta_time.max(50.0),
+                jump_distance: jump_distance,
+                travel_distance: travel_distance,
+                angle: angle,
+                is_spinner: current.is_spinner,
+            }
+        })
+        .collect()
+}
+
+/// The kind of a skill.
+#[derive(Clone, Copy, PartialEq)]
+enum SkillKind {
+    Aim,
+    Speed,
+}
+
+/// A skill, that keeps track of the current strain, and the strain peaks of
+/// each section.
+struct Skill {
+    kind: SkillKind,
+    current_strain: f64,
+    current_section_peak: f64,
+    strain_peaks: Vec<f64>,
+    previous: Option<(f64, f64, f64)>,
+}
+
+impl Skill {
+    fn new(kind: SkillKind) -> Self {
+        Skill {
+            kind: kind,
+            current_strain: 1.0,
+            current_section_peak: 1.0,
+            strain_peaks: Vec::new(),
+            previous: None,
+        }
+    }
+
+    fn skill_multiplier(&self) -> f64 {
+        match self.kind {
+            SkillKind::Aim => 26.25,
+            SkillKind::Speed => 1400.0,
+        }
+    }
+
+    fn strain_decay_base(&self) -> f64 {
+        match self.kind {
+            SkillKind::Aim => 0.15,
+            SkillKind::Speed => 0.3,
+        }
+    }
+
+    fn strain_decay(&self, ms: f64) -> f64 {
+        self.strain_decay_base().powf(ms / 1000.0)
+    }
+
+    fn save_current_peak(&mut self) {
+        if self.previous.is_some() {
+            self.strain_peaks.push(self.current_section_peak);
+        }
+    }
+
+    fn start_new_section_from(&mut self, offset: f64) {
+        if let Some((start_time, _, _)) = self.previous {
+            self.current_section_peak =
+                self.current_strain * self.strain_decay(offset - start_time);
+        }
+    }
+
+    fn process(&mut self, current: &DifficultyObject) {
+        self.current_strain *= self.strain_decay(current.delta_time);
+        self.current_strain += self.strain_value_of(current) * self.skill_multiplier();
+
+        self.current_section_peak = self.current_section_peak.max(self.current_strain);
+
+        self.previous = Some((
+            current.start_time,
+            current.jump_distance,
+            current.strain_time,
+        ));
+    }
+
+    fn strain_value_of(&self, current: &DifficultyObject) -> f64 {
+        if current.is_spinner {
+            return 0.0;
+        }
+
+        match self.kind {
+            SkillKind::Aim => self.aim_strain_value_of(current),
+            SkillKind::Speed => speed_strain_value_of(current),
+        }
+    }
+
+    fn aim_strain_value_of(&self, current: &DifficultyObject) -> f64 {
+        const ANGLE_BONUS_BEGIN: f64 = PI / 3.0;
+        const TIMING_THRESHOLD: f64 = 107.0;
+        const SCALE: f64 = 90.0;
+
+        let apply_diminishing_exp = |val: f64| val.powf(0.99);
+
+        let mut result = 0.0;
+
+        if let (Some((_, previous_jump_distance, previous_strain_time)), Some(angle)) =
+            (self.previous, current.angle)
+        {
+            if angle > ANGLE_BONUS_BEGIN {
+                let angle_bonus = ((previous_jump_distance - SCALE).max(0.0)
+                    * (angle - ANGLE_BONUS_BEGIN).sin().powi(2)
+                    * (current.jump_distance - SCALE).max(0.0))
+                .sqrt();
+
+                result = 1.5 * apply_diminishing_exp(angle_bonus.max(0.0))
+                    / previous_strain_time.max(TIMING_THRESHOLD);
+            }
+        }
+
+        let jump_distance_exp = apply_diminishing_exp(current.jump_distance);
+        let travel_distance_exp = apply_diminishing_exp(current.travel_distance);
+        let distance_sum = jump_distance_exp
+            + travel_distance_exp
+            + (travel_distance_exp * jump_distance_exp).sqrt();
+
+        (result + distance_sum / current.strain_time.max(TIMING_THRESHOLD))
+            .max(distance_sum / current.strain_time)
+    }
+
+    /// The difficulty value of this skill: the weighted sum of its section
+    /// peaks, from highest to lowest.
+    fn difficulty_value(&self) -> f64 {
+        let mut peaks = self.strain_peaks.clone();
+        peaks.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+        let mut difficulty = 0.0;
+        let mut weight = 1.0;
+
+        for strain in peaks {
+            difficulty += strain * weight;
+            weight *= 0.9;
+        }
+
+        difficulty
+    }
+}
+
+fn speed_strain_value_of(current: &DifficultyObject) -> f64 {
+    const SINGLE_SPACING_THRESHOLD: f64 = 125.0;
+    const ANGLE_BONUS_BEGIN: f64 = 5.0 * PI / 6.0;
+    const PI_OVER_4: f64 = PI / 4.0;
+    const PI_OVER_2: f64 = PI / 2.0;
+    const MIN_SPEED_BONUS: f64 = 75.0;
+    const MAX_SPEED_BONUS: f64 = 45.0;
+    const SPEED_BALANCING_FACTOR: f64 = 40.0;
+
+    let distance = SINGLE_SPACING_THRESHOLD.min(current.travel_distance + current.jump_distance);
+    let delta_time = MAX_SPEED_BONUS.max(current.delta_time);
+
+    let mut speed_bonus = 1.0;
+    if delta_time < MIN_SPEED_BONUS {
+        speed_bonus = 1.0 + ((MIN_SPEED_BONUS - delta_time) / SPEED_BALANCING_FACTOR).powi(2);
+    }
+
+    let mut angle_bonus = 1.0;
+    if let Some(angle) = current.angle {
+        if angle < ANGLE_BONUS_BEGIN {
+            angle_bonus = 1.0 + (1.5 * (ANGLE_BONUS_BEGIN - angle)).sin().powi(2) / 3.57;
+
+            if angle < PI_OVER_2 {
+                angle_bonus = 1.28;
+                if distance < 90.0 && angle < PI_OVER_4 {
+                    angle_bonus += (1.0 - angle_bonus) * ((90.0 - distance) / 10.0).min(1.0);
+                } else if distance < 90.0 {
+                    angle_bonus += (1.0 - angle_bonus)
+                        * ((90.0 - distance) / 10.0).min(1.0)
+                        * ((PI_OVER_2 - angle) / PI_OVER_4).sin();
+                }
+            }
+        }
+    }
+
+    (1.0 + (speed_bonus - 1.0) * 0.75)
+        * angle_bonus
+        * (0.95 + speed_bonus * (distance / SINGLE_SPACING_THRESHOLD).powf(3.5))
+        / current.strain_time
+}
+
+/// Calculates the difficulty of an osu!standard `beatmap`, under `mods`.
+pub fn calculate_difficulty(beatmap: &Beatmap, mods: &Mods) -> DifficultyAttributes {
+    let rate = clock_rate(mods);
+    let difficulty = adjusted_difficulty(&beatmap.difficulty, mods);
+
+    let approach_rate = rate_adjusted_approach_rate(difficulty.approach_rate, rate);
+    let overall_difficulty = rate_adjusted_overall_difficulty(difficulty.overall_difficulty, rate);
+
+    let mut attributes = DifficultyAttributes {
+        star_rating: 0.0,
+        aim_strain: 0.0,
+        speed_strain: 0.0,
+        approach_rate: approach_rate,
+        overall_difficulty: overall_difficulty,
+        max_combo: beatmap.max_combo(),
+        circle_count: beatmap.circle_count(),
+        object_count: beatmap.hit_objects.len(),
+    };
+
+    if beatmap.hit_objects.is_empty() {
+        return attributes;
+    }
+
+    let scale = (1.0 - 0.7 * (difficulty.circle_size - 5.0) / 5.0) / 2.0;
+    let objects = osu_objects(beatmap, &difficulty);
+    let difficulty_objects = difficulty_objects(&objects, 64.0 * scale, rate);
+
+    let mut aim = Skill::new(SkillKind::Aim);
+    let mut speed = Skill::new(SkillKind::Speed);
+
+    let section_length = SECTION_LENGTH * rate;
+    let mut current_section_end =
+        (beatmap.hit_objects[0].start_time / section_length).ceil() * section_length;
+
+    for h in &difficulty_objects {
+        while h.start_time > current_section_end {
+            for skill in [&mut aim, &mut speed].iter_mut() {
+                skill.save_current_peak();
+                skill.start_new_section_from(current_section_end);
+            }
+
+            current_section_end += section_length;
+        }
+
+        aim.process(h);
+        speed.process(h);
+    }
+
+    aim.save_current_peak();
+    speed.save_current_peak();
+
+    let aim_rating = aim.difficulty_value().sqrt() * DIFFICULTY_MULTIPLIER;
+    let speed_rating = speed.difficulty_value().sqrt() * DIFFICULTY_MULTIPLIER;
+
+    attributes.aim_strain = aim_rating;
+    attributes.speed_strain = speed_rating;
+    attributes.star_rating = aim_rating + speed_rating + (aim_rating - speed_rating).abs() / 2.0;
+
+    attributes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+
+    #[test]
+    fn test_rate_adjusting_mods() {
+        let beatmap = Beatmap::parse(TEST_BEATMAP).unwrap();
+
+        let nomod = calculate_difficulty(&beatmap, &Mods::default());
+        let dt = calculate_difficulty(&beatmap, &mods![Mod::DT]);
+        let ht = calculate_difficulty(&beatmap, &mods![Mod::HT]);
+
+        assert!(nomod.star_rating > 0.0);
+        assert!(dt.star_rating > nomod.star_rating);
+        assert!(ht.star_rating < nomod.star_rating);
+        assert!(dt.approach_rate > 10.0);
+        assert_eq!(nomod.max_combo, dt.max_combo);
+    }
+
+    #[test]
+    fn test_hard_rock() {
+        let beatmap = Beatmap::parse(TEST_BEATMAP).unwrap();
+
+        let hr = calculate_difficulty(&beatmap, &mods![Mod::HR]);
+
+        assert_eq!(hr.approach_rate, 10.0);
+        assert!((hr.overall_difficulty - 10.0).abs() < 0.5);
+    }
+}
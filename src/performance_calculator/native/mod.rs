@@ -0,0 +1,180 @@
+//! A native Rust `PerformanceBackend`, for osu!standard.
+//!
+//! Parses the .osu file directly, and calculates the difficulty and PP of
+//! plays without spawning any external process, so there's no need for
+//! dotnet or osu-tools. Profile calculations aren't supported, since those
+//! need the osu! api.
+use super::accuracy::hit_results;
+use super::simulate::PlayInfo;
+use super::{
+    CalculationError, PerformanceBackend, ProfileResults, Ruleset, SimulationParams,
+    SimulationResults,
+};
+use crate::beatmap::Beatmap;
+use std::collections::HashMap;
+use std::fs;
+
+pub mod difficulty;
+mod path;
+pub mod performance;
+
+use difficulty::{calculate_difficulty, DifficultyAttributes};
+use performance::calculate_performance;
+
+/// Identifies the difficulty and PP formulas of the native calculator, so
+/// results of older ones aren't taken from the `SimulationCache`. Should be
+/// bumped whenever `difficulty` or `performance` change their results.
+pub const FORMULA_VERSION: u32 = 1;
+
+/// A backend that calculates osu!standard PP natively.
+pub struct NativeBackend;
+
+impl NativeBackend {
+    pub fn new() -> Self {
+        NativeBackend
+    }
+}
+
+/// Reads and parses the osu!standard beatmap at `beatmap_path`. Text that isn't
+/// valid UTF-8 (like the metadata of some old beatmaps) is decoded lossily.
+fn read_beatmap(beatmap_path: &str) -> Result<Beatmap, CalculationError> {
+    let contents = fs::read(beatmap_path)?;
+    let beatmap = Beatmap::parse(&String::from_utf8_lossy(&contents))?;
+
+    if beatmap.mode != 0 {
+        return Err(CalculationError::Unsupported(
+            "Non osu!standard beatmaps".to_string(),
+        ));
+    }
+
+    Ok(beatmap)
+}
+
+/// Checks that `params` are for osu!standard, the only supported ruleset.
+fn check_ruleset(params: &SimulationParams) -> Result<(), CalculationError> {
+    if params.ruleset != Ruleset::Osu {
+        return Err(CalculationError::Unsupported(format!(
+            "{} simulation",
+            params.ruleset.display_name()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Simulates a play described by `params` on `beatmap`, whose difficulty
+/// with the play mods is `attributes`.
+fn simulate_parsed(
+    beatmap: &Beatmap,
+    attributes: &DifficultyAttributes,
+    params: &SimulationParams,
+) -> Result<SimulationResults, CalculationError> {
+    let hits = hit_results(
+        params.accuracy,
+        Ruleset::Osu,
+        attributes.object_count,
+        params.misses.unwrap_or(0),
+    )?;
+    let combo = params.combo.unwrap_or(attributes.max_combo);
+
+    let performance = calculate_performance(attributes, &params.mods, &hits, combo);
+
+    let mut category_attribs = HashMap::new();
+    category_attribs.insert("Aim".to_string(), performance.aim);
+    category_attribs.insert("Speed".to_string(), performance.speed);
+    category_attribs.insert("Accuracy".to_string(), performance.accuracy);
+    category_attribs.insert("OD".to_string(), attributes.overall_difficulty);
+    category_attribs.insert("AR".to_string(), attributes.approach_rate);
+    category_attribs.insert("Max Combo".to_string(), attributes.max_combo as f64);
+    category_attribs.insert("Star Rating".to_string(), attributes.star_rating);
+
+    let metadata = &beatmap.metadata;
+
+    Ok(SimulationResults {
+        ruleset: Ruleset::Osu,
+        beatmap_info: format!(
+            "{} - {} - {} ({}) [{}]",
+            metadata.beatmap_id.unwrap_or(0),
+            metadata.artist,
+            metadata.title,
+            metadata.creator,
+            metadata.version
+        ),
+        mods: params.mods.clone(),
+        play_info: PlayInfo {
+            accuracy: hits.accuracy(Ruleset::Osu) * 100.0,
+            combo: combo as i64,
+            max_combo: attributes.max_combo as i64,
+            great: hits.great as i64,
+            good: hits.good as i64,
+            meh: hits.meh as i64,
+            miss: hits.miss as i64,
+        },
+        category_attribs: category_attribs,
+        pp: performance.pp,
+        hit_results: Some(hits),
+        beatmap: None,
+    })
+}
+
+impl PerformanceBackend for NativeBackend {
+    fn calculate_profile(
+        &self,
+        _user: &str,
+        _ruleset: Ruleset,
+    ) -> Result<ProfileResults, CalculationError> {
+        Err(CalculationError::Unsupported(
+            "Profile calculation".to_string(),
+        ))
+    }
+
+    fn simulate_play(
+        &self,
+        beatmap_path: &str,
+        params: &SimulationParams,
+    ) -> Result<SimulationResults, CalculationError> {
+        check_ruleset(params)?;
+
+        let beatmap = read_beatmap(beatmap_path)?;
+        let attributes = calculate_difficulty(&beatmap, &params.mods);
+
+        simulate_parsed(&beatmap, &attributes, params)
+    }
+
+    /// The native calculator is part of this crate, so it changes with it (or
+    /// with its formulas, which may change without a new crate version).
+    fn version(&self) -> Option<String> {
+        Some(format!(
+            "native-{}-{}",
+            env!("CARGO_PKG_VERSION"),
+            FORMULA_VERSION
+        ))
+    }
+
+    /// Parses the beatmap once, and calculates its difficulty once for each
+    /// mod combination.
+    fn simulate_plays(
+        &self,
+        beatmap_path: &str,
+        params: &[SimulationParams],
+    ) -> Vec<Result<SimulationResults, CalculationError>> {
+        let beatmap = match read_beatmap(beatmap_path) {
+            Ok(beatmap) => beatmap,
+            Err(e) => return params.iter().map(|_| Err(e.clone())).collect(),
+        };
+
+        let mut difficulties = HashMap::new();
+        params
+            .iter()
+            .map(|params| {
+                check_ruleset(params)?;
+
+                let attributes = difficulties
+                    .entry(params.mods.clone())
+                    .or_insert_with(|| calculate_difficulty(&beatmap, &params.mods));
+
+                simulate_parsed(&beatmap, attributes, params)
+            })
+            .collect()
+    }
+}
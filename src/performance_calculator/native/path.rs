@@ -0,0 +1,385 @@
+//! Slider path approximation, following osu!lazer's `PathApproximator` and
+//! `SliderPath`.
+//!
+//! A slider path is turned into a polyline, which is then truncated or
+//! extended to the slider's expected length.
+use crate::beatmap::{CurveType, Position};
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+const BEZIER_TOLERANCE: f64 = 0.25;
+const CATMULL_DETAIL: usize = 50;
+const CIRCULAR_ARC_TOLERANCE: f64 = 0.1;
+
+/// A slider path, approximated into a polyline.
+pub struct SliderPath {
+    points: Vec<Position>,
+    cumulative_length: Vec<f64>,
+}
+
+impl SliderPath {
+    /// Creates the path for a slider with the given `curve_type` and
+    /// `control_points` (relative to the slider head). If `expected_distance`
+    /// is given, the path is truncated or extended to that length.
+    pub fn new(
+        curve_type: CurveType,
+        control_points: &[Position],
+        expected_distance: Option<f64>,
+    ) -> Self {
+        let mut path = SliderPath {
+            points: Vec::new(),
+            cumulative_length: Vec::new(),
+        };
+
+        path.calculate_path(curve_type, control_points);
+        path.calculate_cumulative_length(expected_distance);
+
+        path
+    }
+
+    /// The length of this path.
+    pub fn distance(&self) -> f64 {
+        *self.cumulative_length.last().unwrap_or(&0.0)
+    }
+
+    /// The position (relative to the slider head) at `progress`, where 0 is
+    /// the start of the path and 1 is its end.
+    pub fn position_at(&self, progress: f64) -> Position {
+        let d = progress.max(0.0).min(1.0) * self.distance();
+
+        let i = match self
+            .cumulative_length
+            .binary_search_by(|l| l.partial_cmp(&d).unwrap_or(Ordering::Equal))
+        {
+            Ok(i) => i,
+            Err(i) => i,
+        };
+
+        self.interpolate_vertices(i, d)
+    }
+
+    fn interpolate_vertices(&self, i: usize, d: f64) -> Position {
+        if self.points.is_empty() {
+            return Position::default();
+        }
+
+        if i == 0 {
+            return self.points[0];
+        } else if i >= self.points.len() {
+            return self.points[self.points.len() - 1];
+        }
+
+        let p0 = self.points[i - 1];
+        let p1 = self.points[i];
+        let d0 = self.cumulative_length[i - 1];
+        let d1 = self.cumulative_length[i];
+
+        if (d0 - d1).abs() < 1e-7 {
+            return p0;
+        }
+
+        let w = (d - d0) / (d1 - d0);
+        p0.add(p1.sub(p0).scale(w))
+    }
+
+    fn calculate_path(&mut self, curve_type: CurveType, control_points: &[Position]) {
+        let mut start = 0;
+
+        // Repeated control points split the path into separate segments.
+        for i in 0..control_points.len() {
+            if i == control_points.len() - 1 || control_points[i] == control_points[i + 1] {
+                let segment = &control_points[start..=i];
+
+                for point in calculate_subpath(curve_type, segment) {
+                    if self.points.last() != Some(&point) {
+                        self.points.push(point);
+                    }
+                }
+
+                start = i + 1;
+            }
+        }
+    }
+
+    fn calculate_cumulative_length(&mut self, expected_distance: Option<f64>) {
+        let mut length = 0.0;
+        self.cumulative_length.push(0.0);
+
+        let expected = expected_distance.unwrap_or(std::f64::INFINITY);
+
+        let mut i = 0;
+        while i + 1 < self.points.len() {
+            let diff = self.points[i + 1].sub(self.points[i]);
+            let d = diff.length();
+
+            // Shorten the path to the expected length.
+            if expected - length < d {
+                self.points[i + 1] = self.points[i].add(diff.scale((expected - length) / d));
+                self.points.truncate(i + 2);
+
+                length = expected;
+                self.cumulative_length.push(length);
+                break;
+            }
+
+            length += d;
+            self.cumulative_length.push(length);
+            i += 1;
+        }
+
+        // Lengthen the path, following the direction of its last segment.
+        if let Some(expected) = expected_distance {
+            let count = self.points.len();
+            if length < expected && count > 1 {
+                let diff = self.points[count - 1].sub(self.points[count - 2]);
+                let d = diff.length();
+
+                if d <= 0.0 {
+                    return;
+                }
+
+                self.points[count - 1] =
+                    self.points[count - 1].add(diff.scale((expected - length) / d));
+                self.cumulative_length[count - 1] = expected;
+            }
+        }
+    }
+}
+
+fn calculate_subpath(curve_type: CurveType, points: &[Position]) -> Vec<Position> {
+    match curve_type {
+        CurveType::Linear => points.to_vec(),
+        CurveType::PerfectCurve if points.len() == 3 => {
+            let arc = approximate_circular_arc(points);
+            if arc.is_empty() {
+                approximate_bezier(points)
+            } else {
+                arc
+            }
+        }
+        CurveType::Catmull => approximate_catmull(points),
+        _ => approximate_bezier(points),
+    }
+}
+
+fn is_flat_enough(points: &[Position]) -> bool {
+    for i in 1..points.len() - 1 {
+        let p = points[i - 1].sub(points[i].scale(2.0)).add(points[i + 1]);
+        if p.length_squared() > BEZIER_TOLERANCE * BEZIER_TOLERANCE * 4.0 {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Subdivides a bezier curve in two halves, `left` and `right`.
+fn subdivide(
+    points: &[Position],
+    left: &mut [Position],
+    right: &mut [Position],
+    midpoints: &mut [Position],
+) {
+    let count = points.len();
+    midpoints[..count].copy_from_slice(points);
+
+    for i in 0..count {
+        left[i] = midpoints[0];
+        right[count - i - 1] = midpoints[count - i - 1];
+
+        for j in 0..count - i - 1 {
+            midpoints[j] = midpoints[j].add(midpoints[j + 1]).scale(0.5);
+        }
+    }
+}
+
+fn approximate(points: &[Position], output: &mut Vec<Position>) {
+    let count = points.len();
+    let mut left = vec![Position::default(); count * 2 - 1];
+    let mut right = vec![Position::default(); count];
+    let mut midpoints = vec![Position::default(); count];
+
+    subdivide(points, &mut left, &mut right, &mut midpoints);
+
+    for i in 0..count - 1 {
+        left[count + i] = right[i + 1];
+    }
+
+    output.push(points[0]);
+
+    for i in 1..count - 1 {
+        let index = 2 * i;
+        let p = left[index - 1]
+            .add(left[index].scale(2.0))
+            .add(left[index + 1])
+            .scale(0.25);
+        output.push(p);
+    }
+}
+
+fn approximate_bezier(points: &[Position]) -> Vec<Position> {
+    let mut output = Vec::new();
+    let count = points.len();
+
+    if count == 0 {
+        return output;
+    }
+
+    let mut midpoints = vec![Position::default(); count];
+    let mut to_flatten = vec![points.to_vec()];
+
+    while let Some(mut parent) = to_flatten.pop() {
+        if is_flat_enough(&parent) {
+            approximate(&parent, &mut output);
+            continue;
+        }
+
+        let mut left = vec![Position::default(); count];
+        let mut right = vec![Position::default(); count];
+        subdivide(&parent, &mut left, &mut right, &mut midpoints);
+
+        parent.copy_from_slice(&left);
+
+        to_flatten.push(right);
+        to_flatten.push(parent);
+    }
+
+    output.push(points[count - 1]);
+    output
+}
+
+fn catmull_find_point(v1: Position, v2: Position, v3: Position, v4: Position, t: f64) -> Position {
+    let t2 = t * t;
+    let t3 = t * t2;
+
+    let component = |p1: f64, p2: f64, p3: f64, p4: f64| {
+        0.5 * (2.0 * p2
+            + (-p1 + p3) * t
+            + (2.0 * p1 - 5.0 * p2 + 4.0 * p3 - p4) * t2
+            + (-p1 + 3.0 * p2 - 3.0 * p3 + p4) * t3)
+    };
+
+    Position::new(
+        component(v1.x, v2.x, v3.x, v4.x),
+        component(v1.y, v2.y, v3.y, v4.y),
+    )
+}
+
+fn approximate_catmull(points: &[Position]) -> Vec<Position> {
+    let mut output = Vec::new();
+    let len = points.len();
+
+    for i in 0..len.saturating_sub(1) {
+        let v1 = if i > 0 { points[i - 1] } else { points[i] };
+        let v2 = points[i];
+        let v3 = if i < len - 1 {
+            points[i + 1]
+        } else {
+            v2.scale(2.0).sub(v1)
+        };
+        let v4 = if i < len - 2 {
+            points[i + 2]
+        } else {
+            v3.scale(2.0).sub(v2)
+        };
+
+        for c in 0..CATMULL_DETAIL {
+            let detail = CATMULL_DETAIL as f64;
+            output.push(catmull_find_point(v1, v2, v3, v4, c as f64 / detail));
+            output.push(catmull_find_point(v1, v2, v3, v4, (c + 1) as f64 / detail));
+        }
+    }
+
+    output
+}
+
+fn approximate_circular_arc(points: &[Position]) -> Vec<Position> {
+    let a = points[0];
+    let b = points[1];
+    let c = points[2];
+
+    let a_sq = b.sub(c).length_squared();
+    let b_sq = a.sub(c).length_squared();
+    let c_sq = a.sub(b).length_squared();
+
+    // The points are too close to each other, fall back to a bezier.
+    if a_sq.abs() < 1e-3 || b_sq.abs() < 1e-3 || c_sq.abs() < 1e-3 {
+        return Vec::new();
+    }
+
+    let s = a_sq * (b_sq + c_sq - a_sq);
+    let t = b_sq * (a_sq + c_sq - b_sq);
+    let u = c_sq * (a_sq + b_sq - c_sq);
+    let sum = s + t + u;
+
+    if sum.abs() < 1e-3 {
+        return Vec::new();
+    }
+
+    let centre = a.scale(s).add(b.scale(t)).add(c.scale(u)).scale(1.0 / sum);
+    let d_a = a.sub(centre);
+    let d_c = c.sub(centre);
+
+    let r = d_a.length();
+
+    let theta_start = d_a.y.atan2(d_a.x);
+    let mut theta_end = d_c.y.atan2(d_c.x);
+
+    while theta_end < theta_start {
+        theta_end += 2.0 * PI;
+    }
+
+    let mut dir = 1.0;
+    let mut theta_range = theta_end - theta_start;
+
+    // Decide which way the arc goes, based on which side of AC the point B is.
+    let ortho_a_to_c = Position::new(c.y - a.y, -(c.x - a.x));
+    if ortho_a_to_c.dot(b.sub(a)) < 0.0 {
+        dir = -dir;
+        theta_range = 2.0 * PI - theta_range;
+    }
+
+    let amount_points = if 2.0 * r <= CIRCULAR_ARC_TOLERANCE {
+        2
+    } else {
+        let step = 2.0 * (1.0 - CIRCULAR_ARC_TOLERANCE / r).acos();
+        ((theta_range / step).ceil() as usize).max(2)
+    };
+
+    (0..amount_points)
+        .map(|i| {
+            let fract = i as f64 / (amount_points - 1) as f64;
+            let theta = theta_start + dir * fract * theta_range;
+            centre.add(Position::new(theta.cos(), theta.sin()).scale(r))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_linear_path() {
+        let points = [Position::new(0.0, 0.0), Position::new(100.0, 0.0)];
+        let path = SliderPath::new(CurveType::Linear, &points, Some(50.0));
+
+        assert_eq!(path.distance(), 50.0);
+        assert_eq!(path.position_at(1.0), Position::new(50.0, 0.0));
+        assert_eq!(path.position_at(0.5), Position::new(25.0, 0.0));
+    }
+
+    #[test]
+    fn test_perfect_curve() {
+        // A half circle with radius 50.
+        let points = [
+            Position::new(0.0, 0.0),
+            Position::new(50.0, 50.0),
+            Position::new(100.0, 0.0),
+        ];
+        let path = SliderPath::new(CurveType::PerfectCurve, &points, None);
+
+        assert!((path.distance() - 50.0 * PI).abs() < 1.0);
+        assert!(path.position_at(0.5).distance(Position::new(50.0, 50.0)) < 1.0);
+    }
+}
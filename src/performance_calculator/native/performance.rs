@@ -0,0 +1,233 @@
+//! osu!standard performance (PP) calculation, following osu!lazer's
+//! `OsuPerformanceCalculator`.
+use super::difficulty::DifficultyAttributes;
+use crate::performance_calculator::{HitResults, Mod, Mods, Ruleset};
+
+/// The PP of a play, and its aim, speed and accuracy components.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PerformanceAttributes {
+    pub pp: f64,
+    pub aim: f64,
+    pub speed: f64,
+    pub accuracy: f64,
+}
+
+/// The bonus given to longer beatmaps.
+fn length_bonus(total_hits: f64) -> f64 {
+    0.95 + 0.4 * (total_hits / 2000.0).min(1.0)
+        + if total_hits > 2000.0 {
+            (total_hits / 2000.0).log10() * 0.5
+        } else {
+            0.0
+        }
+}
+
+/// Scales a strain value into PP.
+fn strain_to_pp(strain: f64) -> f64 {
+    (5.0 * (strain / 0.0675).max(1.0) - 4.0).powi(3) / 100000.0
+}
+
+/// Calculates the PP of a play with `hits` and `combo`, under `mods`, on a
+/// beatmap with the given difficulty `attributes`.
+pub fn calculate_performance(
+    attributes: &DifficultyAttributes,
+    mods: &Mods,
+    hits: &HitResults,
+    combo: usize,
+) -> PerformanceAttributes {
+    let mut multiplier = 1.12;
+
+    if mods.contains(&Mod::NF) {
+        multiplier *= 0.90;
+    }
+
+    if mods.contains(&Mod::SO) {
+        multiplier *= 0.95;
+    }
+
+    let aim = aim_value(attributes, mods, hits, combo);
+    let speed = speed_value(attributes, mods, hits, combo);
+    let accuracy = accuracy_value(attributes, mods, hits);
+
+    let pp = (aim.powf(1.1) + speed.powf(1.1) + accuracy.powf(1.1)).powf(1.0 / 1.1) * multiplier;
+
+    PerformanceAttributes {
+        pp: pp,
+        aim: aim,
+        speed: speed,
+        accuracy: accuracy,
+    }
+}
+
+/// The multiplier applied to aim and speed, based on the combo reached.
+fn combo_scaling(attributes: &DifficultyAttributes, combo: usize) -> f64 {
+    if attributes.max_combo > 0 {
+        ((combo as f64).powf(0.8) / (attributes.max_combo as f64).powf(0.8)).min(1.0)
+    } else {
+        1.0
+    }
+}
+
+fn aim_value(
+    attributes: &DifficultyAttributes,
+    mods: &Mods,
+    hits: &HitResults,
+    combo: usize,
+) -> f64 {
+    let total_hits = hits.total() as f64;
+
+    let mut raw_aim = attributes.aim_strain;
+    if mods.contains(&Mod::TD) {
+        raw_aim = raw_aim.powf(0.8);
+    }
+
+    let mut aim = strain_to_pp(raw_aim);
+
+    aim *= length_bonus(total_hits);
+    aim *= 0.97f64.powi(hits.miss as i32);
+    aim *= combo_scaling(attributes, combo);
+
+    let ar = attributes.approach_rate;
+    let mut approach_rate_factor = 1.0;
+    if ar > 10.33 {
+        approach_rate_factor += 0.3 * (ar - 10.33);
+    } else if ar < 8.0 {
+        approach_rate_factor += 0.01 * (8.0 - ar);
+    }
+
+    aim *= approach_rate_factor;
+
+    if mods.contains(&Mod::HD) {
+        aim *= 1.0 + 0.04 * (12.0 - ar);
+    }
+
+    if mods.contains(&Mod::FL) {
+        aim *= 1.0
+            + 0.35 * (total_hits / 200.0).min(1.0)
+            + if total_hits > 200.0 {
+                0.3 * ((total_hits - 200.0) / 300.0).min(1.0)
+                    + if total_hits > 500.0 {
+                        (total_hits - 500.0) / 1200.0
+                    } else {
+                        0.0
+                    }
+            } else {
+                0.0
+            };
+    }
+
+    aim *= 0.5 + hits.accuracy(Ruleset::Osu) / 2.0;
+    aim *= 0.98 + attributes.overall_difficulty.powi(2) / 2500.0;
+
+    aim
+}
+
+fn speed_value(
+    attributes: &DifficultyAttributes,
+    mods: &Mods,
+    hits: &HitResults,
+    combo: usize,
+) -> f64 {
+    let mut speed = strain_to_pp(attributes.speed_strain);
+
+    speed *= length_bonus(hits.total() as f64);
+    speed *= 0.97f64.powi(hits.miss as i32);
+    speed *= combo_scaling(attributes, combo);
+
+    let ar = attributes.approach_rate;
+    if ar > 10.33 {
+        speed *= 1.0 + 0.3 * (ar - 10.33);
+    }
+
+    if mods.contains(&Mod::HD) {
+        speed *= 1.0 + 0.04 * (12.0 - ar);
+    }
+
+    speed *= 0.02 + hits.accuracy(Ruleset::Osu);
+    speed *= 0.96 + attributes.overall_difficulty.powi(2) / 1600.0;
+
+    speed
+}
+
+fn accuracy_value(attributes: &DifficultyAttributes, mods: &Mods, hits: &HitResults) -> f64 {
+    // Only circles are taken into account, since sliders and spinners give
+    // 300s way too easily.
+    let circles = attributes.circle_count as f64;
+    let total_hits = hits.total() as f64;
+
+    let better_accuracy = if circles > 0.0 {
+        (((hits.great as f64 - (total_hits - circles)) * 6.0
+            + hits.good as f64 * 2.0
+            + hits.meh as f64)
+            / (circles * 6.0))
+            .max(0.0)
+    } else {
+        0.0
+    };
+
+    let mut accuracy =
+        1.52163f64.powf(attributes.overall_difficulty) * better_accuracy.powi(24) * 2.83;
+
+    accuracy *= (circles / 1000.0).powf(0.3).min(1.15);
+
+    if mods.contains(&Mod::HD) {
+        accuracy *= 1.08;
+    }
+
+    if mods.contains(&Mod::FL) {
+        accuracy *= 1.02;
+    }
+
+    accuracy
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accuracy() {
+        let hits = HitResults {
+            great: 98,
+            good: 1,
+            meh: 0,
+            miss: 1,
+        };
+
+        assert!((hits.accuracy(Ruleset::Osu) - (98.0 * 300.0 + 100.0) / 30000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_misses_reduce_pp() {
+        let attributes = DifficultyAttributes {
+            star_rating: 5.0,
+            aim_strain: 2.5,
+            speed_strain: 2.3,
+            approach_rate: 9.0,
+            overall_difficulty: 8.0,
+            max_combo: 1000,
+            circle_count: 500,
+            object_count: 700,
+        };
+
+        let fc = HitResults {
+            great: 690,
+            good: 10,
+            meh: 0,
+            miss: 0,
+        };
+        let one_miss = HitResults {
+            great: 689,
+            good: 10,
+            meh: 0,
+            miss: 1,
+        };
+
+        let mods = Mods::default();
+        let fc_pp = calculate_performance(&attributes, &mods, &fc, 1000).pp;
+        let miss_pp = calculate_performance(&attributes, &mods, &one_miss, 600).pp;
+
+        assert!(fc_pp > 0.0);
+        assert!(miss_pp < fc_pp);
+    }
+}
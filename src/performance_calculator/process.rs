@@ -0,0 +1,163 @@
+//! Helpers for running external calculator processes, that may hang.
+//!
+//! Processes are spawned into their own process group (on Unix), so that
+//! killing them also kills anything they've spawned themselves.
+use super::CalculationError;
+use std::io::{self, Read};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often `output_with_timeout` checks if the process has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Spawns `cmd` as the leader of a new process group.
+pub(super) fn spawn_process_group(cmd: &mut Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    cmd.spawn()
+}
+
+/// Kills `child` and its whole process group, and waits for it to exit.
+pub(super) fn kill_process_tree(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Reads everything from `source` on a separate thread, which sends it over
+/// the returned channel once it's done.
+fn read_to_end_async<R: Read + Send + 'static>(source: Option<R>) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut source) = source {
+            let _ = source.read_to_end(&mut buf);
+        }
+        let _ = sender.send(buf);
+    });
+
+    receiver
+}
+
+/// Waits for the output of a `read_to_end_async` reader until `deadline`.
+fn recv_until(reader: &Receiver<Vec<u8>>, deadline: Instant) -> Option<Vec<u8>> {
+    let now = Instant::now();
+    let timeout = if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    };
+
+    reader.recv_timeout(timeout).ok()
+}
+
+/// Like `Command::output()`, but kills the process tree if it doesn't exit
+/// (and close its output) within `timeout`.
+///
+/// # Errors
+///
+/// Will return `CalculationError::Timeout` if the process timed out, or
+/// `CalculationError::Io` if it couldn't be spawned or waited for.
+pub(super) fn output_with_timeout(
+    cmd: &mut Command,
+    timeout: Duration,
+) -> Result<Output, CalculationError> {
+    let mut child = spawn_process_group(
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+
+    let stdout = read_to_end_async(child.stdout.take());
+    let stderr = read_to_end_async(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            kill_process_tree(&mut child);
+
+            return Err(CalculationError::Timeout);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    // Processes that left the group may still have the pipes open after the
+    // process exits, so its output is only waited for until the deadline.
+    match (recv_until(&stdout, deadline), recv_until(&stderr, deadline)) {
+        (Some(stdout), Some(stderr)) => Ok(Output {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+        }),
+        _ => {
+            kill_process_tree(&mut child);
+
+            Err(CalculationError::Timeout)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_with_timeout() {
+        let output =
+            output_with_timeout(Command::new("echo").arg("hello"), Duration::from_secs(5)).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "hello");
+    }
+
+    #[test]
+    fn test_output_timed_out() {
+        let start = Instant::now();
+        // The inner sleep is a grandchild, and should be killed as well.
+        let result = output_with_timeout(
+            Command::new("sh").args(&["-c", "sleep 30; true"]),
+            Duration::from_millis(100),
+        );
+
+        assert_eq!(result.unwrap_err(), CalculationError::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_output_held_open() {
+        let start = Instant::now();
+        // The process exits right away, but leaves a process that keeps its
+        // stdout open on another session.
+        let result = output_with_timeout(
+            Command::new("sh").args(&["-c", "setsid sleep 30 & echo hello"]),
+            Duration::from_millis(200),
+        );
+
+        assert_eq!(result.unwrap_err(), CalculationError::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
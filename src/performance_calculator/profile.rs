@@ -1,12 +1,14 @@
-//! A interface for PerformanceCalculator.dll's `profile` command.
+//! Profile calculation results, and the entry point for calculating them.
 //!
 //! The principal function of this module is `calculate_profile`, which
-//! calls into PerformanceCalculator.
-use super::{Mod, UnsuccessfulCommandError};
-use crate::config_functions::{api_key, dotnet_command, performance_calculator_path};
-use std::collections::BTreeSet;
-use std::error::Error;
-use std::process::Command;
+//! calls into a `PerformanceBackend`. Profiles can also be put together from
+//! scores simulated elsewhere, with `ProfileResults::from_scores`.
+use super::{CalculationError, Mods, PerformanceBackend, Ruleset};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// On profile totals, each score is worth this much of the one before it.
+const SCORE_WEIGHT: f64 = 0.95;
 
 /// A single play, with both live (old) and local (new) PP results.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,7 +18,7 @@ pub struct Score {
     #[serde(alias = "BeatmapName")]
     beatmap_name: String,
     #[serde(alias = "Mods")]
-    mods: BTreeSet<Mod>, // Use BTreeSet for ordering, makes displaying them as a string easier.
+    mods: Mods,
     #[serde(alias = "Accuracy")]
     accuracy: f64,
     #[serde(alias = "LivePP")]
@@ -29,6 +31,77 @@ pub struct Score {
     position_change: i64,
 }
 
+impl Score {
+    /// A score worth `live_pp` on the live PP system, and `local_pp` on the new
+    /// one. Its position change is only known once it's on a profile.
+    pub fn new(
+        beatmap_id: i64,
+        beatmap_name: String,
+        mods: Mods,
+        accuracy: f64,
+        live_pp: f64,
+        local_pp: f64,
+    ) -> Self {
+        Score {
+            beatmap_id: beatmap_id,
+            beatmap_name: beatmap_name,
+            mods: mods,
+            accuracy: accuracy,
+            live_pp: live_pp,
+            local_pp: local_pp,
+            pp_change: local_pp - live_pp,
+            position_change: 0,
+        }
+    }
+}
+
+/// The bonus PP osu! gives for having `score_count` ranked scores.
+pub fn bonus_pp(score_count: usize) -> f64 {
+    416.6667 * (1.0 - 0.9994f64.powi(score_count as i32))
+}
+
+/// The total of `pps`, sorted from highest to lowest, with each one weighted
+/// by its position.
+fn weighted_total<I: Iterator<Item = f64>>(pps: I) -> f64 {
+    pps.enumerate()
+        .map(|(position, pp)| pp * SCORE_WEIGHT.powi(position as i32))
+        .sum()
+}
+
+/// The indices of `scores`, sorted by `pp` from highest to lowest.
+fn order_by<F: Fn(&Score) -> f64>(scores: &[Score], pp: F) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| {
+        pp(&scores[*b])
+            .partial_cmp(&pp(&scores[*a]))
+            .unwrap_or(Ordering::Equal)
+    });
+
+    order
+}
+
+/// The best score (by `pp`) of each beatmap on `scores`, in the order the
+/// beatmaps first appear.
+fn best_per_beatmap<F: Fn(&Score) -> f64>(scores: Vec<Score>, pp: F) -> Vec<Score> {
+    let mut best: Vec<Score> = Vec::new();
+    let mut positions: HashMap<i64, usize> = HashMap::new();
+    for score in scores {
+        match positions.get(&score.beatmap_id) {
+            Some(&position) => {
+                if pp(&score) > pp(&best[position]) {
+                    best[position] = score;
+                }
+            }
+            None => {
+                positions.insert(score.beatmap_id, best.len());
+                best.push(score);
+            }
+        }
+    }
+
+    best
+}
+
 /// The result of a PP calculation for a osu! profile. Contains the list of
 /// scores (from the user actual top 100 plays), ordered by local (new) PP.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,51 +118,144 @@ pub struct ProfileResults {
     scores: Vec<Score>,
 }
 
-/// Parses the output from PerformanceCalculator (`raw_results`) into a ProfileResults struct.
-///
-/// # Errors
-///
-/// Will error if `raw_results` can't be parsed into a valid `ProfileResults`.
-fn parse_profile_results(raw_results: String) -> Result<ProfileResults, Box<Error>> {
-    Ok(serde_json::from_str(raw_results.as_str())?)
-}
+impl ProfileResults {
+    /// The beatmaps of every score, in order.
+    pub fn beatmap_ids(&self) -> Vec<i64> {
+        self.scores.iter().map(|score| score.beatmap_id).collect()
+    }
 
-/// Calculates the new PP system scores for a osu! user profile. `user`, preferably, should
-/// be a user id, but it can also be the user name.
-pub fn calculate_profile(user: String) -> Result<ProfileResults, Box<Error>> {
-    let output = Command::new(dotnet_command())
-        .arg(performance_calculator_path())
-        .arg("profile")
-        .arg(user)
-        .arg(api_key())
-        .arg("--json")
-        //.current_dir(Path::new(PERFORMANCE_CALCULATOR_PATH).parent().unwrap())
-        .output()?;
+    /// Puts together the profile of `user` from `scores`, the way the osu!
+    /// site would: only the best score of each beatmap counts, scores are
+    /// sorted by local PP, moving up or down from their position by live PP,
+    /// and both totals are weighted by position, plus `bonus_pp`. If no score
+    /// has any live PP, there are no positions to move from, so every position
+    /// change is 0.
+    pub fn from_scores(user: String, scores: Vec<Score>, bonus_pp: f64) -> Self {
+        // The live and the local totals each count the best score of every
+        // beatmap by their own PP, which aren't always the same score.
+        let best_live = best_per_beatmap(scores.clone(), |score| score.live_pp);
+        let total_live_pp = weighted_total(
+            order_by(&best_live, |score| score.live_pp)
+                .into_iter()
+                .map(|i| best_live[i].live_pp),
+        );
 
-    if output.status.success() {
-        let raw = String::from_utf8_lossy(&output.stdout).to_string();
+        let scores = best_per_beatmap(scores, |score| score.local_pp);
+        let has_live_pp = scores.iter().any(|score| score.live_pp != 0.0);
 
-        Ok(parse_profile_results(raw)?)
-    } else {
-        let raw = String::from_utf8_lossy(&output.stdout).to_string();
+        let live_order = order_by(&scores, |score| score.live_pp);
+        let mut live_positions = vec![0; scores.len()];
+        for (position, index) in live_order.iter().enumerate() {
+            live_positions[*index] = position;
+        }
 
-        println!("calculate_profile failed! output: {}", raw);
+        let scores: Vec<Score> = order_by(&scores, |score| score.local_pp)
+            .into_iter()
+            .enumerate()
+            .map(|(position, index)| Score {
+                position_change: if has_live_pp {
+                    live_positions[index] as i64 - position as i64
+                } else {
+                    0
+                },
+                ..scores[index].clone()
+            })
+            .collect();
+        let total_local_pp = weighted_total(scores.iter().map(|score| score.local_pp));
 
-        Err(Box::new(UnsuccessfulCommandError))
+        ProfileResults {
+            user: user,
+            total_live_pp: total_live_pp + bonus_pp,
+            total_bonus_pp: bonus_pp,
+            total_local_pp: total_local_pp + bonus_pp,
+            scores: scores,
+        }
     }
 }
 
+/// Calculates the new PP system scores for a osu! user profile on `ruleset`, using
+/// `backend`. `user`, preferably, should be a user id, but it can also be the user name.
+pub fn calculate_profile(
+    backend: &dyn PerformanceBackend,
+    user: String,
+    ruleset: Ruleset,
+) -> Result<ProfileResults, CalculationError> {
+    backend.calculate_profile(&user, ruleset)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config_functions::{
+        dotnet_command, performance_calculator_path, profile_timeout, simulate_timeout,
+    };
+    use crate::performance_calculator::{DotnetBackend, Timeouts};
+
+    #[test]
+    fn test_from_scores() {
+        let score = |beatmap_id, live_pp, local_pp| {
+            Score::new(
+                beatmap_id,
+                format!("Beatmap {}", beatmap_id),
+                Mods::default(),
+                99.0,
+                live_pp,
+                local_pp,
+            )
+        };
+        let scores = vec![
+            score(1, 300.0, 250.0),
+            score(2, 200.0, 280.0),
+            score(3, 100.0, 100.0),
+        ];
+
+        let results = ProfileResults::from_scores("someone".to_string(), scores, 10.0);
+        assert_eq!(results.beatmap_ids(), vec![2, 1, 3]);
+        let changes: Vec<i64> = results.scores.iter().map(|s| s.position_change).collect();
+        assert_eq!(changes, vec![1, -1, 0]);
+        assert_eq!(results.scores[0].pp_change, 80.0);
+
+        let expected_live = 300.0 + 200.0 * 0.95 + 100.0 * 0.95 * 0.95 + 10.0;
+        let expected_local = 280.0 + 250.0 * 0.95 + 100.0 * 0.95 * 0.95 + 10.0;
+        assert!((results.total_live_pp - expected_live).abs() < 1e-9);
+        assert!((results.total_local_pp - expected_local).abs() < 1e-9);
+        assert_eq!(results.total_bonus_pp, 10.0);
+
+        // Only the best score of a beatmap counts.
+        let scores = vec![
+            score(1, 300.0, 250.0),
+            score(2, 200.0, 280.0),
+            score(1, 100.0, 260.0),
+        ];
+        let results = ProfileResults::from_scores("someone".to_string(), scores, 0.0);
+        assert_eq!(results.beatmap_ids(), vec![2, 1]);
+        assert_eq!(results.scores[1].live_pp, 100.0);
+        // But the live total counts the best live score, which isn't the same.
+        let expected_live = 300.0 + 200.0 * 0.95;
+        assert!((results.total_live_pp - expected_live).abs() < 1e-9);
+
+        // Without live PP, no score moves.
+        let scores = vec![score(1, 0.0, 100.0), score(2, 0.0, 200.0)];
+        let results = ProfileResults::from_scores("someone".to_string(), scores, 0.0);
+        assert_eq!(results.beatmap_ids(), vec![2, 1]);
+        assert!(results.scores.iter().all(|s| s.position_change == 0));
+
+        assert_eq!(bonus_pp(0), 0.0);
+        assert!((bonus_pp(100000) - 416.6667).abs() < 1e-3);
+    }
 
     // Calculate a few profiles, just to be sure everything is OK.
     #[test]
     fn test_calculate_profiles() {
+        let timeouts = Timeouts {
+            profile: profile_timeout(),
+            simulate: simulate_timeout(),
+        };
+        let backend = DotnetBackend::new(dotnet_command(), performance_calculator_path(), timeouts);
         let players = vec!["rafis", "mathi", "yeahbennou", "freedomdiver"];
 
         for player in players {
-            let result = calculate_profile(player.to_string());
+            let result = calculate_profile(&backend, player.to_string(), Ruleset::Osu);
 
             if let Err(e) = result {
                 panic!("calculate_profile for {} failed! {}", player, e);
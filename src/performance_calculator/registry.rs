@@ -0,0 +1,199 @@
+//! A registry of named calculators, so that several rebalance proposals can
+//! be evaluated side by side.
+//!
+//! There's always a calculator named `DEFAULT_CALCULATOR`, which is used
+//! whenever a request doesn't ask for a specific one. Extra PerformanceCalculator
+//! builds can be listed in a JSON file (see `load_builds`), like:
+//!
+//! ```json
+//! [{ "name": "aim-rework", "label": "Aim rework", "path": "/opt/aim-rework/PerformanceCalculator.dll" }]
+//! ```
+//!
+//! One of the calculators can be marked as the live PP system (see
+//! `CalculatorRegistry::set_live`), so simulations can be compared against it.
+use super::{CalculationError, PerformanceBackend};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// The name of the calculator used when none is specified.
+pub const DEFAULT_CALCULATOR: &str = "default";
+
+/// A PerformanceCalculator build, as described in the calculators file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalculatorBuild {
+    pub name: String,
+    pub label: String,
+    pub path: String,
+}
+
+/// The public information about a registered calculator.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CalculatorInfo {
+    pub name: String,
+    pub label: String,
+}
+
+/// The registered calculators, and their backends.
+pub struct CalculatorRegistry {
+    calculators: Vec<(CalculatorInfo, Arc<dyn PerformanceBackend>)>,
+    /// The name of the calculator that stands for the live PP system.
+    live: Option<String>,
+}
+
+impl CalculatorRegistry {
+    /// Creates a new registry, with `backend` registered as `DEFAULT_CALCULATOR`,
+    /// under `label`.
+    pub fn new(label: String, backend: Arc<dyn PerformanceBackend>) -> Self {
+        let mut registry = CalculatorRegistry {
+            calculators: Vec::new(),
+            live: None,
+        };
+
+        registry.register(DEFAULT_CALCULATOR.to_string(), label, backend);
+        registry
+    }
+
+    /// Registers `backend` as `name`, replacing any calculator previously
+    /// registered with the same name.
+    pub fn register(&mut self, name: String, label: String, backend: Arc<dyn PerformanceBackend>) {
+        self.calculators.retain(|(info, _)| info.name != name);
+        self.calculators.push((
+            CalculatorInfo {
+                name: name,
+                label: label,
+            },
+            backend,
+        ));
+    }
+
+    /// Gets the backend registered as `name`, if any.
+    pub fn get(&self, name: &str) -> Option<Arc<dyn PerformanceBackend>> {
+        self.calculators
+            .iter()
+            .find(|(info, _)| info.name == name)
+            .map(|(_, backend)| backend.clone())
+    }
+
+    /// Resolves the calculator a request asked for, falling back to
+    /// `DEFAULT_CALCULATOR` if it didn't ask for any. Returns the information
+    /// about the calculator, and its backend.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::UnknownCalculator` if there's no
+    /// calculator registered as `name`.
+    pub fn resolve(
+        &self,
+        name: Option<String>,
+    ) -> Result<(CalculatorInfo, Arc<dyn PerformanceBackend>), CalculationError> {
+        let name = match name {
+            Some(ref name) if !name.is_empty() => name.to_lowercase(),
+            _ => DEFAULT_CALCULATOR.to_string(),
+        };
+
+        self.calculators
+            .iter()
+            .find(|(info, _)| info.name == name)
+            .map(|(info, backend)| (info.clone(), backend.clone()))
+            .ok_or(CalculationError::UnknownCalculator(name))
+    }
+
+    /// Marks the calculator registered as `name` as the live PP system.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::UnknownCalculator` if there's no
+    /// calculator registered as `name`.
+    pub fn set_live(&mut self, name: &str) -> Result<(), CalculationError> {
+        let name = name.to_lowercase();
+        if self.get(&name).is_none() {
+            return Err(CalculationError::UnknownCalculator(name));
+        }
+
+        self.live = Some(name);
+        Ok(())
+    }
+
+    /// The calculator that stands for the live PP system, if one was set.
+    pub fn live(&self) -> Option<(CalculatorInfo, Arc<dyn PerformanceBackend>)> {
+        let name = self.live.as_ref()?;
+
+        self.resolve(Some(name.clone())).ok()
+    }
+
+    /// All registered calculators, in registration order.
+    pub fn calculators(&self) -> Vec<CalculatorInfo> {
+        self.calculators
+            .iter()
+            .map(|(info, _)| info.clone())
+            .collect()
+    }
+}
+
+/// Loads the list of extra PerformanceCalculator builds from `builds_file`.
+/// Names are lowercased, as requests are matched case-insensitively.
+///
+/// # Errors
+///
+/// Will error if `builds_file` couldn't be opened, or isn't a valid list of builds.
+pub fn load_builds(builds_file: &str) -> Result<Vec<CalculatorBuild>, Box<Error>> {
+    let reader = BufReader::new(File::open(builds_file)?);
+    let builds: Vec<CalculatorBuild> = serde_json::from_reader(reader)?;
+
+    Ok(builds
+        .into_iter()
+        .map(|build| CalculatorBuild {
+            name: build.name.to_lowercase(),
+            ..build
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::performance_calculator::NativeBackend;
+
+    #[test]
+    fn test_resolve() {
+        let mut registry =
+            CalculatorRegistry::new("Default".to_string(), Arc::new(NativeBackend::new()));
+        registry.register(
+            "proposal".to_string(),
+            "Some proposal".to_string(),
+            Arc::new(NativeBackend::new()),
+        );
+
+        let name = |requested: Option<&str>| {
+            let (info, _) = registry.resolve(requested.map(str::to_string)).unwrap();
+            info.name
+        };
+
+        assert_eq!(name(None), DEFAULT_CALCULATOR);
+        assert_eq!(name(Some("")), DEFAULT_CALCULATOR);
+        assert_eq!(name(Some("Proposal")), "proposal");
+        assert_eq!(
+            registry.resolve(Some("nope".to_string())).err(),
+            Some(CalculationError::UnknownCalculator("nope".to_string()))
+        );
+
+        let names: Vec<_> = registry.calculators().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, vec![DEFAULT_CALCULATOR, "proposal"]);
+    }
+
+    #[test]
+    fn test_live() {
+        let mut registry =
+            CalculatorRegistry::new("Default".to_string(), Arc::new(NativeBackend::new()));
+        assert!(registry.live().is_none());
+
+        assert_eq!(
+            registry.set_live("nope"),
+            Err(CalculationError::UnknownCalculator("nope".to_string()))
+        );
+        registry.set_live("Default").unwrap();
+        assert_eq!(registry.live().unwrap().0.name, DEFAULT_CALCULATOR);
+    }
+}
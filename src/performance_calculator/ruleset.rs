@@ -0,0 +1,142 @@
+//! The osu! rulesets (game modes).
+use super::CalculationError;
+use std::fmt;
+use std::str::FromStr;
+
+/// A osu! ruleset. Serialized as its short name, the same one osu-tools'
+/// `simulate` command uses.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ruleset {
+    Osu,
+    Taiko,
+    Catch,
+    Mania,
+}
+
+impl Default for Ruleset {
+    fn default() -> Self {
+        Ruleset::Osu
+    }
+}
+
+impl Ruleset {
+    /// All rulesets, ordered by id.
+    pub fn all() -> [Ruleset; 4] {
+        [Ruleset::Osu, Ruleset::Taiko, Ruleset::Catch, Ruleset::Mania]
+    }
+
+    /// The id of this ruleset, as used by the osu! api and .osu files.
+    pub fn id(&self) -> u8 {
+        match *self {
+            Ruleset::Osu => 0,
+            Ruleset::Taiko => 1,
+            Ruleset::Catch => 2,
+            Ruleset::Mania => 3,
+        }
+    }
+
+    /// The ruleset with this `id`, if any.
+    pub fn from_id(id: u8) -> Option<Ruleset> {
+        Ruleset::all().iter().cloned().find(|r| r.id() == id)
+    }
+
+    /// Resolves the ruleset a request asked for, falling back to osu!standard
+    /// if it didn't ask for any.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::UnknownRuleset` if `name` isn't a ruleset.
+    pub fn resolve(name: Option<String>) -> Result<Ruleset, CalculationError> {
+        match name {
+            Some(ref name) if !name.is_empty() => Ok(name.parse()?),
+            _ => Ok(Ruleset::default()),
+        }
+    }
+
+    /// The short name of this ruleset, which is also the name of the
+    /// PerformanceCalculator `simulate` subcommand for it.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Ruleset::Osu => "osu",
+            Ruleset::Taiko => "taiko",
+            Ruleset::Catch => "catch",
+            Ruleset::Mania => "mania",
+        }
+    }
+
+    /// A human-readable name for this ruleset.
+    pub fn display_name(&self) -> &'static str {
+        match *self {
+            Ruleset::Osu => "osu!",
+            Ruleset::Taiko => "osu!taiko",
+            Ruleset::Catch => "osu!catch",
+            Ruleset::Mania => "osu!mania",
+        }
+    }
+}
+
+impl fmt::Display for Ruleset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// An error returned when parsing an unknown ruleset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownRulesetError(String);
+
+impl fmt::Display for UnknownRulesetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown ruleset {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownRulesetError {}
+
+impl From<UnknownRulesetError> for CalculationError {
+    fn from(e: UnknownRulesetError) -> Self {
+        CalculationError::UnknownRuleset(e.0)
+    }
+}
+
+impl FromStr for Ruleset {
+    type Err = UnknownRulesetError;
+
+    /// Parses either the short name of a ruleset ("osu", "taiko", "catch",
+    /// "mania", or "fruits"), or its id.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "osu" | "0" => Ok(Ruleset::Osu),
+            "taiko" | "1" => Ok(Ruleset::Taiko),
+            "catch" | "fruits" | "2" => Ok(Ruleset::Catch),
+            "mania" | "3" => Ok(Ruleset::Mania),
+            _ => Err(UnknownRulesetError(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ruleset() {
+        assert_eq!("taiko".parse(), Ok(Ruleset::Taiko));
+        assert_eq!("Fruits".parse(), Ok(Ruleset::Catch));
+        assert_eq!("3".parse(), Ok(Ruleset::Mania));
+        assert!("ctb2".parse::<Ruleset>().is_err());
+
+        assert_eq!(Ruleset::resolve(None), Ok(Ruleset::Osu));
+        assert_eq!(Ruleset::resolve(Some("".to_string())), Ok(Ruleset::Osu));
+        assert_eq!(
+            Ruleset::resolve(Some("ctb2".to_string())),
+            Err(CalculationError::UnknownRuleset("ctb2".to_string()))
+        );
+
+        for ruleset in Ruleset::all().iter() {
+            assert_eq!(Ruleset::from_id(ruleset.id()), Some(*ruleset));
+            assert_eq!(ruleset.name().parse(), Ok(*ruleset));
+        }
+    }
+}
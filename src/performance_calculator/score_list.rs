@@ -0,0 +1,386 @@
+//! Profiles calculated from a list of scores, instead of from the top plays
+//! the osu! api has for a user. This works for restricted accounts, private
+//! servers and test fixtures, none of which the api knows about.
+//!
+//! Lists are either JSON (an array of `ListedScore`), or CSV with a header row
+//! naming the same fields, like:
+//!
+//! ```text
+//! beatmap_id,mods,good,meh,misses,combo,live_pp
+//! 129891,HDDT,12,0,1,1500,512.3
+//! ```
+//!
+//! Mods are written without commas there, like `HDDT`. Every score is
+//! simulated with `simulate_play`, and the results are put together with
+//! `ProfileResults::from_scores`.
+use super::profile::{bonus_pp, ProfileResults, Score};
+use super::simulate::{simulate_play, SimulationParams, SimulationResults};
+use super::{Accuracy, CalculationError, Mods, PerformanceBackend, Ruleset};
+use crate::beatmap_cache::BeatmapCache;
+use serde_json::{Map, Number, Value};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// The most scores a list can have.
+pub const MAX_LISTED_SCORES: usize = 500;
+
+/// A score of a list. Hit counts and combo are the same as in `SimulationParams`;
+/// aliases make lists exported with osu! api (v1) names work, too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListedScore {
+    pub beatmap_id: i64,
+    #[serde(default)]
+    pub mods: Mods,
+    /// The 100s (goods in osu!taiko, droplets in osu!catch).
+    #[serde(default, alias = "count100")]
+    pub good: usize,
+    /// The 50s (tiny droplets in osu!catch).
+    #[serde(default, alias = "count50")]
+    pub meh: usize,
+    #[serde(default, alias = "countmiss")]
+    pub misses: usize,
+    /// A full combo, if missing.
+    #[serde(default, alias = "maxcombo")]
+    pub combo: Option<usize>,
+    /// Only for osu!mania scores, which are described by it.
+    #[serde(default)]
+    pub score: Option<u32>,
+    /// What the score is worth on the live PP system, if known.
+    #[serde(default, alias = "pp")]
+    pub live_pp: f64,
+}
+
+impl ListedScore {
+    /// The simulation of this score on `ruleset`.
+    pub fn params(&self, ruleset: Ruleset) -> SimulationParams {
+        let accuracy = match ruleset {
+            Ruleset::Mania => Accuracy::default(),
+            _ => Accuracy::Hits {
+                good: self.good,
+                meh: self.meh,
+            },
+        };
+
+        SimulationParams {
+            ruleset: ruleset,
+            accuracy: accuracy,
+            mods: self.mods.clone(),
+            combo: self.combo,
+            misses: Some(self.misses),
+            score: self.score,
+        }
+    }
+}
+
+/// Shorthand for an invalid `field`.
+fn invalid(field: &str, message: String) -> CalculationError {
+    CalculationError::InvalidParams {
+        field: field.to_string(),
+        message: message,
+    }
+}
+
+/// A CSV cell as a JSON value: a number if it looks like one, and a string
+/// otherwise.
+fn csv_value(cell: &str) -> Value {
+    if let Ok(integer) = cell.parse::<u64>() {
+        return Value::from(integer);
+    }
+
+    match cell.parse().ok().and_then(Number::from_f64) {
+        Some(number) => Value::Number(number),
+        None => Value::String(cell.trim_matches('"').to_string()),
+    }
+}
+
+/// Parses a CSV score list. Empty cells are left out, so their fields take
+/// their default values.
+fn parse_csv(contents: &str) -> Result<Vec<ListedScore>, CalculationError> {
+    let mut lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split(',').map(str::trim).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    lines
+        .enumerate()
+        .map(|(row, line)| {
+            let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+            if cells.len() != header.len() {
+                return Err(invalid(
+                    "scores",
+                    format!(
+                        "row {} has {} columns, but the header has {}",
+                        row + 1,
+                        cells.len(),
+                        header.len()
+                    ),
+                ));
+            }
+
+            let object: Map<String, Value> = header
+                .iter()
+                .zip(cells)
+                .filter(|(_, cell)| !cell.is_empty())
+                .map(|(name, cell)| (name.to_string(), csv_value(cell)))
+                .collect();
+
+            serde_json::from_value(Value::Object(object))
+                .map_err(|e| invalid("scores", format!("row {}: {}", row + 1, e)))
+        })
+        .collect()
+}
+
+/// Parses a score list, either JSON (if it's an array) or CSV.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` (for the `scores` field) if
+/// the list can't be parsed, is empty, or has more than `MAX_LISTED_SCORES`
+/// scores.
+pub fn parse_score_list(contents: &str) -> Result<Vec<ListedScore>, CalculationError> {
+    let scores = if contents.trim_start().starts_with('[') {
+        serde_json::from_str(contents).map_err(|e| invalid("scores", e.to_string()))?
+    } else {
+        parse_csv(contents)?
+    };
+
+    if scores.is_empty() {
+        return Err(invalid("scores", "should have some scores".to_string()));
+    }
+    if scores.len() > MAX_LISTED_SCORES {
+        return Err(invalid(
+            "scores",
+            format!("should have at most {} scores", MAX_LISTED_SCORES),
+        ));
+    }
+
+    Ok(scores)
+}
+
+/// A score that couldn't be simulated, and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedScore {
+    /// `None` for scores whose beatmap couldn't be found by its checksum.
+    pub beatmap_id: Option<i64>,
+    /// The checksum of the beatmap, if that's how the score refers to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beatmap_md5: Option<String>,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// A profile calculated from a score list, and the scores left out of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoreListResults {
+    pub profile: ProfileResults,
+    pub failed: Vec<FailedScore>,
+}
+
+/// The name of the beatmap `results` were simulated on, like the ones on
+/// calculated profiles.
+fn beatmap_name(results: &SimulationResults) -> String {
+    match results.beatmap {
+        Some(ref beatmap) => format!(
+            "{} - {} ({}) [{}]",
+            beatmap.artist, beatmap.title, beatmap.creator, beatmap.version
+        ),
+        None => results.beatmap_info.clone(),
+    }
+}
+
+/// Calculates the profile of `user` on `ruleset` from `scores`, simulating each
+/// one with `backend` (taking the .osu files from `beatmaps`). Scores that fail
+/// (for example, because their beatmap couldn't be downloaded, or because the
+/// list took longer than `timeout`) are reported separately, instead of failing
+/// the whole profile. The bonus PP is worked out from the number of beatmaps
+/// with a simulated score, unless `bonus` is given.
+///
+/// # Errors
+///
+/// Will return `CalculationError::InvalidParams` if any score is invalid (the
+/// field is then prefixed by the score, like `scores[2].mods`).
+pub fn calculate_score_list(
+    backend: &dyn PerformanceBackend,
+    beatmaps: &BeatmapCache,
+    user: String,
+    ruleset: Ruleset,
+    scores: &[ListedScore],
+    bonus: Option<f64>,
+    timeout: Duration,
+) -> Result<ScoreListResults, CalculationError> {
+    let deadline = Instant::now() + timeout;
+    let params: Vec<SimulationParams> = scores.iter().map(|s| s.params(ruleset)).collect();
+    for (i, params) in params.iter().enumerate() {
+        params.validate().map_err(|error| match error {
+            CalculationError::InvalidParams { field, message } => {
+                invalid(&format!("scores[{}].{}", i, field), message)
+            }
+            error => error,
+        })?;
+    }
+
+    let mut simulated = Vec::new();
+    let mut simulated_beatmaps = HashSet::new();
+    let mut failed = Vec::new();
+    for (score, params) in scores.iter().zip(params) {
+        let results = if Instant::now() < deadline {
+            simulate_play(backend, beatmaps, score.beatmap_id, params)
+        } else {
+            Err(CalculationError::Timeout)
+        };
+
+        match results {
+            Ok(results) => {
+                simulated_beatmaps.insert(score.beatmap_id);
+                simulated.push(Score::new(
+                    score.beatmap_id,
+                    beatmap_name(&results),
+                    results.mods.clone(),
+                    results.play_info.accuracy,
+                    score.live_pp,
+                    results.pp,
+                ));
+            }
+            Err(error) => failed.push(FailedScore {
+                beatmap_id: Some(score.beatmap_id),
+                beatmap_md5: None,
+                code: error.code(),
+                message: error.to_string(),
+            }),
+        }
+    }
+
+    let bonus = bonus.unwrap_or_else(|| bonus_pp(simulated_beatmaps.len()));
+    Ok(ScoreListResults {
+        profile: ProfileResults::from_scores(user, simulated, bonus),
+        failed: failed,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::beatmap_cache::CacheLimits;
+    use crate::beatmap_source::OfflineSource;
+    use crate::performance_calculator::{Mod, NativeBackend};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_parse_score_list() {
+        let json = r#"[{"beatmap_id": 1, "mods": ["HD"], "good": 2, "live_pp": 100}]"#;
+        let csv = "beatmap_id, mods, count100, meh, misses, combo, pp\n\n1,HD,2,,0,,100\n";
+
+        for contents in [json, csv].iter() {
+            let scores = parse_score_list(contents).unwrap();
+            assert_eq!(scores.len(), 1, "{}", contents);
+            assert_eq!(scores[0].mods, [Mod::HD].iter().cloned().collect());
+            assert_eq!((scores[0].good, scores[0].meh), (2, 0));
+            assert_eq!(scores[0].combo, None);
+            assert_eq!(scores[0].live_pp, 100.0);
+        }
+
+        let field = |contents| match parse_score_list(contents) {
+            Err(CalculationError::InvalidParams { field, message }) => {
+                assert_eq!(field, "scores");
+                message
+            }
+            other => panic!("{} parsed into {:?}", contents, other),
+        };
+        assert!(field("[]").contains("some scores"));
+        assert!(field("").contains("some scores"));
+        assert!(field("beatmap_id,mods\n1,HD,DT\n").contains("row 1 has 3 columns"));
+        assert!(field("beatmap_id,mods\n1,XX\n").starts_with("row 1"));
+    }
+
+    #[test]
+    fn test_calculate_score_list() {
+        let dir = env::temp_dir().join(format!("score_list_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.osu"), TEST_BEATMAP).unwrap();
+
+        let beatmaps = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        let score = |beatmap_id, mods: &str, misses, live_pp| ListedScore {
+            beatmap_id: beatmap_id,
+            mods: mods.parse().unwrap(),
+            good: 0,
+            meh: 0,
+            misses: misses,
+            combo: None,
+            score: None,
+            live_pp: live_pp,
+        };
+        let scores = vec![
+            score(1, "", 1, 300.0),
+            score(1, "HDDT", 0, 10.0),
+            score(2, "", 0, 200.0),
+        ];
+
+        let timeout = Duration::from_secs(60);
+        let results = calculate_score_list(
+            &NativeBackend::new(),
+            &beatmaps,
+            "someone".to_string(),
+            Ruleset::Osu,
+            &scores,
+            None,
+            timeout,
+        )
+        .unwrap();
+        assert_eq!(results.profile.beatmap_ids(), vec![1]);
+        assert_eq!(results.failed.len(), 1);
+        assert_eq!(results.failed[0].code, "beatmap_not_found");
+
+        let json = serde_json::to_value(&results.profile).unwrap();
+        // The HDDT play is worth more than the one with a miss, so it's the
+        // one that counts.
+        assert_eq!(json["scores"][0]["live_pp"], 10.0);
+        assert_eq!(json["scores"][0]["position_change"], 0);
+        // Only beatmap 1 had a score simulated.
+        assert_eq!(json["total_bonus_pp"], bonus_pp(1));
+
+        let results = calculate_score_list(
+            &NativeBackend::new(),
+            &beatmaps,
+            "someone".to_string(),
+            Ruleset::Osu,
+            &scores,
+            None,
+            Duration::from_secs(0),
+        )
+        .unwrap();
+        assert!(results.profile.beatmap_ids().is_empty());
+        assert!(results.failed.iter().all(|failed| failed.code == "timeout"));
+
+        let mut invalid_scores = scores.clone();
+        invalid_scores[1].mods = "HREZ".parse().unwrap();
+        match calculate_score_list(
+            &NativeBackend::new(),
+            &beatmaps,
+            "someone".to_string(),
+            Ruleset::Osu,
+            &invalid_scores,
+            Some(0.0),
+            timeout,
+        ) {
+            Err(CalculationError::InvalidParams { field, .. }) => {
+                assert_eq!(field, "scores[1].mods")
+            }
+            other => panic!("simulated invalid scores into {:?}", other),
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
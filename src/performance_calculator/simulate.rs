@@ -1,170 +1,402 @@
-//! A interface for PerformanceCalculator.dll's `simulate` command.
+//! Play simulation params and results, and the entry point for simulating plays.
 //!
 //! The principal function of this module is `simulate_play`, which
-//! calls into PerformanceCalculator.
-use super::{Accuracy, Mod, UnsuccessfulCommandError};
-use crate::config_functions::{beatmaps_cache, dotnet_command, performance_calculator_path};
-use std::collections::{BTreeSet, HashMap};
-use std::error::Error;
+//! downloads the beatmap if needed, and calls into a `PerformanceBackend`.
+use super::accuracy::{hit_results, object_count};
+use super::{
+    Accuracy, BeatmapInfo, CalculationError, HitResults, Mods, PerformanceBackend, Ruleset,
+};
+use crate::beatmap::Beatmap;
+use crate::beatmap_cache::BeatmapCache;
+use std::collections::HashMap;
 use std::fs;
-use std::fs::File;
-use std::path::PathBuf;
-use std::process::Command;
 
 /// Has miscellaneous info about a simulated play, including accuracy, combo and max combo,
-/// number of 300/100/50s and misses.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// number of 300/100/50s and misses. Not every ruleset reports every field, so missing
+/// ones are zeroed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PlayInfo {
     #[serde(alias = "Accuracy")]
-    accuracy: f64,
+    pub accuracy: f64,
     #[serde(alias = "Combo")]
-    combo: i64,
+    pub combo: i64,
     #[serde(alias = "MaxCombo")]
-    max_combo: i64,
+    pub max_combo: i64,
     #[serde(alias = "Great")]
-    great: i64,
+    pub great: i64,
     #[serde(alias = "Good")]
-    good: i64,
+    pub good: i64,
     #[serde(alias = "Meh")]
-    meh: i64,
+    pub meh: i64,
     #[serde(alias = "Miss")]
-    miss: i64,
+    pub miss: i64,
 }
 
 /// The result of a play simulation. Contains info about the map, the simulation params,
 /// and the simulated play resulting PP.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimulationResults {
+    #[serde(default)]
+    pub ruleset: Ruleset,
     #[serde(alias = "BeatmapInfo")]
-    beatmap_info: String,
+    pub beatmap_info: String,
     #[serde(alias = "Mods")]
-    mods: BTreeSet<Mod>,
+    pub mods: Mods,
     #[serde(alias = "PlayInfo")]
-    play_info: PlayInfo,
+    pub play_info: PlayInfo,
     #[serde(alias = "CategoryAttribs")]
-    category_attribs: HashMap<String, f64>,
+    pub category_attribs: HashMap<String, f64>,
     #[serde(alias = "PP")]
-    pp: f64,
+    pub pp: f64,
+    /// The hit counts the play was simulated with, if they were worked out
+    /// before running the calculator (see `simulate_play`).
+    #[serde(default)]
+    pub hit_results: Option<HitResults>,
+    /// The beatmap metadata and settings, read from the .osu file (see
+    /// `simulate_play`).
+    #[serde(default)]
+    pub beatmap: Option<BeatmapInfo>,
+}
+
+/// How the PP of a play compares with the live PP system, as calculated by
+/// the calculator that stands for it (see `CalculatorRegistry::live`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LiveComparison {
+    /// The name of the live calculator.
+    pub calculator: String,
+    pub live_pp: f64,
+    /// How much the PP changed from the live system.
+    pub delta: f64,
+    /// `delta`, as a percentage of `live_pp`; `None` if `live_pp` is 0.
+    pub delta_percentage: Option<f64>,
+}
+
+impl LiveComparison {
+    /// Compares `pp` with the `live_pp` calculated by `calculator`.
+    pub fn new(calculator: String, live_pp: f64, pp: f64) -> Self {
+        let delta = pp - live_pp;
+
+        LiveComparison {
+            calculator: calculator,
+            live_pp: live_pp,
+            delta: delta,
+            delta_percentage: if live_pp != 0.0 {
+                Some(delta / live_pp * 100.0)
+            } else {
+                None
+            },
+        }
+    }
 }
 
-/// Information that will be used to simulate the play. Contains the play
-/// accuracy, mod combination, and optionally the maximum combo and number of
-/// misses.
+/// Information that will be used to simulate the play. Contains the ruleset
+/// (osu!standard, if not specified), the play accuracy, mod combination, and
+/// optionally the maximum combo and number of misses. Mods can be given as
+/// a list of acronyms, a string like "HDDT", or an osu! api bitmask.
+///
+/// osu!mania plays ignore the accuracy, combo and misses, and are described
+/// by their `score` instead.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimulationParams {
-    accuracy: Accuracy,
-    mods: BTreeSet<Mod>,
-    combo: Option<usize>,
-    misses: Option<usize>,
+    #[serde(default)]
+    pub ruleset: Ruleset,
+    #[serde(default)]
+    pub accuracy: Accuracy,
+    pub mods: Mods,
+    pub combo: Option<usize>,
+    pub misses: Option<usize>,
+    #[serde(default)]
+    pub score: Option<u32>,
 }
 
-/// Parses the output of PerformanceCalculator's `simulate` command (contained into
-/// `raw_results`) into a SimulationResults.
-///
-/// # Errors
-///
-/// Will error if the contents of `raw_results` can't be parsed into a valid
-/// `SimulationResults`.
-fn parse_simulation_results(raw_results: String) -> Result<SimulationResults, Box<Error>> {
-    Ok(serde_json::from_str(raw_results.as_str())?)
+/// The highest possible osu!mania score.
+const MAX_MANIA_SCORE: u32 = 1_000_000;
+
+/// Shorthand for a `CalculationError::InvalidParams`.
+fn invalid(field: &str, message: String) -> CalculationError {
+    CalculationError::InvalidParams {
+        field: field.to_string(),
+        message: message,
+    }
 }
 
-/// Obtains the path for a beatmap's .osu file. If the beatmap isn't currently
-/// cached, downloads it and stores it into `beatmaps_cache()`.
+impl SimulationParams {
+    /// Checks that these params describe a possible play, without looking at
+    /// the beatmap: that the mods can be used together, that the accuracy is
+    /// a percentage between 0 and 100 (or hit counts the ruleset has), and
+    /// that combo and misses don't contradict each other.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams`, naming the first
+    /// invalid field.
+    pub fn validate(&self) -> Result<(), CalculationError> {
+        self.mods
+            .check(self.ruleset)
+            .map_err(|message| invalid("mods", message))?;
+
+        if self.ruleset == Ruleset::Mania {
+            return match self.score {
+                Some(score) if score > MAX_MANIA_SCORE => Err(invalid(
+                    "score",
+                    format!("should be at most {}", MAX_MANIA_SCORE),
+                )),
+                _ => Ok(()),
+            };
+        }
+
+        match self.accuracy {
+            Accuracy::Percentage(pct) if !(pct >= 0.0 && pct <= 100.0) => {
+                return Err(invalid(
+                    "accuracy",
+                    "should be between 0 and 100".to_string(),
+                ));
+            }
+            Accuracy::Hits { meh, .. } if meh > 0 && self.ruleset == Ruleset::Taiko => {
+                return Err(invalid(
+                    "accuracy",
+                    "osu!taiko plays have no mehs".to_string(),
+                ));
+            }
+            _ => {}
+        }
+
+        if let (Some(combo), Some(misses)) = (self.combo, self.misses) {
+            if combo == 0 && misses == 0 {
+                return Err(invalid(
+                    "combo",
+                    "can't be 0 in a play without misses".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the combo and misses fit in a beatmap whose maximum combo
+    /// is `max_combo`. Every miss breaks the combo at least once, so at most
+    /// `max_combo - misses` can be reached.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams`, naming the invalid field.
+    pub fn validate_combo(&self, max_combo: usize) -> Result<(), CalculationError> {
+        let misses = self.misses.unwrap_or(0);
+        if misses > max_combo {
+            return Err(invalid(
+                "misses",
+                format!("should be at most {}", max_combo),
+            ));
+        }
+
+        match self.combo {
+            Some(combo) if combo > max_combo - misses => Err(invalid(
+                "combo",
+                format!(
+                    "should be at most {} with {} misses",
+                    max_combo - misses,
+                    misses
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks `params` against `beatmap`, and converts their accuracy into hit
+/// counts. Returns the hit results the play will have, or `None` if the beatmap
+/// objects can't be counted (like in converts), in which case the backend is
+/// left to do the conversion.
 ///
 /// # Errors
 ///
-/// Will error if the folder `beatmaps_cache()` doesn't exist and can't be created;
-/// and if the beatmap identified by `beatmap_id` doesn't exist and
-/// couldn't be downloaded/saved.
-fn get_beatmap_file(beatmap_id: i64) -> Result<String, Box<Error>> {
-    let mut osu_path: PathBuf = PathBuf::new();
-    osu_path.push(beatmaps_cache());
-    if !osu_path.as_path().exists() {
-        fs::create_dir_all(beatmaps_cache())?;
+/// Will return `CalculationError::InvalidParams` if the combo, misses or hits
+/// don't fit in the beatmap.
+fn resolve_hit_results(
+    beatmap: &Beatmap,
+    params: &mut SimulationParams,
+) -> Result<Option<HitResults>, CalculationError> {
+    if params.ruleset == Ruleset::Mania {
+        return Ok(None);
     }
 
-    osu_path.push(format!("{}.osu", beatmap_id));
-
-    if !osu_path.as_path().exists() {
-        // Download it then...
-        let mut resp = reqwest::get(&format!("https://osu.ppy.sh/osu/{}", beatmap_id))?;
-        let mut file = File::create(osu_path.as_path())?;
-        resp.copy_to(&mut file)?;
+    if params.ruleset == Ruleset::Osu && beatmap.mode == Ruleset::Osu.id() {
+        params.validate_combo(beatmap.max_combo())?;
     }
 
-    Ok(osu_path.to_str().unwrap().to_string())
+    let total = match object_count(beatmap, params.ruleset) {
+        Some(total) => total,
+        None => return Ok(None),
+    };
+
+    let hits = hit_results(
+        params.accuracy,
+        params.ruleset,
+        total,
+        params.misses.unwrap_or(0),
+    )?;
+    params.accuracy = Accuracy::Hits {
+        good: hits.good,
+        meh: hits.meh,
+    };
+
+    Ok(Some(hits))
 }
 
-/// Simulate a play on `beatmap_id`, under the conditions specified by `params`, under the
-/// new PP system. Returns a SimulationResults struct.
+/// Reads and parses the .osu file at `beatmap_path`, for
+/// `simulate_parsed_plays`. Beatmaps we can't parse are `None`, and left for the
+/// backend to complain about.
 ///
 /// # Errors
 ///
-/// Will error if the beatmap isn't cached and, for whatever reason, couldn't be downloaded;
-/// if the call to `PerformanceCalculator.dll` fails, or if the output of `PerformanceCalculator`
-/// couldn't be parsed into a `SimulationResults`.
-pub fn simulate_play(
-    beatmap_id: i64,
-    params: SimulationParams,
-) -> Result<SimulationResults, Box<Error>> {
-    let mut cmd = Command::new(dotnet_command());
-
-    cmd.arg(performance_calculator_path())
-        .arg("simulate")
-        .arg("osu");
-
-    let beatmap = get_beatmap_file(beatmap_id)?;
-    cmd.arg(beatmap);
-
-    match params.accuracy {
-        Accuracy::Percentage(pct) => cmd.arg("-a").arg(format!("{:.*}", 2, pct)),
-        Accuracy::Hits { good, meh } => cmd
-            .arg("-G")
-            .arg(good.to_string())
-            .arg("-M")
-            .arg(meh.to_string()),
-    };
+/// Will error if the file couldn't be read.
+pub(super) fn read_beatmap(beatmap_path: &str) -> Result<Option<Beatmap>, CalculationError> {
+    // Text that isn't valid UTF-8 doesn't stop the rest from being parsed.
+    let contents = fs::read(beatmap_path)?;
+    Ok(Beatmap::parse(&String::from_utf8_lossy(&contents)).ok())
+}
 
-    for m in params.mods {
-        cmd.arg("-m").arg(m.to_arg());
-    }
+/// Simulates each of the already validated `params` on the .osu file at
+/// `beatmap_path`, which is parsed only once, using `backend`. Plays that can't
+/// be simulated get their own error, instead of failing the others. See
+/// `simulate_play`.
+///
+/// # Errors
+///
+/// Will error if the file couldn't be read.
+fn simulate_validated_plays(
+    backend: &dyn PerformanceBackend,
+    beatmap_path: &str,
+    params: Vec<SimulationParams>,
+) -> Result<Vec<Result<SimulationResults, CalculationError>>, CalculationError> {
+    let beatmap = read_beatmap(beatmap_path)?;
+    Ok(simulate_parsed_plays(
+        backend,
+        beatmap_path,
+        beatmap.as_ref(),
+        params,
+    ))
+}
 
-    if let Some(combo) = params.combo {
-        cmd.arg("-c").arg(combo.to_string());
-    }
+/// Like `simulate_validated_plays`, with `beatmap` already parsed from
+/// `beatmap_path` (see `read_beatmap`), so callers splitting their plays up
+/// don't parse it again for each part.
+pub(super) fn simulate_parsed_plays(
+    backend: &dyn PerformanceBackend,
+    beatmap_path: &str,
+    beatmap: Option<&Beatmap>,
+    params: Vec<SimulationParams>,
+) -> Vec<Result<SimulationResults, CalculationError>> {
+    let resolved: Vec<Result<_, CalculationError>> = params
+        .into_iter()
+        .map(|mut params| {
+            let hits = match beatmap {
+                Some(beatmap) => resolve_hit_results(beatmap, &mut params)?,
+                None => None,
+            };
 
-    if let Some(misses) = params.misses {
-        cmd.arg("-X").arg(misses.to_string());
-    }
+            Ok((params, hits))
+        })
+        .collect();
 
-    cmd.arg("--json");
+    let valid: Vec<_> = resolved
+        .iter()
+        .filter_map(|resolved| resolved.as_ref().ok())
+        .map(|(params, _)| params.clone())
+        .collect();
+    let mut outputs = backend.simulate_plays(beatmap_path, &valid).into_iter();
 
-    let output = cmd.output()?;
+    resolved
+        .into_iter()
+        .map(|resolved| {
+            let (params, hits) = resolved?;
+            let mut results = outputs
+                .next()
+                .expect("backends return a result for each play")?;
 
-    if output.status.success() {
-        let raw = String::from_utf8_lossy(&output.stdout).to_string();
+            results.ruleset = params.ruleset;
+            if hits.is_some() {
+                results.hit_results = hits;
+            }
+            results.beatmap = beatmap.map(|beatmap| BeatmapInfo::new(beatmap, &params.mods));
 
-        Ok(parse_simulation_results(raw)?)
-    } else {
-        let raw = String::from_utf8_lossy(&output.stdout).to_string();
+            Ok(results)
+        })
+        .collect()
+}
 
-        println!("simulate_play failed! output: {}", raw);
+/// Simulates a play on the .osu file at `beatmap_path`, with already validated
+/// `params`. See `simulate_play`.
+fn simulate_validated(
+    backend: &dyn PerformanceBackend,
+    beatmap_path: &str,
+    params: SimulationParams,
+) -> Result<SimulationResults, CalculationError> {
+    simulate_validated_plays(backend, beatmap_path, vec![params])?
+        .pop()
+        .unwrap()
+}
 
-        Err(Box::new(UnsuccessfulCommandError))
-    }
+/// Simulate a play on `beatmap_id` (whose .osu file is taken from `beatmaps`), under the
+/// conditions specified by `params`, under the new PP system, using `backend`. Returns a
+/// SimulationResults struct.
+///
+/// `params` are validated before anything is downloaded or spawned. When the
+/// beatmap objects can be counted (see `accuracy::object_count`), the accuracy is
+/// converted into hit counts here, so every backend assumes the same distribution,
+/// which is reported in the results, along with the beatmap metadata and settings
+/// (see `BeatmapInfo`).
+///
+/// # Errors
+///
+/// Will error if `params` are invalid (`CalculationError::InvalidParams`); if the
+/// beatmap isn't cached and, for whatever reason, couldn't be downloaded; or if
+/// `backend` fails to simulate the play.
+pub fn simulate_play(
+    backend: &dyn PerformanceBackend,
+    beatmaps: &BeatmapCache,
+    beatmap_id: i64,
+    params: SimulationParams,
+) -> Result<SimulationResults, CalculationError> {
+    params.validate()?;
+
+    let beatmap_path = beatmaps.get(beatmap_id)?;
+    simulate_validated(backend, &beatmap_path, params)
+}
+
+/// Simulate a play on the .osu file at `beatmap_path`, like an uploaded beatmap
+/// (see `BeatmapUploads`), exactly like `simulate_play` does for cached beatmaps.
+///
+/// # Errors
+///
+/// Will error if `params` are invalid (`CalculationError::InvalidParams`); if the
+/// file couldn't be read; or if `backend` fails to simulate the play.
+pub fn simulate_play_file(
+    backend: &dyn PerformanceBackend,
+    beatmap_path: &str,
+    params: SimulationParams,
+) -> Result<SimulationResults, CalculationError> {
+    params.validate()?;
+
+    simulate_validated(backend, beatmap_path, params)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::beatmap_cache::CacheLimits;
+    use crate::beatmap_source::OfficialSource;
+    use crate::config_functions::{
+        beatmaps_cache, dotnet_command, performance_calculator_path, profile_timeout,
+        simulate_timeout,
+    };
+    use crate::performance_calculator::{DotnetBackend, NativeBackend, Timeouts};
 
-    #[test]
-    fn test_calculate_beatmaps() {
-        println!("{}", performance_calculator_path());
-        use Mod::*;
+    fn beatmap_fixtures() -> Vec<(i64, Accuracy, Mods, Option<usize>, f64)> {
+        use crate::performance_calculator::Mod::*;
 
-        let data = vec![
+        vec![
             // Rafis' Necrofantasia
             (
                 1097543,
@@ -181,17 +413,36 @@ mod test {
                 None,
                 848f64,
             ),
-        ];
+        ]
+    }
+
+    #[test]
+    fn test_calculate_beatmaps() {
+        println!("{}", performance_calculator_path());
+
+        let timeouts = Timeouts {
+            profile: profile_timeout(),
+            simulate: simulate_timeout(),
+        };
+        let backend = DotnetBackend::new(dotnet_command(), performance_calculator_path(), timeouts);
+        let beatmaps = BeatmapCache::new(
+            beatmaps_cache(),
+            Box::new(OfficialSource),
+            false,
+            CacheLimits::default(),
+        );
 
-        for (beatmap_id, acc, mods, combo, pp) in data {
+        for (beatmap_id, acc, mods, combo, pp) in beatmap_fixtures() {
             let params = SimulationParams {
+                ruleset: Ruleset::Osu,
                 accuracy: acc,
                 mods: mods,
                 combo: combo,
                 misses: None,
+                score: None,
             };
 
-            match simulate_play(beatmap_id, params) {
+            match simulate_play(&backend, &beatmaps, beatmap_id, params) {
                 Ok(result) => {
                     // who cares about decimal places
                     assert_eq!(result.pp.trunc(), pp);
@@ -202,4 +453,150 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_native_calculate_beatmaps() {
+        let backend = NativeBackend::new();
+        let beatmaps = BeatmapCache::new(
+            beatmaps_cache(),
+            Box::new(OfficialSource),
+            false,
+            CacheLimits::default(),
+        );
+
+        for (beatmap_id, acc, mods, combo, pp) in beatmap_fixtures() {
+            let params = SimulationParams {
+                ruleset: Ruleset::Osu,
+                accuracy: acc,
+                mods: mods,
+                combo: combo,
+                misses: None,
+                score: None,
+            };
+
+            match simulate_play(&backend, &beatmaps, beatmap_id, params) {
+                Ok(result) => {
+                    // The native calculator isn't bit-for-bit identical to
+                    // osu-tools, so allow for some small differences.
+                    let error = (result.pp - pp).abs() / pp;
+                    assert!(error < 0.02, "{} pp, expected {}", result.pp, pp);
+                }
+                Err(e) => {
+                    panic!(format!("simulate_play failed! {}", e));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_live_comparison() {
+        let comparison = LiveComparison::new("live".to_string(), 200.0, 250.0);
+        assert_eq!(comparison.delta, 50.0);
+        assert_eq!(comparison.delta_percentage, Some(25.0));
+
+        let comparison = LiveComparison::new("live".to_string(), 0.0, 10.0);
+        assert_eq!(comparison.delta, 10.0);
+        assert_eq!(comparison.delta_percentage, None);
+    }
+
+    #[test]
+    fn test_validate_params() {
+        let params = |ruleset, accuracy, mods: &str, combo, misses| SimulationParams {
+            ruleset: ruleset,
+            accuracy: accuracy,
+            mods: mods.parse().unwrap(),
+            combo: combo,
+            misses: misses,
+            score: None,
+        };
+        let field = |result: Result<(), CalculationError>| match result {
+            Err(CalculationError::InvalidParams { field, .. }) => Some(field),
+            _ => None,
+        };
+
+        let osu = Ruleset::Osu;
+        let pct = Accuracy::Percentage;
+        assert!(params(osu, pct(98.5), "HDDT", Some(500), Some(1))
+            .validate()
+            .is_ok());
+        assert_eq!(
+            field(params(osu, pct(98.5), "HREZ", None, None).validate()),
+            Some("mods".to_string())
+        );
+        assert_eq!(
+            field(params(osu, pct(100.5), "", None, None).validate()),
+            Some("accuracy".to_string())
+        );
+        assert_eq!(
+            field(params(osu, pct(std::f64::NAN), "", None, None).validate()),
+            Some("accuracy".to_string())
+        );
+        assert_eq!(
+            field(
+                params(
+                    Ruleset::Taiko,
+                    Accuracy::Hits { good: 3, meh: 1 },
+                    "",
+                    None,
+                    None
+                )
+                .validate()
+            ),
+            Some("accuracy".to_string())
+        );
+        assert_eq!(
+            field(params(osu, pct(100.0), "", Some(0), Some(0)).validate()),
+            Some("combo".to_string())
+        );
+
+        assert!(params(osu, pct(100.0), "", Some(998), Some(2))
+            .validate_combo(1000)
+            .is_ok());
+        assert_eq!(
+            field(params(osu, pct(100.0), "", Some(999), Some(2)).validate_combo(1000)),
+            Some("combo".to_string())
+        );
+        assert_eq!(
+            field(params(osu, pct(100.0), "", None, Some(1001)).validate_combo(1000)),
+            Some("misses".to_string())
+        );
+    }
+
+    #[test]
+    fn test_simulate_play_file() {
+        use crate::beatmap::test::TEST_BEATMAP;
+        use std::env;
+        use std::process;
+
+        let path = env::temp_dir().join(format!("simulate_play_file_{}.osu", process::id()));
+        fs::write(&path, TEST_BEATMAP).unwrap();
+        let path = path.to_str().unwrap();
+
+        let params = SimulationParams {
+            ruleset: Ruleset::Osu,
+            accuracy: Accuracy::Percentage(100.0),
+            mods: mods![crate::performance_calculator::Mod::HR],
+            combo: None,
+            misses: None,
+            score: None,
+        };
+        let results = simulate_play_file(&NativeBackend::new(), path, params.clone()).unwrap();
+        assert!(results.pp > 0.0);
+        assert_eq!(results.hit_results.map(|hits| hits.great), Some(5));
+        assert_eq!(results.beatmap.unwrap().title, "Test Song");
+
+        let mut invalid = params.clone();
+        invalid.combo = Some(11);
+        assert!(simulate_play_file(&NativeBackend::new(), path, invalid).is_err());
+
+        // Old beatmaps may have metadata that isn't valid UTF-8.
+        let mut contents = TEST_BEATMAP.as_bytes().to_vec();
+        let title = contents.windows(4).position(|w| w == b"Song").unwrap();
+        contents[title] = 0xff;
+        fs::write(path, contents).unwrap();
+        let results = simulate_play_file(&NativeBackend::new(), path, params).unwrap();
+        assert_eq!(results.hit_results.map(|hits| hits.great), Some(5));
+
+        let _ = fs::remove_file(path);
+    }
 }
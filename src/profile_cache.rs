@@ -5,8 +5,11 @@
 //! was placed into the cache). This information is used to determine
 //! whether this calculation is "too fresh", and as so to avoid user
 //! abuse.
+//!
+//! Results are kept separately for each calculator and ruleset, so that
+//! results from different rebalance proposals or game modes never mix.
 
-use super::performance_calculator::ProfileResults;
+use super::performance_calculator::{ProfileResults, Ruleset, DEFAULT_CALCULATOR};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
@@ -14,9 +17,32 @@ use std::io::{BufReader, BufWriter};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
+/// The cached results of a single calculator and ruleset, by player.
+type PlayerResults = HashMap<String, (ProfileResults, SystemTime)>;
+
+/// The cached results of a single calculator, by ruleset.
+type CalculatorResults = HashMap<Ruleset, PlayerResults>;
+
+/// The contents of a results file. Older files either have osu!standard
+/// results by calculator, or only the default calculator's osu!standard results.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredResults {
+    ByRuleset(HashMap<String, CalculatorResults>),
+    ByCalculator(HashMap<String, PlayerResults>),
+    Legacy(PlayerResults),
+}
+
+/// Wraps osu!standard results of a single calculator.
+fn osu_results(results: PlayerResults) -> CalculatorResults {
+    let mut by_ruleset = HashMap::new();
+    by_ruleset.insert(Ruleset::Osu, results);
+    by_ruleset
+}
+
 /// A cache for the profile calculation results.
 pub struct ProfileCache {
-    data: Arc<Mutex<HashMap<String, (ProfileResults, SystemTime)>>>,
+    data: Arc<Mutex<HashMap<String, CalculatorResults>>>,
 }
 
 impl ProfileCache {
@@ -28,11 +54,22 @@ impl ProfileCache {
     /// or if its contents aren't a valid cache representation.
     fn load_results(
         results_file: String,
-    ) -> Result<HashMap<String, (ProfileResults, SystemTime)>, Box<Error>> {
+    ) -> Result<HashMap<String, CalculatorResults>, Box<Error>> {
         let file = File::open(results_file)?;
         let reader = BufReader::new(file);
 
-        let results = serde_json::from_reader(reader)?;
+        let results = match serde_json::from_reader(reader)? {
+            StoredResults::ByRuleset(results) => results,
+            StoredResults::ByCalculator(results) => results
+                .into_iter()
+                .map(|(calculator, results)| (calculator, osu_results(results)))
+                .collect(),
+            StoredResults::Legacy(results) => {
+                let mut by_calculator = HashMap::new();
+                by_calculator.insert(DEFAULT_CALCULATOR.to_string(), osu_results(results));
+                by_calculator
+            }
+        };
 
         Ok(results)
     }
@@ -43,7 +80,7 @@ impl ProfileCache {
     ///
     /// Will error if the HashMap fails to be written to `results_file`.
     fn save_results(
-        data: &HashMap<String, (ProfileResults, SystemTime)>,
+        data: &HashMap<String, CalculatorResults>,
         results_file: String,
     ) -> Result<(), Box<Error>> {
         let file = File::create(results_file)?;
@@ -95,25 +132,34 @@ impl ProfileCache {
     }
 
     /// Gets a `ProfileResults`, and the time it was calculated,
-    /// associated with said `player`.
+    /// associated with said `player` on `ruleset`, as calculated by `calculator`.
     ///
     /// If no result is found, returns None.
-    pub fn get(&self, player: String) -> Option<(ProfileResults, SystemTime)> {
+    pub fn get(
+        &self,
+        calculator: &str,
+        ruleset: Ruleset,
+        player: String,
+    ) -> Option<(ProfileResults, SystemTime)> {
         let _guard = self.data.lock().unwrap();
 
-        if _guard.contains_key(&player) {
-            let pair = &_guard[&player];
-            Some(pair.clone())
-        } else {
-            None
-        }
+        _guard
+            .get(calculator)
+            .and_then(|results| results.get(&ruleset))
+            .and_then(|results| results.get(&player))
+            .cloned()
     }
 
-    /// Associates the `result` with this `player`. Also stores
-    /// the time this was set.
-    pub fn set(&self, player: String, result: ProfileResults) {
+    /// Associates the `result` with this `player` on `ruleset`, for `calculator`.
+    /// Also stores the time this was set.
+    pub fn set(&self, calculator: &str, ruleset: Ruleset, player: String, result: ProfileResults) {
         let mut _guard = self.data.lock().unwrap();
 
-        _guard.insert(player.clone(), (result, SystemTime::now()));
+        _guard
+            .entry(calculator.to_string())
+            .or_insert_with(HashMap::new)
+            .entry(ruleset)
+            .or_insert_with(HashMap::new)
+            .insert(player, (result, SystemTime::now()));
     }
 }
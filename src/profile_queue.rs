@@ -5,66 +5,104 @@
 //!
 //! A single user name/ID can be requested multiple times, however,
 //! while in the queue, they will always be associated with a single job.
-//! This avoid unnecessary computations.
+//! This avoid unnecessary computations. Requests for different calculators
+//! or rulesets are separate jobs, though.
+//!
+//! Optionally, the beatmaps of every calculated profile are prefetched into the
+//! beatmaps cache, so simulating plays on them later doesn't wait for downloads.
+//! A single thread does that, one profile at a time, and profiles that arrive
+//! while `PREFETCH_BACKLOG` others are waiting are skipped.
 extern crate mt_job_queue;
 
+use super::beatmap_cache::BeatmapCache;
 use super::performance_calculator::calculate_profile;
-use super::performance_calculator::ProfileResults;
+use super::performance_calculator::{
+    CalculationError, CalculatorRegistry, ProfileResults, Ruleset,
+};
 use super::profile_cache::ProfileCache;
 use mt_job_queue::queue::JobState;
 use mt_job_queue::Queue;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::thread;
+
+use std::collections::HashMap;
+
+/// How many profiles can be waiting for their beatmaps to be prefetched.
+const PREFETCH_BACKLOG: usize = 16;
 
-use std::collections::{BTreeSet, HashMap};
+/// A calculation job: the name of the calculator, the ruleset, and the user.
+type Job = (String, Ruleset, String);
 
 /// The ProfileQueue struct.
 pub struct ProfileQueue {
-    calculation_errors: Arc<Mutex<BTreeSet<String>>>,
-    user_job_id: Arc<Mutex<HashMap<String, usize>>>,
-    job_queue: Queue<String>,
+    calculation_errors: Arc<Mutex<HashMap<Job, CalculationError>>>,
+    user_job_id: Arc<Mutex<HashMap<Job, usize>>>,
+    job_queue: Queue<Job>,
     profile_cache: Arc<ProfileCache>,
 }
 
 /// A enum, that represents the status of a request. When it's `Pending`,
 /// the associated `usize` is the position of this request on the queue
-/// (i.e. how many people are ahead of you.)
-#[derive(Clone, Copy, PartialEq)]
+/// (i.e. how many people are ahead of you.) When it's `Error`, it carries
+/// what went wrong with the calculation.
+#[derive(Debug, Clone, PartialEq)]
 pub enum RequestStatus {
     Pending(usize),
     Calculating,
     Done,
-    Error,
+    Error(CalculationError),
 }
 
 impl ProfileQueue {
-    /// Creates a new `ProfileQueue`, with `num_threads` workers.
+    /// Creates a new `ProfileQueue`, with `num_threads` workers, that
+    /// calculate profiles using the calculators in `registry`.
     ///
-    /// The results will be stored into `profile_cache`.
-    pub fn new(profile_cache: Arc<ProfileCache>, num_threads: usize) -> Self {
-        let calculation_errors = Arc::new(Mutex::new(BTreeSet::new()));
-        let process_job = Arc::new(|user: String| {
-            let opt = match calculate_profile(user.clone()) {
-                Ok(result) => Some(result),
-                Err(_) => None,
+    /// The results will be stored into `profile_cache`. If `prefetch` is set,
+    /// the beatmaps of each calculated profile are fetched into it, on the
+    /// background (unless its calculator fetches them on its own).
+    pub fn new(
+        profile_cache: Arc<ProfileCache>,
+        registry: Arc<CalculatorRegistry>,
+        prefetch: Option<Arc<BeatmapCache>>,
+        num_threads: usize,
+    ) -> Self {
+        let calculation_errors = Arc::new(Mutex::new(HashMap::new()));
+        let prefetcher = prefetch.map(prefetcher);
+        let process_job = Arc::new(move |job: Job| {
+            let result = match registry.get(&job.0) {
+                Some(backend) => {
+                    let result = calculate_profile(&*backend, job.2.clone(), job.1);
+                    match (&result, &prefetcher) {
+                        (Ok(results), Some(prefetcher)) if !backend.fetches_profile_beatmaps() => {
+                            prefetch_beatmaps(prefetcher, results.beatmap_ids())
+                        }
+                        _ => {}
+                    }
+
+                    result
+                }
+                None => Err(CalculationError::UnknownCalculator(job.0.clone())),
             };
 
-            (user, opt)
+            (job, result)
         });
 
         let job_completed_profile_cache = profile_cache.clone();
         let job_completed_calculation_errors = calculation_errors.clone();
-        let on_job_completed =
-            Arc::new(
-                move |(user, result): (String, Option<ProfileResults>)| match result {
-                    Some(profile_results) => job_completed_profile_cache.set(user, profile_results),
-                    None => {
-                        job_completed_calculation_errors
-                            .lock()
-                            .unwrap()
-                            .insert(user);
-                    }
-                },
-            );
+        let on_job_completed = Arc::new(
+            move |(job, result): (Job, Result<ProfileResults, CalculationError>)| match result {
+                Ok(profile_results) => {
+                    job_completed_profile_cache.set(&job.0, job.1, job.2, profile_results)
+                }
+                Err(error) => {
+                    job_completed_calculation_errors
+                        .lock()
+                        .unwrap()
+                        .insert(job, error);
+                }
+            },
+        );
 
         ProfileQueue {
             calculation_errors: calculation_errors,
@@ -74,35 +112,171 @@ impl ProfileQueue {
         }
     }
 
-    /// Places a new `user` into the calculation queue. If the user already is
-    /// on the queue, nothing happens.
-    pub fn enqueue(&self, user: String) {
+    /// Places a new `user` into the calculation queue, to be calculated by
+    /// `calculator` on `ruleset`. If the user already is on the queue for that
+    /// calculator and ruleset, nothing happens.
+    pub fn enqueue(&self, calculator: &str, ruleset: Ruleset, user: String) {
         let mut _guard = self.user_job_id.lock().unwrap();
+        let job = (calculator.to_string(), ruleset, user);
 
-        if _guard.contains_key(&user) {
+        if _guard.contains_key(&job) {
             return;
         }
 
-        let job_id = self.job_queue.enqueue(user.clone());
+        let job_id = self.job_queue.enqueue(job.clone());
 
-        _guard.insert(user, job_id);
+        _guard.insert(job, job_id);
     }
 
-    /// Obtains the status of a calculation request for a `user`.
-    pub fn status(&self, user: String) -> Option<RequestStatus> {
-        match self.user_job_id.lock().unwrap().get(&user) {
+    /// Obtains the status of a calculation request for a `user`, with `calculator`
+    /// on `ruleset`.
+    pub fn status(
+        &self,
+        calculator: &str,
+        ruleset: Ruleset,
+        user: String,
+    ) -> Option<RequestStatus> {
+        let job = (calculator.to_string(), ruleset, user);
+
+        match self.user_job_id.lock().unwrap().get(&job) {
             Some(job_id) => Some(match self.job_queue.job_state(*job_id) {
                 JobState::Pending => RequestStatus::Pending(self.job_queue.position(*job_id)),
                 JobState::Acknowledged => RequestStatus::Calculating,
-                JobState::Complete => {
-                    if self.calculation_errors.lock().unwrap().contains(&user) {
-                        RequestStatus::Error
-                    } else {
-                        RequestStatus::Done
-                    }
-                }
+                JobState::Complete => match self.calculation_errors.lock().unwrap().get(&job) {
+                    Some(error) => RequestStatus::Error(error.clone()),
+                    None => RequestStatus::Done,
+                },
             }),
             None => None,
         }
     }
 }
+
+/// Starts the thread that prefetches beatmaps into `beatmaps`, returning where
+/// to send the beatmap ids of each profile.
+fn prefetcher(beatmaps: Arc<BeatmapCache>) -> Mutex<SyncSender<Vec<i64>>> {
+    let (sender, receiver) = sync_channel::<Vec<i64>>(PREFETCH_BACKLOG);
+    thread::spawn(move || {
+        for beatmap_ids in receiver {
+            beatmaps.prefetch(&beatmap_ids);
+        }
+    });
+
+    Mutex::new(sender)
+}
+
+/// Sends `beatmap_ids` to be prefetched, unless too many are waiting already.
+fn prefetch_beatmaps(prefetcher: &Mutex<SyncSender<Vec<i64>>>, beatmap_ids: Vec<i64>) {
+    if let Err(TrySendError::Full(_)) = prefetcher.lock().unwrap().try_send(beatmap_ids) {
+        println!("Too many profiles waiting for their beatmaps, not prefetching.");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::performance_calculator::{
+        PerformanceBackend, SimulationParams, SimulationResults, DEFAULT_CALCULATOR,
+    };
+    use std::time::Duration;
+
+    /// A backend that "calculates" profiles instantly, without calling into
+    /// PerformanceCalculator.
+    struct FakeBackend;
+
+    impl PerformanceBackend for FakeBackend {
+        fn calculate_profile(
+            &self,
+            user: &str,
+            _ruleset: Ruleset,
+        ) -> Result<ProfileResults, CalculationError> {
+            if user == "nobody" {
+                return Err(CalculationError::UserNotFound(user.to_string()));
+            } else if user == "slowpoke" {
+                return Err(CalculationError::Timeout);
+            }
+
+            let raw = format!(
+                r#"{{"user": "{}", "total_live_pp": 1.0, "total_bonus_pp": 0.0,
+                    "total_local_pp": 2.0, "scores": []}}"#,
+                user
+            );
+            Ok(serde_json::from_str(&raw)?)
+        }
+
+        fn simulate_play(
+            &self,
+            _beatmap_path: &str,
+            _params: &SimulationParams,
+        ) -> Result<SimulationResults, CalculationError> {
+            Err(CalculationError::Unsupported("Simulation".to_string()))
+        }
+    }
+
+    /// Polls the `queue` until the request for `user` with `calculator` on
+    /// `ruleset` is either done or errored.
+    fn wait_for(
+        queue: &ProfileQueue,
+        calculator: &str,
+        ruleset: Ruleset,
+        user: &str,
+    ) -> Option<RequestStatus> {
+        for _ in 0..500 {
+            match queue.status(calculator, ruleset, user.to_string()) {
+                Some(RequestStatus::Pending(_)) | Some(RequestStatus::Calculating) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                status => return status,
+            }
+        }
+
+        None
+    }
+
+    #[test]
+    fn test_queue_with_fake_backend() {
+        let cache = Arc::new(ProfileCache::new(None));
+        let registry = Arc::new(CalculatorRegistry::new(
+            "Fake".to_string(),
+            Arc::new(FakeBackend),
+        ));
+        let queue = ProfileQueue::new(cache.clone(), registry, None, 1);
+
+        let osu = Ruleset::Osu;
+        queue.enqueue(DEFAULT_CALCULATOR, osu, "somebody".to_string());
+        queue.enqueue(DEFAULT_CALCULATOR, osu, "nobody".to_string());
+        queue.enqueue(DEFAULT_CALCULATOR, osu, "slowpoke".to_string());
+        queue.enqueue("missing", osu, "somebody".to_string());
+
+        assert!(wait_for(&queue, DEFAULT_CALCULATOR, osu, "somebody") == Some(RequestStatus::Done));
+        assert_eq!(
+            wait_for(&queue, DEFAULT_CALCULATOR, osu, "nobody"),
+            Some(RequestStatus::Error(CalculationError::UserNotFound(
+                "nobody".to_string()
+            )))
+        );
+        assert_eq!(
+            wait_for(&queue, DEFAULT_CALCULATOR, osu, "slowpoke"),
+            Some(RequestStatus::Error(CalculationError::Timeout))
+        );
+
+        assert!(cache
+            .get(DEFAULT_CALCULATOR, osu, "somebody".to_string())
+            .is_some());
+        assert!(cache
+            .get(DEFAULT_CALCULATOR, osu, "nobody".to_string())
+            .is_none());
+        assert!(cache
+            .get(DEFAULT_CALCULATOR, Ruleset::Taiko, "somebody".to_string())
+            .is_none());
+
+        // Results from other calculators never mix with the default one's.
+        assert_eq!(
+            wait_for(&queue, "missing", osu, "somebody"),
+            Some(RequestStatus::Error(CalculationError::UnknownCalculator(
+                "missing".to_string()
+            )))
+        );
+        assert!(cache.get("missing", osu, "somebody".to_string()).is_none());
+    }
+}
@@ -0,0 +1,319 @@
+//! A parser for the header of osu! replay (.osr) files.
+//!
+//! The header has everything a simulation needs: the ruleset, the checksum of
+//! the beatmap .osu file, the hit counts, the max combo and the mods of the
+//! play. The (compressed) replay frames after it are never read, so truncated
+//! replays are fine as long as the header is there.
+//!
+//! Numbers are little endian, and strings are a `0x0b` byte followed by their
+//! ULEB128 encoded length and UTF-8 bytes (or a single `0x00`, if missing).
+//! Local scores (see `scores_db`) are stored the same way.
+use super::performance_calculator::{Accuracy, CalculationError, Mods, Ruleset, SimulationParams};
+
+/// The play recorded in a replay.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Replay {
+    pub ruleset: Ruleset,
+    /// The osu! version the replay was made with, like `20190101`.
+    pub game_version: i32,
+    /// The MD5 checksum of the .osu file of the beatmap, lowercase.
+    pub beatmap_md5: String,
+    pub player: String,
+    pub count_300: u16,
+    pub count_100: u16,
+    pub count_50: u16,
+    pub count_geki: u16,
+    pub count_katu: u16,
+    pub count_miss: u16,
+    pub score: u32,
+    pub max_combo: u16,
+    /// Whether the play reached the maximum combo of the beatmap.
+    pub perfect: bool,
+    pub mods: Mods,
+}
+
+/// Reads the values of an osu! binary file, in order. Errors are reported as
+/// `CalculationError::InvalidParams`, for the `field` the file came from.
+pub(crate) struct Reader<'a> {
+    contents: &'a [u8],
+    position: usize,
+    field: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(contents: &'a [u8], field: &'static str) -> Self {
+        Reader {
+            contents: contents,
+            position: 0,
+            field: field,
+        }
+    }
+
+    pub(crate) fn invalid(&self, message: String) -> CalculationError {
+        CalculationError::InvalidParams {
+            field: self.field.to_string(),
+            message: message,
+        }
+    }
+
+    /// The next `count` bytes, which are the `what` of the file.
+    fn bytes(&mut self, count: usize, what: &str) -> Result<&'a [u8], CalculationError> {
+        if self.contents.len() - self.position < count {
+            return Err(self.invalid(format!("truncated before its {}", what)));
+        }
+
+        let bytes = &self.contents[self.position..self.position + count];
+        self.position += count;
+
+        Ok(bytes)
+    }
+
+    /// The next `count` bytes as a little endian number.
+    fn number(&mut self, count: usize, what: &str) -> Result<u64, CalculationError> {
+        Ok(self
+            .bytes(count, what)?
+            .iter()
+            .rev()
+            .fold(0, |value, byte| value << 8 | u64::from(*byte)))
+    }
+
+    pub(crate) fn byte(&mut self, what: &str) -> Result<u8, CalculationError> {
+        Ok(self.bytes(1, what)?[0])
+    }
+
+    pub(crate) fn u16(&mut self, what: &str) -> Result<u16, CalculationError> {
+        Ok(self.number(2, what)? as u16)
+    }
+
+    pub(crate) fn u32(&mut self, what: &str) -> Result<u32, CalculationError> {
+        Ok(self.number(4, what)? as u32)
+    }
+
+    pub(crate) fn u64(&mut self, what: &str) -> Result<u64, CalculationError> {
+        self.number(8, what)
+    }
+
+    fn uleb128(&mut self, what: &str) -> Result<usize, CalculationError> {
+        let mut value = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte(what)?;
+            value |= usize::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(self.invalid(format!("its {} length is too long", what)))
+    }
+
+    pub(crate) fn string(&mut self, what: &str) -> Result<String, CalculationError> {
+        match self.byte(what)? {
+            0x00 => Ok(String::new()),
+            0x0b => {
+                let length = self.uleb128(what)?;
+                String::from_utf8(self.bytes(length, what)?.to_vec())
+                    .map_err(|_| self.invalid(format!("its {} isn't valid UTF-8", what)))
+            }
+            other => Err(self.invalid(format!(
+                "its {} starts with an unknown byte {:#04x}",
+                what, other
+            ))),
+        }
+    }
+}
+
+impl Replay {
+    /// Parses the header of the .osr file `contents`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` (for the `replay` field) if
+    /// the header is truncated or malformed, or if its ruleset is unknown.
+    pub fn parse(contents: &[u8]) -> Result<Replay, CalculationError> {
+        let mut reader = Reader::new(contents, "replay");
+        let replay = Replay::read(&mut reader)?;
+
+        if replay.beatmap_md5.is_empty() {
+            return Err(reader.invalid("doesn't say what its beatmap is".to_string()));
+        }
+
+        Ok(replay)
+    }
+
+    /// Reads a replay header from `reader`.
+    pub(crate) fn read(reader: &mut Reader) -> Result<Replay, CalculationError> {
+        let mode = reader.byte("mode")?;
+        let ruleset = Ruleset::from_id(mode)
+            .ok_or_else(|| reader.invalid(format!("unknown mode {}", mode)))?;
+        let game_version = reader.u32("version")? as i32;
+        let beatmap_md5 = reader.string("beatmap checksum")?.to_lowercase();
+        let player = reader.string("player name")?;
+        // The checksum of the replay itself.
+        reader.string("replay checksum")?;
+
+        Ok(Replay {
+            ruleset: ruleset,
+            game_version: game_version,
+            beatmap_md5: beatmap_md5,
+            player: player,
+            count_300: reader.u16("300 count")?,
+            count_100: reader.u16("100 count")?,
+            count_50: reader.u16("50 count")?,
+            count_geki: reader.u16("geki count")?,
+            count_katu: reader.u16("katu count")?,
+            count_miss: reader.u16("miss count")?,
+            score: reader.u32("score")?,
+            max_combo: reader.u16("max combo")?,
+            perfect: reader.byte("perfect flag")? != 0,
+            mods: Mods::from_bits(reader.u32("mods")?),
+        })
+    }
+
+    /// The simulation of this play. osu!mania plays are described by their
+    /// score; the others by their 100s and 50s (the droplets and tiny droplets,
+    /// in osu!catch), misses and max combo.
+    pub fn simulation_params(&self) -> SimulationParams {
+        let (accuracy, score) = match self.ruleset {
+            Ruleset::Mania => (Accuracy::default(), Some(self.score)),
+            Ruleset::Taiko => (
+                Accuracy::Hits {
+                    good: self.count_100.into(),
+                    meh: 0,
+                },
+                None,
+            ),
+            Ruleset::Osu | Ruleset::Catch => (
+                Accuracy::Hits {
+                    good: self.count_100.into(),
+                    meh: self.count_50.into(),
+                },
+                None,
+            ),
+        };
+
+        SimulationParams {
+            ruleset: self.ruleset,
+            accuracy: accuracy,
+            mods: self.mods.clone(),
+            combo: Some(self.max_combo.into()),
+            misses: Some(self.count_miss.into()),
+            score: score,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::performance_calculator::Mod;
+
+    /// Encodes `value` as an osu! string.
+    pub fn string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![0x0b];
+        let mut length = value.len();
+        loop {
+            let byte = (length & 0x7f) as u8;
+            length >>= 7;
+            if length == 0 {
+                bytes.push(byte);
+                break;
+            }
+            bytes.push(byte | 0x80);
+        }
+
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+
+    /// The header of a replay on `ruleset`, of the beatmap with `beatmap_md5`,
+    /// with 2 100s, 1 50, 1 miss, a 200x combo and HDDT.
+    fn replay_header(ruleset: Ruleset, beatmap_md5: &str) -> Vec<u8> {
+        let mut header = vec![ruleset.id()];
+        header.extend(&20190101u32.to_le_bytes());
+        header.extend(string(beatmap_md5));
+        header.extend(string(&"player ".repeat(30)));
+        header.extend(string("d41d8cd98f00b204e9800998ecf8427e"));
+        for count in [300u16, 2, 1, 0, 0, 1].iter() {
+            header.extend(&count.to_le_bytes());
+        }
+        header.extend(&987_654u32.to_le_bytes());
+        header.extend(&200u16.to_le_bytes());
+        header.push(0);
+        header.extend(&(Mod::HD.bits() | Mod::DT.bits()).to_le_bytes());
+
+        header
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut contents = replay_header(Ruleset::Osu, "ABCDEF0123456789ABCDEF0123456789");
+        // Life bar graph, timestamp and frames, which aren't read.
+        contents.extend(&[0x00, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let replay = Replay::parse(&contents).unwrap();
+        assert_eq!(replay.ruleset, Ruleset::Osu);
+        assert_eq!(replay.game_version, 20190101);
+        assert_eq!(replay.beatmap_md5, "abcdef0123456789abcdef0123456789");
+        assert_eq!(replay.player.len(), 210);
+        assert_eq!(
+            (replay.count_300, replay.count_100, replay.count_50),
+            (300, 2, 1)
+        );
+        assert_eq!((replay.count_miss, replay.max_combo), (1, 200));
+        assert_eq!(replay.score, 987_654);
+        assert!(!replay.perfect);
+        assert_eq!(replay.mods, [Mod::HD, Mod::DT].iter().cloned().collect());
+
+        let params = replay.simulation_params();
+        assert_eq!(params.accuracy, Accuracy::Hits { good: 2, meh: 1 });
+        assert_eq!((params.combo, params.misses), (Some(200), Some(1)));
+        assert_eq!(params.score, None);
+    }
+
+    #[test]
+    fn test_simulation_params() {
+        let params = |ruleset| {
+            Replay::parse(&replay_header(ruleset, "abc"))
+                .unwrap()
+                .simulation_params()
+        };
+
+        assert_eq!(
+            params(Ruleset::Taiko).accuracy,
+            Accuracy::Hits { good: 2, meh: 0 }
+        );
+        assert_eq!(
+            params(Ruleset::Catch).accuracy,
+            Accuracy::Hits { good: 2, meh: 1 }
+        );
+
+        let mania = params(Ruleset::Mania);
+        assert_eq!(mania.score, Some(987_654));
+        assert_eq!(mania.accuracy, Accuracy::default());
+    }
+
+    #[test]
+    fn test_invalid_replays() {
+        let message = |contents: &[u8]| match Replay::parse(contents) {
+            Err(CalculationError::InvalidParams { field, message }) => {
+                assert_eq!(field, "replay");
+                message
+            }
+            other => panic!("parsed into {:?}", other),
+        };
+
+        let header = replay_header(Ruleset::Osu, "abc");
+        assert!(message(&header[..header.len() - 1]).contains("before its mods"));
+        assert!(message(&[]).contains("before its mode"));
+        assert!(message(&[9]).contains("unknown mode 9"));
+
+        let mut unknown_string = header.clone();
+        unknown_string[5] = 0x05;
+        assert!(message(&unknown_string).contains("beatmap checksum starts with"));
+
+        let mut no_beatmap = vec![0, 0, 0, 0, 0, 0x00];
+        no_beatmap.extend(&header[10..]);
+        assert!(message(&no_beatmap).contains("doesn't say what its beatmap is"));
+    }
+}
@@ -0,0 +1,430 @@
+//! A parser for the local scores database of osu! (stable), `scores.db`.
+//!
+//! It has every play set on the computer, grouped by beatmap, including the
+//! ones that were never submitted, or were set on private servers. Each score
+//! is stored like a replay header (see `replay`), followed by a few fields that
+//! don't matter here.
+//!
+//! Along with a local Songs folder as the beatmap source, it's enough to
+//! calculate the profile of a player with no network access at all (see
+//! `calculate_scores_db_profile`).
+use super::beatmap_cache::BeatmapCache;
+use super::performance_calculator::score_list::{FailedScore, ListedScore, MAX_LISTED_SCORES};
+use super::performance_calculator::{
+    calculate_score_list, simulate_play, CalculationError, Mod, PerformanceBackend, Ruleset,
+    ScoreListResults,
+};
+use super::replay::{Reader, Replay};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The scores on a `scores.db` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoresDb {
+    /// The osu! version that wrote the file, like `20190101`.
+    pub version: i32,
+    pub scores: Vec<Replay>,
+}
+
+impl ScoresDb {
+    /// Parses the `scores.db` file `contents`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `CalculationError::InvalidParams` (for the `scores_db` field)
+    /// if the file is truncated or malformed.
+    pub fn parse(contents: &[u8]) -> Result<ScoresDb, CalculationError> {
+        let mut reader = Reader::new(contents, "scores_db");
+        let version = reader.u32("version")? as i32;
+        let beatmap_count = reader.u32("beatmap count")?;
+
+        let mut scores = Vec::new();
+        for _ in 0..beatmap_count {
+            reader.string("beatmap checksum")?;
+            let score_count = reader.u32("score count")?;
+
+            for _ in 0..score_count {
+                let score = Replay::read(&mut reader)?;
+                // The life bar graph (always empty), the timestamp, the
+                // length of the replay data (always -1) and the online id.
+                reader.string("life bar graph")?;
+                reader.u64("timestamp")?;
+                reader.u32("replay length")?;
+                reader.u64("online score id")?;
+                // Target Practice scores have their accuracy stored, too.
+                if score.mods.contains(&Mod::TP) {
+                    reader.u64("target practice accuracy")?;
+                }
+
+                scores.push(score);
+            }
+        }
+
+        Ok(ScoresDb {
+            version: version,
+            scores: scores,
+        })
+    }
+
+    /// Reads and parses the `scores.db` file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Will error if the file couldn't be read (`CalculationError::Io`), or
+    /// parsed (see `ScoresDb::parse`).
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ScoresDb, CalculationError> {
+        ScoresDb::parse(&fs::read(path)?)
+    }
+
+    /// The best play (the one with the highest score, like on the osu! local
+    /// rankings) of `player` on each beatmap, on `ruleset`. Player names are
+    /// compared ignoring case.
+    pub fn best_plays(&self, player: &str, ruleset: Ruleset) -> Vec<&Replay> {
+        let mut best: HashMap<&str, &Replay> = HashMap::new();
+        let plays = self
+            .scores
+            .iter()
+            .filter(|score| score.ruleset == ruleset && score.player.eq_ignore_ascii_case(player));
+
+        for play in plays {
+            let current = best.entry(play.beatmap_md5.as_str()).or_insert(play);
+            if play.score > current.score {
+                *current = play;
+            }
+        }
+
+        let mut plays: Vec<&Replay> = best.into_iter().map(|(_, play)| play).collect();
+        plays.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then(a.beatmap_md5.cmp(&b.beatmap_md5))
+        });
+
+        plays
+    }
+}
+
+/// A `scores.db` file, which is only parsed again when it changes (by its size
+/// or modification time), since it can be big, and changes with every play.
+pub struct ScoresDbFile {
+    path: PathBuf,
+    parsed: Mutex<Option<((u64, SystemTime), Arc<ScoresDb>)>>,
+}
+
+impl ScoresDbFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        ScoresDbFile {
+            path: path.into(),
+            parsed: Mutex::new(None),
+        }
+    }
+
+    /// The scores on the file, as of its last change.
+    ///
+    /// # Errors
+    ///
+    /// Will error if the file couldn't be read (`CalculationError::Io`), or
+    /// parsed (see `ScoresDb::parse`).
+    pub fn scores(&self) -> Result<Arc<ScoresDb>, CalculationError> {
+        let metadata = fs::metadata(&self.path)?;
+        let stamp = (metadata.len(), metadata.modified()?);
+
+        let mut parsed = self.parsed.lock().unwrap();
+        if let Some((parsed_stamp, scores_db)) = parsed.as_ref() {
+            if *parsed_stamp == stamp {
+                return Ok(scores_db.clone());
+            }
+        }
+
+        let scores_db = Arc::new(ScoresDb::from_file(&self.path)?);
+        *parsed = Some((stamp, scores_db.clone()));
+
+        Ok(scores_db)
+    }
+}
+
+/// A play of a `scores.db` file, on `beatmap_id`, as a score of a list.
+fn listed_score(play: &Replay, beatmap_id: i64, live_pp: f64) -> ListedScore {
+    let params = play.simulation_params();
+
+    ListedScore {
+        beatmap_id: beatmap_id,
+        mods: params.mods,
+        good: play.count_100.into(),
+        meh: if play.ruleset == Ruleset::Taiko {
+            0
+        } else {
+            play.count_50.into()
+        },
+        misses: play.count_miss.into(),
+        combo: params.combo,
+        score: params.score,
+        live_pp: live_pp,
+    }
+}
+
+/// Calculates the profile of `player` on `ruleset` from their best plays on
+/// `scores_db` (at most `MAX_LISTED_SCORES`, highest score first), simulating
+/// them with `backend`. Beatmaps are found by their checksum on `beatmaps` (see
+/// `BeatmapCache::find_by_md5`); plays whose beatmap can't be found, that are
+/// invalid, or that aren't simulated within `timeout`, are reported as failed.
+///
+/// Local scores don't know what they're worth, so their live PP is simulated
+/// with `live` (the live calculator), if there's one, and is 0 otherwise.
+///
+/// # Errors
+///
+/// Will return `CalculationError::UserNotFound` if `player` has no plays on
+/// `ruleset`.
+pub fn calculate_scores_db_profile(
+    backend: &dyn PerformanceBackend,
+    live: Option<&dyn PerformanceBackend>,
+    beatmaps: &BeatmapCache,
+    scores_db: &ScoresDb,
+    player: &str,
+    ruleset: Ruleset,
+    timeout: Duration,
+) -> Result<ScoreListResults, CalculationError> {
+    let deadline = Instant::now() + timeout;
+    let mut plays = scores_db.best_plays(player, ruleset);
+    if plays.is_empty() {
+        return Err(CalculationError::UserNotFound(player.to_string()));
+    }
+    plays.truncate(MAX_LISTED_SCORES);
+
+    let mut scores = Vec::new();
+    let mut failed = Vec::new();
+    for play in plays {
+        let params = play.simulation_params();
+        let beatmap_id = match params
+            .validate()
+            .and_then(|_| beatmaps.find_by_md5(&play.beatmap_md5))
+        {
+            Ok(beatmap_id) => beatmap_id,
+            Err(error) => {
+                failed.push(FailedScore {
+                    beatmap_id: None,
+                    beatmap_md5: Some(play.beatmap_md5.clone()),
+                    code: error.code(),
+                    message: error.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let live_pp = live
+            .filter(|_| Instant::now() < deadline)
+            .and_then(|live| simulate_play(live, beatmaps, beatmap_id, params).ok())
+            .map_or(0.0, |results| results.pp);
+        scores.push(listed_score(play, beatmap_id, live_pp));
+    }
+
+    let now = Instant::now();
+    let remaining = if now < deadline {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    };
+    let mut results = calculate_score_list(
+        backend,
+        beatmaps,
+        player.to_string(),
+        ruleset,
+        &scores,
+        None,
+        remaining,
+    )?;
+    results.failed.extend(failed);
+
+    Ok(results)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::beatmap_cache::{md5_hex, CacheLimits};
+    use crate::beatmap_source::OfflineSource;
+    use crate::performance_calculator::NativeBackend;
+    use crate::replay::test::string;
+    use std::env;
+    use std::process;
+
+    /// A score of `player` on the beatmap with `beatmap_md5`, as stored on a
+    /// `scores.db` file.
+    pub fn score_entry(
+        player: &str,
+        beatmap_md5: &str,
+        score: u32,
+        misses: u16,
+        mods: u32,
+    ) -> Vec<u8> {
+        let mut entry = vec![Ruleset::Osu.id()];
+        entry.extend(&20190101u32.to_le_bytes());
+        entry.extend(string(beatmap_md5));
+        entry.extend(string(player));
+        entry.extend(vec![0x00]);
+        for count in [4u16, 1, 0, 0, 0, misses].iter() {
+            entry.extend(&count.to_le_bytes());
+        }
+        entry.extend(&score.to_le_bytes());
+        entry.extend(&3u16.to_le_bytes());
+        entry.push(0);
+        entry.extend(&mods.to_le_bytes());
+        entry.push(0x00);
+        entry.extend(&636_000_000_000_000_000u64.to_le_bytes());
+        entry.extend(&(-1i32).to_le_bytes());
+        entry.extend(&0u64.to_le_bytes());
+        if mods & Mod::TP.bits() != 0 {
+            entry.extend(&95.5f64.to_le_bytes());
+        }
+
+        entry
+    }
+
+    /// A `scores.db` file with `beatmaps`, each with their checksum and scores.
+    pub fn scores_db(beatmaps: &[(&str, Vec<Vec<u8>>)]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        contents.extend(&20190101u32.to_le_bytes());
+        contents.extend(&(beatmaps.len() as u32).to_le_bytes());
+        for (md5, scores) in beatmaps {
+            contents.extend(string(md5));
+            contents.extend(&(scores.len() as u32).to_le_bytes());
+            for score in scores {
+                contents.extend(score);
+            }
+        }
+
+        contents
+    }
+
+    #[test]
+    fn test_parse() {
+        let contents = scores_db(&[
+            (
+                "aaa",
+                vec![
+                    score_entry("someone", "aaa", 1000, 0, 0),
+                    score_entry("SomeOne", "aaa", 2000, 1, Mod::HD.bits()),
+                    score_entry("another", "aaa", 3000, 0, 0),
+                ],
+            ),
+            (
+                "bbb",
+                vec![score_entry("someone", "bbb", 500, 0, Mod::TP.bits())],
+            ),
+            ("ccc", vec![]),
+        ]);
+
+        let db = ScoresDb::parse(&contents).unwrap();
+        assert_eq!(db.version, 20190101);
+        assert_eq!(db.scores.len(), 4);
+        assert_eq!(db.scores[3].mods, [Mod::TP].iter().cloned().collect());
+
+        let best = db.best_plays("someone", Ruleset::Osu);
+        let scores: Vec<u32> = best.iter().map(|play| play.score).collect();
+        assert_eq!(scores, vec![2000, 500]);
+        assert!(db.best_plays("someone", Ruleset::Taiko).is_empty());
+
+        match ScoresDb::parse(&contents[..contents.len() - 4]) {
+            Err(CalculationError::InvalidParams { field, message }) => {
+                assert_eq!(field, "scores_db");
+                assert!(message.contains("truncated"));
+            }
+            other => panic!("parsed a truncated file into {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scores_db_file() {
+        let path = env::temp_dir().join(format!("scores_db_file_{}.db", process::id()));
+        let file = ScoresDbFile::new(path.clone());
+        assert!(file.scores().is_err());
+
+        fs::write(&path, scores_db(&[("aaa", vec![])])).unwrap();
+        let first = file.scores().unwrap();
+        assert!(Arc::ptr_eq(&first, &file.scores().unwrap()));
+
+        // Files are parsed again once they change.
+        let scores = vec![score_entry("someone", "aaa", 1000, 0, 0)];
+        fs::write(&path, scores_db(&[("aaa", scores)])).unwrap();
+        assert_eq!(file.scores().unwrap().scores.len(), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_calculate_scores_db_profile() {
+        let dir = env::temp_dir().join(format!("scores_db_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.osu"), TEST_BEATMAP).unwrap();
+
+        let beatmaps = BeatmapCache::new(
+            dir.clone(),
+            Box::new(OfflineSource),
+            false,
+            CacheLimits::default(),
+        );
+        let md5 = md5_hex(TEST_BEATMAP.as_bytes());
+        let contents = scores_db(&[
+            (&md5, vec![score_entry("someone", &md5, 1000, 1, 0)]),
+            ("missing", vec![score_entry("someone", "missing", 10, 0, 0)]),
+            (
+                "invalid",
+                vec![score_entry(
+                    "someone",
+                    "invalid",
+                    5,
+                    0,
+                    Mod::HR.bits() | Mod::EZ.bits(),
+                )],
+            ),
+        ]);
+        let db = ScoresDb::parse(&contents).unwrap();
+        let backend = NativeBackend::new();
+        let live: &dyn PerformanceBackend = &backend;
+
+        let results = calculate_scores_db_profile(
+            &backend,
+            Some(live),
+            &beatmaps,
+            &db,
+            "someone",
+            Ruleset::Osu,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(results.profile.beatmap_ids(), vec![1]);
+        assert_eq!(results.failed.len(), 2);
+        assert_eq!(results.failed[0].code, "unknown_beatmap_hash");
+        assert_eq!(results.failed[0].beatmap_md5, Some("missing".to_string()));
+        // Invalid plays are left out, instead of failing the whole profile.
+        assert_eq!(results.failed[1].code, "invalid_params");
+        assert_eq!(results.failed[1].beatmap_md5, Some("invalid".to_string()));
+
+        // The live calculator is the same one, so nothing changes.
+        let json = serde_json::to_value(&results.profile).unwrap();
+        assert!(json["scores"][0]["live_pp"].as_f64().unwrap() > 0.0);
+        assert_eq!(json["scores"][0]["pp_change"], 0.0);
+
+        assert_eq!(
+            calculate_scores_db_profile(
+                &backend,
+                None,
+                &beatmaps,
+                &db,
+                "nobody",
+                Ruleset::Osu,
+                Duration::from_secs(60),
+            )
+            .unwrap_err(),
+            CalculationError::UserNotFound("nobody".to_string())
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,469 @@
+//! A thread-safe cache for simulation results, so identical simulations aren't
+//! recalculated from scratch.
+//!
+//! Results are keyed by a hash of everything that determines them: the
+//! calculator and its build (see `PerformanceBackend::version`), the MD5 of the
+//! .osu file, and the params the backend was called with. Changing the beatmap
+//! file or rebuilding the calculator changes the key, so stale results are
+//! never served, and just age out of the cache.
+//!
+//! Results are kept in memory, up to a number of entries, evicting the least
+//! recently used ones; and optionally on disk, as `<key>.json` files on a
+//! directory, evicting the oldest ones.
+//!
+//! `CachingBackend` wraps a `PerformanceBackend`, looking up its simulations
+//! on the cache before running them.
+use super::beatmap_cache::{md5_hex, write_atomically};
+use super::performance_calculator::{
+    CalculationError, PerformanceBackend, ProfileResults, Ruleset, SimulationParams,
+    SimulationResults,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The results in memory, and when they were last used.
+struct MemoryTier {
+    entries: HashMap<String, (SimulationResults, u64)>,
+    /// The keys of `entries`, by when they were last used.
+    by_use: BTreeMap<u64, String>,
+    /// Incremented on every use.
+    clock: u64,
+}
+
+impl MemoryTier {
+    fn get(&mut self, key: &str) -> Option<SimulationResults> {
+        self.clock += 1;
+        let clock = self.clock;
+
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.1);
+        self.by_use.insert(clock, key.to_string());
+        entry.1 = clock;
+
+        Some(entry.0.clone())
+    }
+
+    /// Inserts `results`, evicting the least recently used entries so there
+    /// are at most `max_entries`.
+    fn insert(&mut self, key: String, results: SimulationResults, max_entries: usize) {
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (results, self.clock)) {
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(self.clock, key);
+
+        while self.entries.len() > max_entries {
+            let oldest = match self.by_use.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.by_use.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// The size and hit rate of a `SimulationCache`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SimulationCacheStats {
+    pub entries: usize,
+    pub max_entries: usize,
+    pub disk_files: Option<usize>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A cache of simulation results, in memory and optionally on disk.
+pub struct SimulationCache {
+    memory: Mutex<MemoryTier>,
+    max_entries: usize,
+    disk_dir: Option<PathBuf>,
+    max_disk_files: usize,
+    /// Roughly how many files are on `disk_dir`; counted on startup, and
+    /// recounted whenever it's pruned.
+    disk_files: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl SimulationCache {
+    /// Creates a new cache, keeping up to `max_entries` results in memory; and,
+    /// if `disk_dir` is set, up to `max_disk_files` results on it.
+    pub fn new(max_entries: usize, disk_dir: Option<PathBuf>, max_disk_files: usize) -> Self {
+        let disk_files = disk_dir
+            .as_ref()
+            .map(|dir| Self::json_files(dir).len())
+            .unwrap_or(0);
+
+        SimulationCache {
+            memory: Mutex::new(MemoryTier {
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                clock: 0,
+            }),
+            max_entries: max_entries,
+            disk_dir: disk_dir,
+            max_disk_files: max_disk_files,
+            disk_files: AtomicUsize::new(disk_files),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The key for the results of simulating `params` on a beatmap whose .osu
+    /// file hashes to `beatmap_md5`, with the `version` build of `calculator`.
+    pub fn key(
+        calculator: &str,
+        version: &str,
+        beatmap_md5: &str,
+        params: &SimulationParams,
+    ) -> String {
+        // Mods are a set, so the params always serialize the same way.
+        let params = serde_json::to_string(params).unwrap();
+
+        md5_hex(format!("{}\n{}\n{}\n{}", calculator, version, beatmap_md5, params).as_bytes())
+    }
+
+    /// Gets the results stored under `key`, looking at the disk if they aren't
+    /// in memory.
+    pub fn get(&self, key: &str) -> Option<SimulationResults> {
+        let in_memory = self.memory.lock().unwrap().get(key);
+        let results = in_memory.or_else(|| {
+            let path = self.disk_dir.as_ref()?.join(format!("{}.json", key));
+            let results: SimulationResults = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+
+            self.memory
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), results.clone(), self.max_entries);
+            Some(results)
+        });
+
+        match results {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        results
+    }
+
+    /// Stores `results` under `key`.
+    pub fn set(&self, key: String, results: &SimulationResults) {
+        if let Some(ref dir) = self.disk_dir {
+            let path = dir.join(format!("{}.json", key));
+            let stored = fs::create_dir_all(dir).and_then(|_| {
+                write_atomically(&path, serde_json::to_string(results).unwrap().as_bytes())
+            });
+
+            match stored {
+                Ok(_) => {
+                    let files = self.disk_files.fetch_add(1, Ordering::Relaxed) + 1;
+                    if files > self.max_disk_files {
+                        self.prune_disk();
+                    }
+                }
+                Err(e) => println!("Couldn't store simulation results: {}", e),
+            }
+        }
+
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key, results.clone(), self.max_entries);
+    }
+
+    /// The size and hit rate of this cache.
+    pub fn stats(&self) -> SimulationCacheStats {
+        SimulationCacheStats {
+            entries: self.memory.lock().unwrap().entries.len(),
+            max_entries: self.max_entries,
+            disk_files: self
+                .disk_dir
+                .as_ref()
+                .map(|_| self.disk_files.load(Ordering::Relaxed)),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The results files on `dir`, with their modification times.
+    fn json_files(dir: &PathBuf) -> Vec<(SystemTime, PathBuf)> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "json"))
+            .map(|entry| {
+                let modified = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(UNIX_EPOCH);
+
+                (modified, entry.path())
+            })
+            .collect()
+    }
+
+    /// Removes the oldest results from the disk, down to 90% of
+    /// `max_disk_files`, so this doesn't happen on every new result.
+    fn prune_disk(&self) {
+        let dir = match self.disk_dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+
+        let mut files = Self::json_files(dir);
+        let target = self.max_disk_files - self.max_disk_files / 10;
+        if files.len() > target {
+            files.sort();
+            let excess = files.len() - target;
+            for (_, path) in files.drain(..excess) {
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        self.disk_files.store(files.len(), Ordering::Relaxed);
+    }
+}
+
+/// A `PerformanceBackend` that looks up simulations on a `SimulationCache`,
+/// and only runs the ones that aren't there on the wrapped backend. Profiles
+/// aren't cached (see `ProfileCache`).
+pub struct CachingBackend {
+    calculator: String,
+    backend: Arc<dyn PerformanceBackend>,
+    cache: Arc<SimulationCache>,
+}
+
+impl CachingBackend {
+    /// Wraps `backend`, registered as `calculator`, storing its results on `cache`.
+    pub fn new(
+        calculator: String,
+        backend: Arc<dyn PerformanceBackend>,
+        cache: Arc<SimulationCache>,
+    ) -> Self {
+        CachingBackend {
+            calculator: calculator,
+            backend: backend,
+            cache: cache,
+        }
+    }
+
+    /// The cache keys for simulating each of `params` on the .osu file at
+    /// `beatmap_path`, or `None` if the results can't be cached (because the
+    /// backend has no version, or the file can't be read).
+    fn keys(&self, beatmap_path: &str, params: &[SimulationParams]) -> Option<Vec<String>> {
+        let version = self.backend.version()?;
+        let beatmap_md5 = md5_hex(&fs::read(beatmap_path).ok()?);
+
+        Some(
+            params
+                .iter()
+                .map(|params| {
+                    SimulationCache::key(&self.calculator, &version, &beatmap_md5, params)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl PerformanceBackend for CachingBackend {
+    fn calculate_profile(
+        &self,
+        user: &str,
+        ruleset: Ruleset,
+    ) -> Result<ProfileResults, CalculationError> {
+        self.backend.calculate_profile(user, ruleset)
+    }
+
+    fn simulate_play(
+        &self,
+        beatmap_path: &str,
+        params: &SimulationParams,
+    ) -> Result<SimulationResults, CalculationError> {
+        self.simulate_plays(beatmap_path, &[params.clone()])
+            .pop()
+            .unwrap()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.backend.version()
+    }
+
+    fn fetches_profile_beatmaps(&self) -> bool {
+        self.backend.fetches_profile_beatmaps()
+    }
+
+    fn simulate_plays(
+        &self,
+        beatmap_path: &str,
+        params: &[SimulationParams],
+    ) -> Vec<Result<SimulationResults, CalculationError>> {
+        let keys = match self.keys(beatmap_path, params) {
+            Some(keys) => keys,
+            None => return self.backend.simulate_plays(beatmap_path, params),
+        };
+
+        let mut results: Vec<_> = keys.iter().map(|key| self.cache.get(key)).collect();
+
+        // Only the plays that weren't cached are simulated.
+        let missing: Vec<_> = (0..params.len())
+            .filter(|&i| results[i].is_none())
+            .collect();
+        let missing_params: Vec<_> = missing.iter().map(|&i| params[i].clone()).collect();
+        let mut simulated = if missing.is_empty() {
+            Vec::new()
+        } else {
+            self.backend.simulate_plays(beatmap_path, &missing_params)
+        }
+        .into_iter();
+
+        let mut missing = missing.into_iter().peekable();
+        (0..params.len())
+            .map(|i| {
+                if missing.peek() != Some(&i) {
+                    return Ok(results[i].take().unwrap());
+                }
+                missing.next();
+
+                let result = simulated
+                    .next()
+                    .expect("backends return a result for each play")?;
+                self.cache.set(keys[i].clone(), &result);
+
+                Ok(result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beatmap::test::TEST_BEATMAP;
+    use crate::performance_calculator::{Accuracy, NativeBackend};
+    use std::env;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Counts the simulations that reach the native backend.
+    struct CountingBackend {
+        simulations: AtomicUsize,
+        version: Mutex<String>,
+    }
+
+    impl PerformanceBackend for CountingBackend {
+        fn calculate_profile(
+            &self,
+            _user: &str,
+            _ruleset: Ruleset,
+        ) -> Result<ProfileResults, CalculationError> {
+            Err(CalculationError::Unsupported("Profiles".to_string()))
+        }
+
+        fn simulate_play(
+            &self,
+            beatmap_path: &str,
+            params: &SimulationParams,
+        ) -> Result<SimulationResults, CalculationError> {
+            self.simulations.fetch_add(1, Ordering::SeqCst);
+            NativeBackend::new().simulate_play(beatmap_path, params)
+        }
+
+        fn version(&self) -> Option<String> {
+            Some(self.version.lock().unwrap().clone())
+        }
+    }
+
+    fn params(accuracy: f64) -> SimulationParams {
+        SimulationParams {
+            ruleset: Ruleset::Osu,
+            accuracy: Accuracy::Percentage(accuracy),
+            mods: Default::default(),
+            combo: None,
+            misses: None,
+            score: None,
+        }
+    }
+
+    #[test]
+    fn test_memory_lru() {
+        let dir = env::temp_dir().join(format!("simulation_cache_lru_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.osu");
+        fs::write(&path, TEST_BEATMAP).unwrap();
+        let results = NativeBackend::new()
+            .simulate_play(path.to_str().unwrap(), &params(100.0))
+            .unwrap();
+
+        let cache = SimulationCache::new(2, None, 0);
+
+        cache.set("a".to_string(), &results);
+        cache.set("b".to_string(), &results);
+        assert!(cache.get("a").is_some());
+        cache.set("c".to_string(), &results);
+
+        // "b" was the least recently used.
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!((cache.stats().hits, cache.stats().misses), (3, 1));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_caching_backend() {
+        let dir = env::temp_dir().join(format!("simulation_cache_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let beatmap_path = dir.join("1.osu");
+        fs::write(&beatmap_path, TEST_BEATMAP).unwrap();
+        let beatmap_path = beatmap_path.to_str().unwrap();
+
+        let counting = Arc::new(CountingBackend {
+            simulations: AtomicUsize::new(0),
+            version: Mutex::new("1".to_string()),
+        });
+        let cache = Arc::new(SimulationCache::new(100, Some(dir.join("results")), 100));
+        let backend = CachingBackend::new("default".to_string(), counting.clone(), cache);
+        let simulations = || counting.simulations.load(Ordering::SeqCst);
+
+        let first = backend.simulate_play(beatmap_path, &params(99.0)).unwrap();
+        let again = backend.simulate_play(beatmap_path, &params(99.0)).unwrap();
+        assert_eq!(simulations(), 1);
+        assert_eq!(first.pp, again.pp);
+
+        // Only the new play of the batch is simulated.
+        let batch = backend.simulate_plays(beatmap_path, &[params(99.0), params(95.0)]);
+        assert!(batch.iter().all(Result::is_ok));
+        assert_eq!(simulations(), 2);
+
+        // A fresh cache on the same directory finds the results on disk.
+        let cache = Arc::new(SimulationCache::new(100, Some(dir.join("results")), 100));
+        assert_eq!(cache.stats().disk_files, Some(2));
+        let backend = CachingBackend::new("default".to_string(), counting.clone(), cache);
+        backend.simulate_play(beatmap_path, &params(95.0)).unwrap();
+        assert_eq!(simulations(), 2);
+
+        // A new calculator build, or a changed beatmap, invalidate the results.
+        *counting.version.lock().unwrap() = "2".to_string();
+        backend.simulate_play(beatmap_path, &params(95.0)).unwrap();
+        assert_eq!(simulations(), 3);
+
+        fs::write(dir.join("1.osu"), TEST_BEATMAP.replace("Insane", "Extra")).unwrap();
+        backend.simulate_play(beatmap_path, &params(95.0)).unwrap();
+        assert_eq!(simulations(), 4);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
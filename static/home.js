@@ -14,8 +14,50 @@ const beatmapTab = () => {
 
 const stopProfileLoadingAnimation = () => document.getElementById("button").className = document.getElementById("button").className.replace(" is-loading", "");
 
+const errorMessage = (json, fallback) => json.error ? json.error.message : fallback;
+
+// The selected calculator, or an empty string for the default one.
+const selectedCalculator = () => {
+    let select = document.getElementById("calculator");
+    return select ? select.value : "";
+}
+
+const selectedRuleset = () => document.getElementById("ruleset").value;
+
+const calculatorQuery = () => "&calculator=" + encodeURIComponent(selectedCalculator())
+    + "&ruleset=" + encodeURIComponent(selectedRuleset());
+
+// Placeholders for the hit count fields, on each ruleset. Mania plays are
+// described by their score instead.
+const hitPlaceholders = {
+    osu: ["100s", "50s"],
+    taiko: ["goods", null],
+    catch: ["droplets", "tiny droplets"],
+    mania: null
+};
+
+const onRulesetChange = () => {
+    let placeholders = hitPlaceholders[selectedRuleset()];
+
+    document.getElementById("hits_fields").hidden = placeholders === null;
+    document.getElementById("score_field").hidden = placeholders !== null;
+    if (placeholders !== null) {
+        document.getElementById("good").placeholder = placeholders[0];
+        document.getElementById("meh_field").hidden = placeholders[1] === null;
+        document.getElementById("meh").placeholder = placeholders[1] || "";
+    }
+}
+
+// Local profiles (from the server's scores.db) have routes of their own.
+const isLocalProfile = () => {
+    let checkbox = document.getElementById("local");
+    return checkbox ? checkbox.checked : false;
+}
+
+const profileRoute = () => isLocalProfile() ? "/pp_local" : "/pp";
+
 const checkPPRequest = async (user, last_status, last_queue_pos) => {
-    let resp = await fetch("/pp_check?user=" + encodeURIComponent(user));
+    let resp = await fetch(profileRoute() + "_check?user=" + encodeURIComponent(user) + calculatorQuery());
 
     let json = await resp.json();
     let status = json["status"];
@@ -29,7 +71,9 @@ const checkPPRequest = async (user, last_status, last_queue_pos) => {
         } else if (status == "calculating") {
             toastr.info("Calculating new PP...", "", {timeOut: 0, extendedTimeOut: 0});
         } else if (status == "error") {
-            toastr.error("Error while calculating", "", {timeOut: 0, extendedTimeOut: 0});
+            toastr.error(errorMessage(json, "Error while calculating"), "", {timeOut: 0, extendedTimeOut: 0});
+        } else if (status == "timed_out") {
+            toastr.error("Calculation took too long, and was cancelled", "", {timeOut: 0, extendedTimeOut: 0});
         }
     } else {
         if (status == "pending" && last_queue_pos != json["pos"]) {
@@ -38,18 +82,20 @@ const checkPPRequest = async (user, last_status, last_queue_pos) => {
         }
     }
 
-    if (status != "done" && status != "error") {
+    if (status != "done" && status != "error" && status != "timed_out") {
         setTimeout(() => checkPPRequest(user, status, last_queue_pos), 2000);
     } else if (status == "done") {
         stopProfileLoadingAnimation();
-        window.location.href = "/pp?user=" + encodeURIComponent(user);
-    } else if (status == "error") {
+        window.location.href = profileRoute() + "?user=" + encodeURIComponent(user) + calculatorQuery();
+    } else if (status == "error" || status == "timed_out") {
         stopProfileLoadingAnimation();
     }
 }
 
 const requestPPCalc = async (user, force) => {
-    let resp = await fetch("/pp_request?user=" + encodeURIComponent(user) + "&force=" + encodeURIComponent(force));
+    // Local profiles are always calculated again, so they can't be forced.
+    let forceQuery = isLocalProfile() ? "" : "&force=" + encodeURIComponent(force);
+    let resp = await fetch(profileRoute() + "_request?user=" + encodeURIComponent(user) + forceQuery + calculatorQuery());
 
     let json = await resp.json()
     let status = json["status"];
@@ -58,12 +104,20 @@ const requestPPCalc = async (user, force) => {
     if (status == "done") {
         stopProfileLoadingAnimation();
 
-        window.location.href = "/pp?user=" + encodeURIComponent(user);
+        window.location.href = profileRoute() + "?user=" + encodeURIComponent(user) + calculatorQuery();
+    } else if (status == "busy") {
+        stopProfileLoadingAnimation();
+
+        toastr.error("Too many local profiles are being calculated, try again later.", "", {timeOut: 0, extendedTimeOut: 0});
     } else if (status == "cant_force") {
         stopProfileLoadingAnimation();
 
         toastr.error("Can't force recalculation for this user yet. "
             + json["remaining"] + " seconds until force is available.", "", {timeOut: 0, extendedTimeOut: 0});
+    } else if (status == "error") {
+        stopProfileLoadingAnimation();
+
+        toastr.error(errorMessage(json, "Error while requesting calculation"), "", {timeOut: 0, extendedTimeOut: 0});
     } else {
         console.log("pending, now waiting...");
 
@@ -83,12 +137,13 @@ const onProfileFormSubmit = () => {
     return false;
 }
 
-const beatmapRegexes = [
-    /^.*osu[.]ppy[.]sh\/beatmapsets\/\d+#osu\/(\d+).*$/,
-    /^.*osu[.]ppy[.]sh\/b\/(\d+).*$/
-];
-
-const availableMods = ["HD", "HR", "DT", "NC", "FL", "NF", "EZ", "HT", "SO", "SD", "PF", "TD"];
+const commonMods = ["HD", "DT", "NC", "FL", "NF", "EZ", "HT", "SD", "PF"];
+const availableMods = {
+    osu: commonMods.concat(["HR", "SO", "TD", "RX", "AP"]),
+    taiko: commonMods.concat(["HR", "RX"]),
+    catch: commonMods.concat(["HR", "RX"]),
+    mania: commonMods.concat(["FI", "RD", "MR", "DS", "1K", "2K", "3K", "4K", "5K", "6K", "7K", "8K", "9K"])
+};
 
 const sendBeatmapRequest = async () => {
     let beatmap = fieldValueById("beatmap");
@@ -101,30 +156,40 @@ const sendBeatmapRequest = async () => {
     let missesField = fieldValueById("misses");
     
     let modsField = fieldValueById("mods");
+    let scoreField = fieldValueById("score");
 
-    let simulation_params = {};
-    let beatmap_id = parseInt(beatmap);
-    // scream
-    if (Object.is(beatmap_id, NaN)) { // because, apparently, NaN === NaN is false. xd
-        let match = beatmapRegexes.map((r) => beatmap.match(r)).find((v) => v !== null)
+    let osuFile = document.getElementById("osu_file").files[0];
 
-        if (!match) {
-            toastr.error("Beatmap field is invalid.");
-            return false;
-        }
+    let ruleset = selectedRuleset();
+    let simulation_params = { ruleset: ruleset };
+    let setMode = document.getElementById("set_mode").checked;
+    if (setMode) {
+        osuFile = undefined;
+    }
 
-        beatmap_id = parseInt(match[1])
-        if (Object.is(beatmap_id, NaN)) {
-            toastr.error("Beatmap field is invalid.");
-            return false;
-        }
+    let replayFile = document.getElementById("replay").files[0];
+    if (replayFile && !setMode) {
+        return sendReplayRequest(replayFile);
+    }
+
+    // Beatmap ids and links are resolved by the server.
+    if (beatmap.trim() == "" && !osuFile) {
+        toastr.error("Fill the beatmap field, or upload a .osu file.");
+        return false;
     }
 
     let accPct = parseFloat(accPctField.replace(",", "."));
     let good = parseInt(goodField);
     let meh = parseInt(mehField);
 
-    if ([accPct, good, meh].every((x) => Object.is(x, NaN))) {
+    if (ruleset == "mania") {
+        let score = parseInt(scoreField);
+        if (Object.is(score, NaN) || score < 0 || score > 1000000) {
+            toastr.error("Score should be between 0 and 1000000.");
+            return false;
+        }
+        simulation_params.score = score;
+    } else if ([accPct, good, meh].every((x) => Object.is(x, NaN))) {
         toastr.error("Fill either accuracy (%) or number of 300s and 100s!");
         return false;
     } else if (!Object.is(accPct, NaN)) {
@@ -136,7 +201,7 @@ const sendBeatmapRequest = async () => {
     } else {
         simulation_params.accuracy = {
             good: good || 0,
-            meh: meh || 0
+            meh: ruleset == "taiko" ? 0 : (meh || 0)
         };
     }
 
@@ -151,37 +216,263 @@ const sendBeatmapRequest = async () => {
     }
 
     simulation_params.mods = modsField.toUpperCase().split(",").filter((v) => v != "");
-    if (!simulation_params.mods.every((mod) => availableMods.lastIndexOf(mod) !== -1)) {
+    if (!simulation_params.mods.every((mod) => availableMods[ruleset].lastIndexOf(mod) !== -1)) {
         toastr.error("Mods field is invalid.");
         return false;
     }
 
-    let res = await fetch("/simulate", {
+    // An uploaded .osu file takes precedence over the beatmap id.
+    let url = "/simulate";
+    let body = {
+        beatmap: beatmap,
+        params: simulation_params,
+        calculator: selectedCalculator() || null
+    };
+    if (setMode) {
+        url = "/simulate_set";
+        delete body.beatmap;
+        body.beatmapset = beatmap;
+    } else if (osuFile) {
+        url = "/simulate_file";
+        delete body.beatmap;
+        body.osu_file = await osuFile.text();
+    }
+
+    let res = await fetch(url, {
         method: "post",
         headers: {
             'Accept': 'application/json',
             'Content-Type': 'application/json'
         },
-        body: JSON.stringify({
-            beatmap_id: beatmap_id,
-            params: simulation_params
-        })
+        body: JSON.stringify(body)
     });
 
     let json = await res.json();
     if (json.status == "error") {
-        toastr.error("Error while calculating beatmap pp");
+        toastr.error(errorMessage(json, "Error while calculating beatmap pp"));
+        return false;
+    } else if (json.status == "timed_out") {
+        toastr.error("Beatmap pp calculation took too long, and was cancelled");
+        return false;
+    }
+
+    if (setMode) {
+        showSetCalcResult(json.results);
+    } else {
+        // Curves are only available for beatmaps the server can download.
+        lastCurveRequest = osuFile ? null : body;
+        showBeatmapCalcResult(json.results);
+        showLiveComparison(json.live);
+        loadCurve();
+    }
+    return false;
+}
+
+// Replays say what their beatmap is and how it was played, so the rest of the
+// form doesn't matter.
+const sendReplayRequest = async (replayFile) => {
+    let calculator = selectedCalculator();
+    let url = "/simulate_replay" + (calculator ? "?calculator=" + encodeURIComponent(calculator) : "");
+
+    let res = await fetch(url, {
+        method: "post",
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/octet-stream'
+        },
+        body: replayFile
+    });
+
+    let json = await res.json();
+    if (json.status == "error") {
+        toastr.error(errorMessage(json, "Error while calculating the replay pp"));
+        return false;
+    } else if (json.status == "timed_out") {
+        toastr.error("Replay pp calculation took too long, and was cancelled");
         return false;
     }
 
+    lastCurveRequest = {
+        beatmap_id: json.beatmap_id,
+        params: json.params,
+        calculator: calculator || null
+    };
     showBeatmapCalcResult(json.results);
+    showLiveComparison(json.live);
+    loadCurve();
     return false;
 }
 
+// The last simulation on a beatmap id or link, which its curves are based on.
+let lastCurveRequest = null;
+
+const fetchCurve = async (format) => {
+    let body = Object.assign({}, lastCurveRequest, {
+        curve: { axis: fieldValueById("curve_axis") }
+    });
+
+    return await fetch("/simulate_curve" + (format ? "?format=" + format : ""), {
+        method: "post",
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(body)
+    });
+}
+
+const svgElement = (name, attributes) => {
+    let element = document.createElementNS("http://www.w3.org/2000/svg", name);
+    for (let key in attributes) {
+        element.setAttribute(key, attributes[key]);
+    }
+
+    return element;
+}
+
+// Draws the points of a curve as a line chart, with the value on the x axis
+// and the pp on the y axis.
+const drawCurve = (svg, curve) => {
+    svg.innerHTML = "";
+    if (curve.points.length == 0) {
+        return;
+    }
+
+    const width = 400, height = 200, margin = 40;
+    let xs = curve.points.map((point) => point.value);
+    let pps = curve.points.map((point) => point.pp);
+    let minX = Math.min(...xs), maxX = Math.max(...xs);
+    let minPP = Math.min(...pps), maxPP = Math.max(...pps);
+
+    let x = (value) => margin + (value - minX) / ((maxX - minX) || 1) * (width - margin * 1.5);
+    let y = (pp) => height - margin - (pp - minPP) / ((maxPP - minPP) || 1) * (height - margin * 1.5);
+
+    svg.appendChild(svgElement("line", { x1: margin, y1: height - margin, x2: width - margin / 2, y2: height - margin, stroke: "#999" }));
+    svg.appendChild(svgElement("line", { x1: margin, y1: margin / 2, x2: margin, y2: height - margin, stroke: "#999" }));
+    svg.appendChild(svgElement("polyline", {
+        points: curve.points.map((point) => x(point.value) + "," + y(point.pp)).join(" "),
+        fill: "none",
+        stroke: "#ff66aa",
+        "stroke-width": 2
+    }));
+
+    let labels = [
+        [margin, height - margin / 2, "start", +minX.toFixed(2)],
+        [width - margin / 2, height - margin / 2, "end", +maxX.toFixed(2) + " " + curve.axis],
+        [margin - 4, height - margin, "end", minPP.toFixed(0)],
+        [margin - 4, margin / 2 + 8, "end", maxPP.toFixed(0) + "pp"]
+    ];
+    for (let [lx, ly, anchor, text] of labels) {
+        let label = svgElement("text", { x: lx, y: ly, "text-anchor": anchor, "font-size": 10 });
+        label.textContent = text;
+        svg.appendChild(label);
+    }
+}
+
+const loadCurve = async () => {
+    let section = document.getElementById("beatmap-results-curve-section");
+    section.hidden = !lastCurveRequest;
+    if (!lastCurveRequest) {
+        return;
+    }
+
+    let svg = document.getElementById("beatmap-results-curve");
+    svg.innerHTML = "";
+
+    let json = await (await fetchCurve()).json();
+    if (json.status != "ok") {
+        toastr.error(errorMessage(json, "Error while calculating the pp curve"));
+        return;
+    }
+
+    drawCurve(svg, json.results);
+}
+
+const downloadCurveCsv = async () => {
+    // Errors are reported as JSON, even when asking for CSV.
+    let res = await fetchCurve("csv");
+    if (!(res.headers.get("Content-Type") || "").startsWith("text/csv")) {
+        toastr.error("Error while calculating the pp curve");
+        return;
+    }
+
+    let link = document.createElement("a");
+    link.href = URL.createObjectURL(await res.blob());
+    link.download = "curve_" + fieldValueById("curve_axis") + ".csv";
+    link.click();
+}
+
+// In set mode, the beatmap field takes a beatmap set, and uploads don't apply.
+const onSetModeChange = () => {
+    let setMode = document.getElementById("set_mode").checked;
+
+    document.getElementById("osu_file_field").hidden = setMode;
+    document.getElementById("replay_field").hidden = setMode;
+    document.getElementById("beatmap").placeholder = setMode
+        ? "beatmap set id or link..."
+        : "beatmap id (preferred) or link...";
+}
+
 const setInnerById = (id, val) => document.getElementById(id).innerHTML = val;
+// For text that comes from the .osu file, which shouldn't be parsed as HTML.
+const setTextById = (id, val) => document.getElementById(id).textContent = val;
+
+// Formats a beatmap setting, showing its mod-adjusted value when it differs.
+const formatStat = (base, adjusted, digits) => {
+    let text = +base.toFixed(digits);
+    if (Math.abs(adjusted - base) > 1e-6) {
+        text += " → " + +adjusted.toFixed(digits);
+    }
+
+    return text;
+}
+
+const formatLength = (seconds) => {
+    seconds = Math.round(seconds);
+    return Math.floor(seconds / 60) + ":" + String(seconds % 60).padStart(2, "0");
+}
+
+const formatBpm = (stats) => {
+    let bpm = +stats.bpm.toFixed(1);
+    if (stats.min_bpm != stats.max_bpm) {
+        bpm += " (" + +stats.min_bpm.toFixed(1) + "-" + +stats.max_bpm.toFixed(1) + ")";
+    }
+
+    return bpm;
+}
+
+// Shows the beatmap metadata and settings, if the server could read them.
+const showBeatmapInfo = (beatmap) => {
+    document.getElementById("beatmap-results-info").hidden = !beatmap;
+    if (!beatmap) {
+        return;
+    }
+
+    let stats = beatmap.stats;
+    let adjusted = beatmap.adjusted_stats;
+    let bpm = formatBpm(stats);
+    if (adjusted.bpm != stats.bpm) {
+        bpm += " → " + formatBpm(adjusted);
+    }
+    let length = formatLength(stats.length);
+    if (adjusted.length != stats.length) {
+        length += " → " + formatLength(adjusted.length);
+    }
+
+    setTextById("beatmap-results-name", `${beatmap.artist} - ${beatmap.title} [${beatmap.version}]`);
+    setTextById("beatmap-results-creator", beatmap.creator);
+    setInnerById("beatmap-results-cs", formatStat(stats.circle_size, adjusted.circle_size, 1));
+    setInnerById("beatmap-results-ar", formatStat(stats.approach_rate, adjusted.approach_rate, 2));
+    setInnerById("beatmap-results-od", formatStat(stats.overall_difficulty, adjusted.overall_difficulty, 2));
+    setInnerById("beatmap-results-hp", formatStat(stats.hp_drain_rate, adjusted.hp_drain_rate, 1));
+    setInnerById("beatmap-results-bpm", bpm);
+    setInnerById("beatmap-results-length", length);
+    setInnerById("beatmap-results-objects",
+        `${beatmap.circle_count} circles, ${beatmap.slider_count} sliders, ${beatmap.spinner_count} spinners`);
+}
 
 const showBeatmapCalcResult = (data) => {
     setInnerById("beatmap-results-name", data.beatmap_info);
+    showBeatmapInfo(data.beatmap);
     let mods = "";
     if (data.mods.length > 0) {
         mods = data.mods.join(",");
@@ -190,6 +481,12 @@ const showBeatmapCalcResult = (data) => {
     }
 
     setInnerById("beatmap-results-accuracy", data.play_info.accuracy.toFixed(2));
+    // The hit counts the play was simulated with, when the server worked them out.
+    let hits = data.hit_results;
+    document.getElementById("beatmap-results-hits-row").hidden = !hits;
+    if (hits) {
+        setInnerById("beatmap-results-hits", [hits.great, hits.good, hits.meh, hits.miss].join(" / "));
+    }
     setInnerById("beatmap-results-mods", mods);
     setInnerById("beatmap-results-combo", data.play_info.combo);
     setInnerById("beatmap-results-max-combo", data.play_info.max_combo);
@@ -198,6 +495,54 @@ const showBeatmapCalcResult = (data) => {
     document.getElementById("beatmap-results").className = "modal is-active";
 }
 
+// Shows how the pp compares with the live pp system, when the server has a
+// calculator for it.
+const showLiveComparison = (live) => {
+    document.getElementById("beatmap-results-live-row").hidden = !live;
+    if (!live) {
+        return;
+    }
+
+    let sign = live.delta >= 0 ? "+" : "";
+    let delta = sign + live.delta.toFixed(2) + "pp";
+    if (live.delta_percentage !== null) {
+        delta += ", " + sign + live.delta_percentage.toFixed(1) + "%";
+    }
+
+    setInnerById("beatmap-results-live-pp", live.live_pp.toFixed(2));
+    setInnerById("beatmap-results-live-delta", delta);
+}
+
+// Shows a row for each difficulty of the set (already sorted by pp), and the
+// ones that couldn't be calculated.
+const showSetCalcResult = (data) => {
+    let rows = document.getElementById("set-results-rows");
+    rows.innerHTML = "";
+
+    for (let difficulty of data.difficulties) {
+        let results = difficulty.results;
+        let row = rows.insertRow();
+        row.insertCell().textContent = difficulty.version;
+        row.insertCell().textContent = results.play_info.accuracy.toFixed(2) + "%";
+        row.insertCell().textContent = results.play_info.combo + "/" + results.play_info.max_combo + "x";
+        row.insertCell().textContent = results.pp.toFixed(2) + "pp";
+    }
+
+    let failed = document.getElementById("set-results-failed");
+    failed.innerHTML = "";
+    for (let difficulty of data.failed) {
+        let line = document.createElement("p");
+        line.textContent = `${difficulty.version}: ${difficulty.message}`;
+        failed.appendChild(line);
+    }
+
+    document.getElementById("set-results").className = "modal is-active";
+}
+
+const hideSetResults = () => {
+    document.getElementById("set-results").className = "modal";
+}
+
 const hideBeatmapResults = () => {
     document.getElementById("beatmap-results").className = "modal";
 }